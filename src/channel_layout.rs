@@ -0,0 +1,42 @@
+use crate::{bail_ffmpeg, cstr, rstr};
+use anyhow::Result;
+use ffmpeg_sys_the_third::{
+    av_channel_layout_describe, av_channel_layout_from_string, AVChannelLayout,
+};
+
+/// Parse a channel layout from a string (eg. "stereo", "5.1", "FL+FR")
+pub unsafe fn channel_layout_from_str(layout: &str) -> Result<AVChannelLayout> {
+    let mut ret = AVChannelLayout::empty();
+    let r = av_channel_layout_from_string(&mut ret, cstr!(layout));
+    bail_ffmpeg!(r, format!("Invalid channel layout {}", layout));
+    Ok(ret)
+}
+
+/// Get a human-readable description of a channel layout (eg. "5.1(side)")
+pub unsafe fn channel_layout_describe(layout: *const AVChannelLayout) -> Result<String> {
+    let mut buf = [0 as libc::c_char; 128];
+    let r = av_channel_layout_describe(layout, buf.as_mut_ptr(), buf.len());
+    bail_ffmpeg!(r);
+    Ok(rstr!(buf.as_ptr()).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_describe() -> Result<()> {
+        unsafe {
+            let layout = channel_layout_from_str("5.1")?;
+            assert_eq!(layout.nb_channels, 6);
+            assert!(channel_layout_describe(&layout)?.starts_with("5.1"));
+
+            let layout = channel_layout_from_str("stereo")?;
+            assert_eq!(layout.nb_channels, 2);
+            assert_eq!(channel_layout_describe(&layout)?, "stereo");
+
+            assert!(channel_layout_from_str("not-a-layout").is_err());
+        }
+        Ok(())
+    }
+}
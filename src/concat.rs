@@ -0,0 +1,144 @@
+use crate::{Demuxer, DemuxerInfo, Muxer};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{av_packet_free, av_rescale_q, AV_NOPTS_VALUE, AV_TIME_BASE_Q};
+use std::collections::HashMap;
+
+/// Join multiple inputs into a single output using stream copy
+///
+/// All inputs must contain the same streams with matching codecs,
+/// timestamps are offset so that they are continuous across inputs
+pub struct Concatenator {
+    inputs: Vec<Demuxer>,
+    muxer: Muxer,
+}
+
+impl Concatenator {
+    pub unsafe fn new(inputs: Vec<Demuxer>, output: &str) -> Result<Self> {
+        if inputs.is_empty() {
+            bail!("No inputs to concatenate");
+        }
+        let muxer = Muxer::builder().with_output_path(output, None)?.build()?;
+        Ok(Self { inputs, muxer })
+    }
+
+    /// Check that an input has the same streams as the first input
+    fn check_compatible(first: &DemuxerInfo, info: &DemuxerInfo) -> Result<()> {
+        if first.streams.len() != info.streams.len() {
+            bail!(
+                "Input stream count mismatch {} != {}",
+                first.streams.len(),
+                info.streams.len()
+            );
+        }
+        for (a, b) in first.streams.iter().zip(info.streams.iter()) {
+            if a.stream_type != b.stream_type || a.codec != b.codec {
+                bail!(
+                    "Input stream #{} is not compatible ({} != {})",
+                    b.index,
+                    a,
+                    b
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the concatenation
+    pub unsafe fn run(mut self, mux_options: Option<HashMap<String, String>>) -> Result<()> {
+        let mut infos = Vec::with_capacity(self.inputs.len());
+        for input in self.inputs.iter_mut() {
+            infos.push(input.probe_input()?);
+        }
+        for info in infos.iter().skip(1) {
+            Self::check_compatible(&infos[0], info)?;
+        }
+
+        // map input stream index to output stream index
+        let mut stream_map = HashMap::new();
+        for stream in &infos[0].streams {
+            let dst_stream = self.muxer.add_copy_stream(stream.stream)?;
+            stream_map.insert(stream.index as i32, (*dst_stream).index);
+        }
+        self.muxer.open(mux_options)?;
+
+        // offset in AV_TIME_BASE units applied to the current input
+        let mut offset = 0;
+        for input in self.inputs.iter_mut() {
+            let mut end = 0;
+            loop {
+                let (mut pkt, _) = input.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                let dst_index = if let Some(idx) = stream_map.get(&(*pkt).stream_index) {
+                    *idx
+                } else {
+                    av_packet_free(&mut pkt);
+                    continue;
+                };
+
+                let tb = (*pkt).time_base;
+                if (*pkt).pts != AV_NOPTS_VALUE {
+                    end = end.max(av_rescale_q(
+                        (*pkt).pts + (*pkt).duration,
+                        tb,
+                        AV_TIME_BASE_Q,
+                    ));
+                }
+                let pkt_offset = av_rescale_q(offset, AV_TIME_BASE_Q, tb);
+                if (*pkt).pts != AV_NOPTS_VALUE {
+                    (*pkt).pts += pkt_offset;
+                }
+                if (*pkt).dts != AV_NOPTS_VALUE {
+                    (*pkt).dts += pkt_offset;
+                }
+                (*pkt).stream_index = dst_index;
+                self.muxer.write_packet(pkt)?;
+                av_packet_free(&mut pkt);
+            }
+            offset += end;
+        }
+        self.muxer.close()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_test_video;
+
+    unsafe fn count_packets(path: &str) -> Result<(f32, usize)> {
+        let mut demux = Demuxer::new(path)?;
+        let info = demux.probe_input()?;
+        let mut count = 0;
+        loop {
+            let (mut pkt, _) = demux.get_packet()?;
+            if pkt.is_null() {
+                break;
+            }
+            count += 1;
+            av_packet_free(&mut pkt);
+        }
+        Ok((info.duration, count))
+    }
+
+    #[test]
+    fn test_concat() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_concat_input.mp4";
+            let output = "test_output/test_concat.mp4";
+            generate_test_video(input, 90, 30)?;
+
+            let concat =
+                Concatenator::new(vec![Demuxer::new(input)?, Demuxer::new(input)?], output)?;
+            concat.run(None)?;
+
+            let (in_duration, in_packets) = count_packets(input)?;
+            let (out_duration, out_packets) = count_packets(output)?;
+            assert_eq!(in_packets * 2, out_packets);
+            assert!((out_duration - in_duration * 2.0).abs() < 0.1);
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{bail_ffmpeg, options_to_dict, rstr, StreamInfo};
+use crate::{bail_ffmpeg, cstr, options_to_dict, rstr, Encoder, FfmpegError, Muxer, StreamInfo};
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
@@ -6,14 +6,15 @@ use std::ptr;
 
 use anyhow::Error;
 use ffmpeg_sys_the_third::{
-    av_buffer_ref, av_frame_alloc, av_frame_free, av_hwdevice_ctx_create,
-    av_hwdevice_get_type_name, av_hwdevice_iterate_types, avcodec_alloc_context3,
-    avcodec_find_decoder, avcodec_free_context, avcodec_get_hw_config, avcodec_get_name,
-    avcodec_open2, avcodec_parameters_to_context, avcodec_receive_frame, avcodec_send_packet,
-    AVCodec, AVCodecContext, AVCodecHWConfig, AVFrame, AVHWDeviceType, AVPacket, AVStream, AVERROR,
-    AVERROR_EOF, AV_CODEC_HW_CONFIG_METHOD_HW_DEVICE_CTX,
+    av_frame_alloc, av_frame_free, av_hwdevice_ctx_create, av_hwdevice_get_type_name,
+    av_hwdevice_iterate_types, av_inv_q, av_rescale_q, avcodec_alloc_context3,
+    avcodec_find_decoder, avcodec_find_decoder_by_name, avcodec_free_context,
+    avcodec_get_hw_config, avcodec_get_name, avcodec_open2, avcodec_parameters_to_context,
+    avcodec_receive_frame, avcodec_send_packet, AVCodec, AVCodecContext, AVCodecHWConfig, AVFrame,
+    AVHWDeviceType, AVPacket, AVStream, AVERROR, AVERROR_EOF,
+    AV_CODEC_HW_CONFIG_METHOD_HW_DEVICE_CTX, AV_NOPTS_VALUE,
 };
-use log::trace;
+use log::{trace, warn};
 
 pub struct DecoderCodecContext {
     pub context: *mut AVCodecContext,
@@ -32,6 +33,22 @@ impl DecoderCodecContext {
         crate::list_opts(self.context as *mut libc::c_void)
     }
 
+    /// List the codec private options, excluding the generic [AVCodecContext] options
+    pub fn list_private_opts(&self) -> Result<Vec<String>, Error> {
+        unsafe {
+            if (*self.context).priv_data.is_null() {
+                return Ok(vec![]);
+            }
+            crate::list_opts((*self.context).priv_data)
+        }
+    }
+
+    /// Check if the decoder is using a hardware device, hardware decoding falls back to
+    /// software when no device of the enabled types is available for the codec
+    pub fn is_hardware(&self) -> bool {
+        !self.hw_config.is_null()
+    }
+
     /// Get the codec name
     pub fn codec_name(&self) -> String {
         let codec_name = unsafe { rstr!((*self.codec).name) };
@@ -70,6 +87,14 @@ pub struct Decoder {
     codecs: HashMap<i32, DecoderCodecContext>,
     /// List of [AVHWDeviceType] which are enabled
     hw_decoder_types: Option<HashSet<AVHWDeviceType>>,
+    /// Stamp decoded frames with the packet timestamps when missing
+    pkt_timestamps: bool,
+    /// Options applied to every decoder unless overridden
+    default_options: HashMap<String, String>,
+    /// Codec flags (AV_CODEC_FLAG_*) applied to every decoder
+    flags: u32,
+    /// Codec flags2 (AV_CODEC_FLAG2_*) applied to every decoder
+    flags2: u32,
 }
 
 impl Default for Decoder {
@@ -83,6 +108,10 @@ impl Decoder {
         Self {
             codecs: HashMap::new(),
             hw_decoder_types: None,
+            pkt_timestamps: false,
+            default_options: HashMap::new(),
+            flags: 0,
+            flags2: 0,
         }
     }
 
@@ -113,6 +142,30 @@ impl Decoder {
         self.hw_decoder_types = Some(res);
     }
 
+    /// Stamp decoded frames with the PTS/duration of the packet they were decoded from,
+    /// when the decoder does not set them
+    pub fn enable_packet_timestamps(&mut self, enable: bool) {
+        self.pkt_timestamps = enable;
+    }
+
+    /// Set options which are applied to every decoder setup after this call (eg. threads),
+    /// options passed when setting up a decoder take priority
+    pub fn set_default_options(&mut self, options: HashMap<String, String>) {
+        self.default_options = options;
+    }
+
+    /// Enable a codec flag (AV_CODEC_FLAG_*) on every decoder setup after this call,
+    /// eg. [AV_CODEC_FLAG_LOW_DELAY]
+    pub fn enable_flag(&mut self, flag: u32) {
+        self.flags |= flag;
+    }
+
+    /// Enable a codec flag2 (AV_CODEC_FLAG2_*) on every decoder setup after this call,
+    /// eg. [AV_CODEC_FLAG2_FAST]
+    pub fn enable_flag2(&mut self, flag: u32) {
+        self.flags2 |= flag;
+    }
+
     /// Set up a decoder for a given channel
     pub fn setup_decoder(
         &mut self,
@@ -122,6 +175,22 @@ impl Decoder {
         unsafe { self.setup_decoder_for_stream(channel.stream, options) }
     }
 
+    /// Set up a decoder for a given channel using a specific decoder by name (eg. h264_cuvid)
+    pub fn setup_decoder_by_name(
+        &mut self,
+        channel: &StreamInfo,
+        name: &str,
+        options: Option<HashMap<String, String>>,
+    ) -> Result<&mut DecoderCodecContext, Error> {
+        unsafe {
+            let codec = avcodec_find_decoder_by_name(cstr!(name));
+            if codec.is_null() {
+                anyhow::bail!("Failed to find decoder: {}", name)
+            }
+            self.setup_decoder_with_codec(channel.stream, codec, options)
+        }
+    }
+
     /// Get the codec context of a stream by stream index
     pub fn get_decoder(&self, stream: i32) -> Option<&DecoderCodecContext> {
         self.codecs.get(&stream)
@@ -166,14 +235,31 @@ impl Decoder {
             "Codec parameters are missing from stream"
         );
 
+        let codec = avcodec_find_decoder((*codec_par).codec_id);
+        if codec.is_null() {
+            anyhow::bail!(
+                "Failed to find codec: {}",
+                rstr!(avcodec_get_name((*codec_par).codec_id))
+            )
+        }
+        self.setup_decoder_with_codec(stream, codec, options)
+    }
+
+    /// Set up a decoder from an [AVStream] using a specific codec instance
+    pub unsafe fn setup_decoder_with_codec(
+        &mut self,
+        stream: *mut AVStream,
+        codec: *const AVCodec,
+        options: Option<HashMap<String, String>>,
+    ) -> Result<&mut DecoderCodecContext, Error> {
+        if stream.is_null() {
+            anyhow::bail!("stream is null");
+        }
+        if codec.is_null() {
+            anyhow::bail!("codec is null");
+        }
+
         if let Entry::Vacant(e) = self.codecs.entry((*stream).index) {
-            let codec = avcodec_find_decoder((*codec_par).codec_id);
-            if codec.is_null() {
-                anyhow::bail!(
-                    "Failed to find codec: {}",
-                    rstr!(avcodec_get_name((*codec_par).codec_id))
-                )
-            }
             let context = avcodec_alloc_context3(codec);
             if context.is_null() {
                 anyhow::bail!("Failed to alloc context")
@@ -181,6 +267,8 @@ impl Decoder {
 
             let mut ret = avcodec_parameters_to_context(context, (*stream).codecpar);
             bail_ffmpeg!(ret, "Failed to copy codec parameters to context");
+            (*context).flags |= self.flags as libc::c_int;
+            (*context).flags2 |= self.flags2 as libc::c_int;
 
             let codec_name = rstr!(avcodec_get_name((*codec).id));
             // try use HW decoder
@@ -208,14 +296,33 @@ impl Decoder {
                             ptr::null_mut(),
                             0,
                         );
-                        bail_ffmpeg!(ret, "Failed to create HW ctx");
-                        (*context).hw_device_ctx = av_buffer_ref(hw_buf_ref);
+                        if ret < 0 {
+                            // try the next device type, eg. no GPU present for this type
+                            warn!(
+                                "Failed to create HW device {}: {}",
+                                hw_name,
+                                FfmpegError::from_ret(ret)
+                            );
+                            continue;
+                        }
+                        // the context takes ownership of the device reference
+                        (*context).hw_device_ctx = hw_buf_ref;
                         break;
                     }
                 }
+                if hw_config.is_null() {
+                    warn!(
+                        "No hardware decoder available for {}, using software decoding",
+                        codec_name
+                    );
+                }
+            }
+            let mut opts = self.default_options.clone();
+            if let Some(options) = options {
+                opts.extend(options);
             }
-            let mut dict = if let Some(options) = options {
-                options_to_dict(options)?
+            let mut dict = if !opts.is_empty() {
+                options_to_dict(opts)?
             } else {
                 ptr::null_mut()
             };
@@ -240,11 +347,71 @@ impl Decoder {
     pub unsafe fn flush(&mut self) -> Result<Vec<*mut AVFrame>, Error> {
         let mut pkgs = Vec::new();
         for ctx in self.codecs.values_mut() {
-            pkgs.extend(Self::decode_pkt_internal(ctx.context, ptr::null_mut())?);
+            match Self::decode_pkt_internal(ctx.context, ptr::null_mut()) {
+                Ok(frames) => pkgs.extend(frames),
+                Err(e) => {
+                    for mut frame in pkgs {
+                        av_frame_free(&mut frame);
+                    }
+                    return Err(e);
+                }
+            }
         }
         Ok(pkgs)
     }
 
+    /// Flush all decoders, passing each remaining frame and its input stream to `f`
+    /// which takes ownership of the frame
+    ///
+    /// Returns the number of frames drained from the decoders
+    pub unsafe fn drain<F>(&mut self, mut f: F) -> Result<usize, Error>
+    where
+        F: FnMut(*mut AVStream, *mut AVFrame) -> Result<(), Error>,
+    {
+        let mut drained = 0;
+        for ctx in self.codecs.values_mut() {
+            let mut frames = Self::decode_pkt_internal(ctx.context, ptr::null_mut())?.into_iter();
+            while let Some(frame) = frames.next() {
+                drained += 1;
+                if let Err(e) = f(ctx.stream, frame) {
+                    for mut frame in frames {
+                        av_frame_free(&mut frame);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(drained)
+    }
+
+    /// Flush all decoders, encoding the remaining frames with the encoder of their
+    /// input stream (by stream index) and then flushing the encoders into `muxer`
+    ///
+    /// Frames are encoded as decoded, they must already match the encoder (size, format
+    /// and time base), use [Decoder::drain] to scale/resample them first.
+    /// Frames of streams without an encoder are dropped, returns the number of frames
+    /// drained from the decoders
+    pub unsafe fn drain_into(
+        &mut self,
+        muxer: &mut Muxer,
+        encoders: &mut HashMap<i32, Encoder>,
+    ) -> Result<usize, Error> {
+        let drained = self.drain(|stream, mut frame| {
+            let ret = if let Some(enc) = encoders.get_mut(&(*stream).index) {
+                muxer.write_encoded(enc, frame)
+            } else {
+                Ok(())
+            };
+            av_frame_free(&mut frame);
+            ret
+        })?;
+        // encoders are flushed after all decoders so no frames are lost
+        for enc in encoders.values_mut() {
+            muxer.write_encoded(enc, ptr::null_mut())?;
+        }
+        Ok(drained)
+    }
+
     pub unsafe fn decode_pkt_internal(
         ctx: *mut AVCodecContext,
         pkt: *mut AVPacket,
@@ -261,7 +428,11 @@ impl Decoder {
                 if ret == AVERROR_EOF || ret == AVERROR(libc::EAGAIN) {
                     break;
                 }
-                return Err(Error::msg(format!("Failed to decode {}", ret)));
+                bail_ffmpeg!(ret, "Failed to decode", {
+                    for mut frame in pkgs {
+                        av_frame_free(&mut frame);
+                    }
+                });
             }
             pkgs.push(frame);
         }
@@ -273,9 +444,412 @@ impl Decoder {
             return self.flush();
         }
         if let Some(ctx) = self.codecs.get_mut(&(*pkt).stream_index) {
-            Self::decode_pkt_internal(ctx.context, pkt)
+            let frames = Self::decode_pkt_internal(ctx.context, pkt)?;
+            if self.pkt_timestamps {
+                // frames drained by `pkt` may come from earlier packets (decoder delay or
+                // reordering), so only use the packet fields kept on the frame itself
+                let framerate = (*ctx.context).framerate;
+                let duration = if framerate.num > 0 && framerate.den > 0 && !ctx.stream.is_null() {
+                    av_rescale_q(1, av_inv_q(framerate), (*ctx.stream).time_base)
+                } else {
+                    0
+                };
+                for frame in &frames {
+                    if (**frame).pts == AV_NOPTS_VALUE {
+                        (**frame).pts = if (**frame).pkt_dts != AV_NOPTS_VALUE {
+                            (**frame).pkt_dts
+                        } else {
+                            (**frame).best_effort_timestamp
+                        };
+                    }
+                    #[cfg(feature = "avutil_version_greater_than_57_30")]
+                    if (**frame).duration == 0 {
+                        (**frame).duration = duration;
+                    }
+                    #[cfg(not(feature = "avutil_version_greater_than_57_30"))]
+                    if (**frame).pkt_duration == 0 {
+                        (**frame).pkt_duration = duration;
+                    }
+                }
+            }
+            Ok(frames)
         } else {
             Ok(vec![])
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        frame_is_keyframe, frame_picture_type, generate_test_frame, generate_test_video, Demuxer,
+        Encoder, FfmpegError, Scaler,
+    };
+    use ffmpeg_sys_the_third::{
+        av_buffer_get_ref_count, av_buffer_ref, av_buffer_unref, av_packet_free, AVCodecID,
+        AVPictureType, AVPixelFormat,
+    };
+
+    #[test]
+    fn test_decode_after_eof() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_decode_after_eof.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let mut decoder = Decoder::new();
+            decoder.setup_decoder(info.best_video().expect("no video stream"), None)?;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                for mut frame in decoder.decode_pkt(pkt)? {
+                    av_frame_free(&mut frame);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+
+            // decoder is already flushed
+            let err = decoder.decode_pkt(ptr::null_mut()).unwrap_err();
+            assert_eq!(err.downcast_ref::<FfmpegError>(), Some(&FfmpegError::Eof));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_picture_type() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_decode_picture_type.mp4";
+            generate_test_video(path, 30, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let mut decoder = Decoder::new();
+            decoder.setup_decoder(info.best_video().expect("no video stream"), None)?;
+            let mut keyframes = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                for mut frame in decoder.decode_pkt(pkt)? {
+                    if frame_picture_type(frame) == AVPictureType::AV_PICTURE_TYPE_I {
+                        assert!(frame_is_keyframe(frame));
+                        keyframes += 1;
+                    }
+                    av_frame_free(&mut frame);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            assert!(keyframes >= 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_packet_timestamps() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_packet_timestamps.mp4";
+            generate_test_video(path, 30, 10)?;
+
+            // returns the pts of the decoded frames and if any packet was reordered
+            let decode = |strip_pts: bool| -> Result<(Vec<i64>, bool), Error> {
+                let mut demux = Demuxer::new(path)?;
+                let info = demux.probe_input()?;
+                let mut decoder = Decoder::new();
+                decoder.enable_packet_timestamps(strip_pts);
+                decoder.setup_decoder(info.best_video().expect("no video stream"), None)?;
+                let mut pts = Vec::new();
+                let mut reordered = false;
+                loop {
+                    let (mut pkt, _) = demux.get_packet()?;
+                    if pkt.is_null() {
+                        break;
+                    }
+                    reordered |= (*pkt).pts != (*pkt).dts;
+                    if strip_pts {
+                        // simulate an input without frame timestamps
+                        (*pkt).pts = AV_NOPTS_VALUE;
+                    }
+                    for mut frame in decoder.decode_pkt(pkt)? {
+                        assert_ne!((*frame).pts, AV_NOPTS_VALUE);
+                        pts.push((*frame).pts);
+                        av_frame_free(&mut frame);
+                    }
+                    av_packet_free(&mut pkt);
+                }
+                Ok((pts, reordered))
+            };
+
+            let (expected, reordered) = decode(false)?;
+            // the test video has b-frames, so frames come out after later packets
+            assert!(reordered);
+            let (pts, _) = decode(true)?;
+            assert!(!pts.is_empty());
+            assert_eq!(pts, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires a hardware video decoder"]
+    fn test_download_hw_reuse() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_download_hw_reuse.mp4";
+            generate_test_video(path, 100, 30)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_video().expect("no video stream");
+            let mut decoder = Decoder::new();
+            decoder.enable_hw_decoder_any();
+            decoder.setup_decoder(stream, None)?;
+
+            let mut dst = av_frame_alloc();
+            let mut data = ptr::null_mut();
+            let mut downloaded = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                let frames = decoder.decode_pkt(pkt)?;
+                for mut frame in frames {
+                    assert!(!(*frame).hw_frames_ctx.is_null(), "not a hardware frame");
+                    crate::download_hw_into(frame, dst)?;
+                    if data.is_null() {
+                        data = (*dst).data[0];
+                    }
+                    assert_eq!(data, (*dst).data[0]);
+                    assert!((*dst).hw_frames_ctx.is_null());
+                    downloaded += 1;
+                    av_frame_free(&mut frame);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut dst);
+            assert_eq!(downloaded, 100);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_into() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_drain_into_input.mp4";
+            let out_path = "test_output/test_drain_into.mp4";
+            generate_test_video(path, 30, 30)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_video().expect("no video stream");
+            let mut decoder = Decoder::new();
+            decoder.setup_decoder(stream, None)?;
+
+            // decoded frames are encoded as is, so keep the input time base
+            let encoder = crate::test_encoder(stream.width as _, stream.height as _)?
+                .with_time_base((*stream.stream).time_base)?
+                .open(None)?;
+            let mut muxer = Muxer::builder()
+                .with_output_path(out_path, None)?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            let index = stream.index as i32;
+            let mut encoders = HashMap::from([(index, encoder)]);
+
+            let mut packets = 0;
+            let mut decoded = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                packets += 1;
+                for mut frame in decoder.decode_pkt(pkt)? {
+                    decoded += 1;
+                    muxer.write_encoded(encoders.get_mut(&index).unwrap(), frame)?;
+                    av_frame_free(&mut frame);
+                }
+                av_packet_free(&mut pkt);
+            }
+            let drained = decoder.drain_into(&mut muxer, &mut encoders)?;
+            muxer.close()?;
+            // the decoder delay is returned on drain
+            assert!(drained > 0);
+            assert_eq!(decoded + drained, packets);
+
+            let mut demux = Demuxer::new(out_path)?;
+            demux.probe_input()?;
+            let mut out_packets = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                out_packets += 1;
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(out_packets, packets);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decoded_frames_freed() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_decoded_frames_freed.mp4";
+            generate_test_video(path, 30, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let mut decoder = Decoder::new();
+            decoder.setup_decoder(info.best_video().expect("no video stream"), None)?;
+
+            // hold a reference to the buffer of every decoded frame
+            let mut buffers = Vec::new();
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                for mut frame in decoder.decode_pkt(pkt)? {
+                    buffers.push(av_buffer_ref((*frame).buf[0]));
+                    av_frame_free(&mut frame);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            drop(decoder);
+
+            // only our reference is left when all frames are freed
+            assert_eq!(buffers.len(), 30);
+            for mut buf in buffers {
+                assert_eq!(av_buffer_get_ref_count(buf), 1);
+                av_buffer_unref(&mut buf);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_options() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_default_options.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_video().expect("no video stream");
+
+            let mut decoder = Decoder::new();
+            decoder.set_default_options(HashMap::from([(
+                "strict".to_string(),
+                "experimental".to_string(),
+            )]));
+            let ctx = decoder.setup_decoder(stream, None)?;
+            assert_eq!((*ctx.context).strict_std_compliance, -2);
+
+            // per-stream options override the defaults
+            let mut decoder = Decoder::new();
+            decoder.set_default_options(HashMap::from([
+                ("strict".to_string(), "experimental".to_string()),
+                ("threads".to_string(), "2".to_string()),
+            ]));
+            let ctx = decoder.setup_decoder(
+                stream,
+                Some(HashMap::from([(
+                    "strict".to_string(),
+                    "strict".to_string(),
+                )])),
+            )?;
+            assert_eq!((*ctx.context).strict_std_compliance, 1);
+            assert_eq!((*ctx.context).thread_count, 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decoder_by_name() -> Result<(), Error> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_decoder_by_name.jpg";
+            let mut frame = generate_test_frame();
+            let mut scaler = Scaler::new();
+            let mut jpeg_frame = scaler.process_frame(
+                frame,
+                (*frame).width as u16,
+                (*frame).height as u16,
+                AVPixelFormat::AV_PIX_FMT_YUVJ420P,
+            )?;
+            Encoder::new(AVCodecID::AV_CODEC_ID_MJPEG)?
+                .with_width((*jpeg_frame).width)
+                .with_height((*jpeg_frame).height)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_YUVJ420P)
+                .open(None)?
+                .save_picture(jpeg_frame, path)?;
+            av_frame_free(&mut frame);
+            av_frame_free(&mut jpeg_frame);
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_video().expect("no video stream");
+
+            let mut decoder = Decoder::new();
+            let ctx = decoder.setup_decoder_by_name(stream, "mjpeg", None)?;
+            assert_eq!(ctx.codec_name(), "mjpeg");
+
+            let mut frames = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                for mut frame in decoder.decode_pkt(pkt)? {
+                    assert_eq!((*frame).width, 1024);
+                    frames += 1;
+                    av_frame_free(&mut frame);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(frames, 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_hw_fallback() -> Result<(), Error> {
+        unsafe {
+            let path = "test_output/test_hw_fallback.mp4";
+            generate_test_video(path, 10, 10)?;
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let video = info.best_video().expect("no video stream");
+
+            // the h264 decoder has no DRM hwaccel
+            let mut decoder = Decoder::new();
+            decoder.enable_hw_decoder(AVHWDeviceType::AV_HWDEVICE_TYPE_DRM);
+            let ctx = decoder.setup_decoder(video, None)?;
+            assert!(!ctx.is_hardware());
+            assert_eq!(ctx.codec_name(), "h264");
+
+            let mut frames = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                for mut frame in decoder.decode_pkt(pkt)? {
+                    assert!((*frame).hw_frames_ctx.is_null());
+                    frames += 1;
+                    av_frame_free(&mut frame);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(frames, 10);
+        }
+        Ok(())
+    }
+}
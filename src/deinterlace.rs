@@ -0,0 +1,154 @@
+use crate::{frame_is_interlaced, Filter};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{AVFrame, AVRational};
+
+/// Filter used for deinterlacing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeinterlaceFilter {
+    Yadif,
+    Bwdif,
+}
+
+/// Converts interlaced frames to progressive frames using the yadif/bwdif filter
+///
+/// Only frames flagged as interlaced are deinterlaced, progressive frames pass through
+pub struct Deinterlacer {
+    kind: DeinterlaceFilter,
+    /// Output one frame per field (doubling the frame rate)
+    double_rate: bool,
+    time_base: AVRational,
+    framerate: AVRational,
+    filter: Option<Filter>,
+}
+
+impl Deinterlacer {
+    /// Create a deinterlacer for frames with timestamps in `time_base`
+    pub fn new(kind: DeinterlaceFilter, time_base: AVRational, framerate: AVRational) -> Self {
+        Self {
+            kind,
+            double_rate: false,
+            time_base,
+            framerate,
+            filter: None,
+        }
+    }
+
+    /// Output a frame for each field instead of each frame, doubling the frame rate
+    pub fn with_double_rate(mut self, double_rate: bool) -> Self {
+        self.double_rate = double_rate;
+        self
+    }
+
+    /// Check if a frame needs deinterlacing
+    pub unsafe fn is_interlaced(frame: *const AVFrame) -> bool {
+        frame_is_interlaced(frame)
+    }
+
+    /// Process a frame, returning zero or more progressive frames
+    ///
+    /// The input frame is not consumed, output frames must be freed by the caller
+    pub unsafe fn process_frame(&mut self, frame: *mut AVFrame) -> Result<Vec<*mut AVFrame>> {
+        if self.filter.is_none() {
+            let graph = format!(
+                "{}=mode={}:parity=auto:deint=interlaced",
+                match self.kind {
+                    DeinterlaceFilter::Yadif => "yadif",
+                    DeinterlaceFilter::Bwdif => "bwdif",
+                },
+                if self.double_rate {
+                    "send_field"
+                } else {
+                    "send_frame"
+                }
+            );
+            self.filter = Some(Filter::parse_video(
+                &graph,
+                frame,
+                self.time_base,
+                self.framerate,
+            )?);
+        }
+        if let Some(filter) = self.filter.as_mut() {
+            filter.process_frame(frame)
+        } else {
+            bail!("Deinterlace filter is not initialized");
+        }
+    }
+
+    /// Flush the frames buffered by the filter
+    pub unsafe fn flush(&mut self) -> Result<Vec<*mut AVFrame>> {
+        if let Some(filter) = self.filter.as_mut() {
+            filter.flush()
+        } else {
+            Ok(vec![])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frame_plane_mut, generate_test_frame, Scaler};
+    use ffmpeg_sys_the_third::{av_frame_free, AVPixelFormat};
+    #[cfg(feature = "avutil_version_greater_than_58_22")]
+    use ffmpeg_sys_the_third::{AV_FRAME_FLAG_INTERLACED, AV_FRAME_FLAG_TOP_FIELD_FIRST};
+
+    unsafe fn deinterlace(frame: *mut AVFrame, double_rate: bool) -> Result<usize> {
+        let mut deint = Deinterlacer::new(
+            DeinterlaceFilter::Yadif,
+            AVRational { num: 1, den: 25 },
+            AVRational { num: 25, den: 1 },
+        )
+        .with_double_rate(double_rate);
+        let mut out = Vec::new();
+        for pts in 0..3 {
+            (*frame).pts = pts;
+            out.extend(deint.process_frame(frame)?);
+        }
+        out.extend(deint.flush()?);
+        let count = out.len();
+        for mut f in out {
+            assert!(!frame_is_interlaced(f));
+            av_frame_free(&mut f);
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn deinterlace_yadif() -> Result<()> {
+        unsafe {
+            let mut src_frame = generate_test_frame();
+            let mut frame = Scaler::new().process_frame(
+                src_frame,
+                256,
+                256,
+                AVPixelFormat::AV_PIX_FMT_YUV420P,
+            )?;
+            // alternate the lines of the top and bottom fields
+            let stride = (*frame).linesize[0] as usize;
+            for (line, row) in frame_plane_mut(frame, 0)?.chunks_mut(stride).enumerate() {
+                row.fill(if line % 2 == 0 { 0x20 } else { 0xe0 });
+            }
+            #[cfg(feature = "avutil_version_greater_than_58_22")]
+            {
+                (*frame).flags |=
+                    (AV_FRAME_FLAG_INTERLACED | AV_FRAME_FLAG_TOP_FIELD_FIRST) as libc::c_int;
+            }
+            #[cfg(not(feature = "avutil_version_greater_than_58_22"))]
+            {
+                (*frame).interlaced_frame = 1;
+                (*frame).top_field_first = 1;
+            }
+            assert!(Deinterlacer::is_interlaced(frame));
+
+            let frames = deinterlace(frame, false)?;
+            assert_eq!(frames, 3);
+            let fields = deinterlace(frame, true)?;
+            assert_eq!(fields, frames * 2);
+
+            av_frame_free(&mut frame);
+            av_frame_free(&mut src_frame);
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,5 @@
-use crate::{bail_ffmpeg, cstr, rstr};
-use crate::{DemuxerInfo, StreamInfo, StreamType};
+use crate::{bail_ffmpeg, options_to_dict, rstr};
+use crate::{Attachment, DemuxerInfo, FieldOrder, StreamInfo, StreamType};
 #[cfg(feature = "avformat_version_greater_than_60_19")]
 use crate::{StreamGroupInfo, StreamGroupType};
 use anyhow::{bail, Error, Result};
@@ -7,9 +7,11 @@ use anyhow::{bail, Error, Result};
 use ffmpeg_sys_the_third::AVStreamGroupParamsType::AV_STREAM_GROUP_PARAMS_TILE_GRID;
 use ffmpeg_sys_the_third::*;
 use log::warn;
-use slimbox::{slimbox_unsize, SlimBox, SlimMut};
+use slimbox::{slimbox_unsize, SlimBox};
 use std::collections::HashMap;
-use std::io::Read;
+use std::ffi::{CStr, CString};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::time::Duration;
 use std::{ptr, slice};
 
 #[no_mangle]
@@ -18,25 +20,136 @@ unsafe extern "C" fn read_data(
     dst_buffer: *mut libc::c_uchar,
     size: libc::c_int,
 ) -> libc::c_int {
-    let mut buffer: SlimMut<'_, dyn Read + 'static> = SlimMut::from_raw(opaque);
-    let dst_slice: &mut [u8] = slice::from_raw_parts_mut(dst_buffer, size as usize);
-    match buffer.read(dst_slice) {
-        Ok(r) => r as libc::c_int,
+    if size <= 0 {
+        return 0;
+    }
+    let input = &mut *(opaque as *mut StreamInput);
+    read_into(
+        &mut *input.reader,
+        input.retry,
+        slice::from_raw_parts_mut(dst_buffer, size as usize),
+    )
+}
+
+unsafe extern "C" fn read_seekable_data(
+    opaque: *mut libc::c_void,
+    dst_buffer: *mut libc::c_uchar,
+    size: libc::c_int,
+) -> libc::c_int {
+    if size <= 0 {
+        return 0;
+    }
+    let input = &mut *(opaque as *mut SeekableInput);
+    read_into(
+        &mut input.reader,
+        input.retry,
+        slice::from_raw_parts_mut(dst_buffer, size as usize),
+    )
+}
+
+unsafe extern "C" fn seek_input(
+    opaque: *mut libc::c_void,
+    offset: i64,
+    whence: libc::c_int,
+) -> i64 {
+    let input = &mut *(opaque as *mut SeekableInput);
+    let whence = whence & !(AVSEEK_FORCE as libc::c_int);
+    if whence & AVSEEK_SIZE as libc::c_int != 0 {
+        return input.size as i64;
+    }
+    let pos = match whence {
+        libc::SEEK_SET => SeekFrom::Start(offset as u64),
+        libc::SEEK_CUR => SeekFrom::Current(offset),
+        libc::SEEK_END => SeekFrom::End(offset),
+        _ => return AVERROR(libc::EINVAL) as i64,
+    };
+    match input.reader.seek(pos) {
+        Ok(p) => p as i64,
         Err(e) => {
-            eprintln!("read_data {}", e);
-            AVERROR_EOF
+            warn!("seek_input {}", e);
+            AVERROR(libc::EIO) as i64
+        }
+    }
+}
+
+/// Read from `reader` into `dst_slice`, returning the number of bytes read or an AVERROR
+fn read_into(reader: &mut dyn Read, retry: ReadRetry, dst_slice: &mut [u8]) -> libc::c_int {
+    let mut retries = 0;
+    loop {
+        match reader.read(dst_slice) {
+            // a read of 0 bytes into a non-empty buffer is EOF
+            Ok(0) => return AVERROR_EOF,
+            Ok(r) => return r as libc::c_int,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            // retried here rather than in the demuxer so that no buffered data is dropped,
+            // see [Demuxer::with_read_retry]
+            Err(e)
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && retries < retry.count =>
+            {
+                retries += 1;
+                warn!("read_data {}, retrying ({}/{})", e, retries, retry.count);
+                std::thread::sleep(retry.delay);
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return AVERROR(libc::EAGAIN),
+            Err(e) if e.kind() == ErrorKind::TimedOut => return AVERROR(libc::ETIMEDOUT),
+            Err(e) => {
+                warn!("read_data {}", e);
+                return AVERROR_EOF;
+            }
         }
     }
 }
 
+/// A [Read] + [Seek] input for [Demuxer::with_custom_io_and_seek_and_size],
+/// implemented for every type which is both
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Retry policy for reads which block or time out, see [Demuxer::with_read_retry]
+#[derive(Clone, Copy, Default)]
+struct ReadRetry {
+    count: u32,
+    delay: Duration,
+}
+
+/// Non-seekable reader, the opaque of the AVIO context
+struct StreamInput {
+    reader: SlimBox<dyn Read + 'static>,
+    retry: ReadRetry,
+}
+
+/// Seekable reader with a known total size, the opaque of the AVIO context
+struct SeekableInput {
+    reader: Box<dyn ReadSeek + 'static>,
+    /// Total size in bytes, reported for [AVSEEK_SIZE]
+    size: u64,
+    retry: ReadRetry,
+}
+
 pub enum DemuxerInput {
     Url(String),
     Reader(Option<SlimBox<dyn Read + 'static>>, Option<String>),
+    /// Seekable reader with its total size in bytes
+    ReaderSeeker(Option<Box<dyn ReadSeek + 'static>>, u64, Option<String>),
 }
 
 pub struct Demuxer {
     ctx: *mut AVFormatContext,
     input: DemuxerInput,
+    /// Options passed to [avformat_open_input], reaching the demuxer and protocol
+    open_options: HashMap<String, String>,
+    /// Retry policy of custom IO reads
+    read_retry: ReadRetry,
+    /// Input format to use instead of probing
+    input_format: *const AVInputFormat,
+    /// Number of times opening the input is retried, see [Demuxer::with_open_retry]
+    open_retries: u32,
+    /// Delay between open retries
+    open_retry_delay: Duration,
+    /// Options applied with [Demuxer::set_opt], applied again when the context is reallocated
+    format_options: HashMap<String, String>,
 }
 
 impl Demuxer {
@@ -50,6 +163,12 @@ impl Demuxer {
             Ok(Self {
                 ctx,
                 input: DemuxerInput::Url(input.to_string()),
+                open_options: HashMap::new(),
+                read_retry: ReadRetry::default(),
+                input_format: ptr::null(),
+                open_retries: 0,
+                open_retry_delay: Duration::ZERO,
+                format_options: HashMap::new(),
             })
         }
     }
@@ -66,65 +185,300 @@ impl Demuxer {
             Ok(Self {
                 ctx,
                 input: DemuxerInput::Reader(Some(slimbox_unsize!(reader)), url),
+                open_options: HashMap::new(),
+                read_retry: ReadRetry::default(),
+                input_format: ptr::null(),
+                open_retries: 0,
+                open_retry_delay: Duration::ZERO,
+                format_options: HashMap::new(),
+            })
+        }
+    }
+
+    /// Create a new [Demuxer] from a seekable reader whose total size is known upfront
+    /// (eg. from an HTTP Content-Length)
+    ///
+    /// The size is reported to FFmpeg so it can seek relative to the end of the input
+    /// (eg. to find the moov of an MP4) without asking the reader for its length
+    pub fn with_custom_io_and_seek_and_size<R: Read + Seek + 'static>(
+        reader: R,
+        size: u64,
+        url: Option<String>,
+    ) -> Result<Self> {
+        unsafe {
+            let ctx = avformat_alloc_context();
+            if ctx.is_null() {
+                bail!("Failed to allocate AV context");
+            }
+            (*ctx).flags |= AVFMT_FLAG_CUSTOM_IO;
+
+            Ok(Self {
+                ctx,
+                input: DemuxerInput::ReaderSeeker(Some(Box::new(reader)), size, url),
+                open_options: HashMap::new(),
+                read_retry: ReadRetry::default(),
+                input_format: ptr::null(),
+                open_retries: 0,
+                open_retry_delay: Duration::ZERO,
+                format_options: HashMap::new(),
             })
         }
     }
 
+    /// Set [AVFormatContext] flags (AVFMT_FLAG_*), eg. [AVFMT_FLAG_GENPTS] to generate
+    /// missing packet PTS, must be set before probing
+    pub fn with_flags(self, flags: i32) -> Self {
+        unsafe {
+            (*self.ctx).flags |= flags;
+        }
+        self
+    }
+
+    /// Force a specific input format instead of probing the input,
+    /// eg. from [av_find_input_format] or a custom registered format
+    pub fn with_input_format(mut self, format: *const AVInputFormat) -> Self {
+        self.input_format = format;
+        self
+    }
+
+    /// Set options used when opening the input, these are passed to the demuxer
+    /// and the protocol (eg. http `reconnect`, `user_agent`)
+    pub fn with_open_options(mut self, options: HashMap<String, String>) -> Self {
+        self.open_options.extend(options);
+        self
+    }
+
+    /// Reconnect http inputs when the connection drops
+    pub fn with_reconnect(self) -> Self {
+        self.with_open_options(HashMap::from([
+            ("reconnect".to_string(), "1".to_string()),
+            ("reconnect_streamed".to_string(), "1".to_string()),
+            ("reconnect_on_network_error".to_string(), "1".to_string()),
+        ]))
+    }
+
+    /// Only allow the given comma separated list of protocols (eg. `file,concat`)
+    /// to be used when opening the input and any nested inputs
+    pub fn with_protocol_whitelist(self, protocols: &str) -> Self {
+        self.with_open_options(HashMap::from([(
+            "protocol_whitelist".to_string(),
+            protocols.to_string(),
+        )]))
+    }
+
+    /// Disallow the given comma separated list of protocols (eg. `http,https`)
+    pub fn with_protocol_blacklist(self, protocols: &str) -> Self {
+        self.with_open_options(HashMap::from([(
+            "protocol_blacklist".to_string(),
+            protocols.to_string(),
+        )]))
+    }
+
+    /// Retry reads from a custom IO reader up to `count` times with `delay` between
+    /// attempts when it fails with [ErrorKind::WouldBlock] or [ErrorKind::TimedOut]
+    ///
+    /// Without retries these fail the read with `EAGAIN` / `ETIMEDOUT`
+    ///
+    /// Url inputs are retried by the protocol instead, see [Demuxer::with_reconnect]
+    pub fn with_read_retry(mut self, count: u32, delay: Duration) -> Self {
+        self.read_retry = ReadRetry { count, delay };
+        self
+    }
+
+    /// Limit how much of the input is read by [Demuxer::probe_input], `probesize` in bytes
+    ///
+    /// Useful for inputs which are still being written, where probing would otherwise
+    /// read up to the end of the incomplete data
+    pub fn with_probe_limits(self, probesize: i64, max_analyze_duration: Duration) -> Self {
+        unsafe {
+            (*self.ctx).probesize = probesize;
+            (*self.ctx).max_analyze_duration = max_analyze_duration.as_micros() as i64;
+        }
+        self
+    }
+
+    /// Retry opening a url/file input up to `count` times with `delay` between attempts,
+    /// eg. for a recording where the header (or mp4 moov) is not written yet
+    ///
+    /// Custom IO inputs are not retried as the reader is consumed by the first attempt
+    pub fn with_open_retry(mut self, count: u32, delay: Duration) -> Self {
+        self.open_retries = count;
+        self.open_retry_delay = delay;
+        self
+    }
+
     /// Set [AVFormatContext] options
     pub fn set_opt(&mut self, options: HashMap<String, String>) -> Result<(), Error> {
-        crate::set_opts(self.ctx as *mut libc::c_void, options)
+        crate::set_opts(self.ctx as *mut libc::c_void, options.clone())?;
+        self.format_options.extend(options);
+        Ok(())
+    }
+
+    /// Allocate a new context for another open attempt, a failed open frees the context
+    unsafe fn realloc_context(&mut self, flags: i32, probesize: i64, analyze: i64) -> Result<()> {
+        self.ctx = avformat_alloc_context();
+        if self.ctx.is_null() {
+            bail!("Failed to allocate AV context");
+        }
+        (*self.ctx).flags = flags;
+        (*self.ctx).probesize = probesize;
+        (*self.ctx).max_analyze_duration = analyze;
+        crate::set_opts(self.ctx as *mut libc::c_void, self.format_options.clone())
     }
 
     unsafe fn open(&mut self) -> Result<()> {
-        match &mut self.input {
-            DemuxerInput::Url(input) => {
-                let ret = avformat_open_input(
-                    &mut self.ctx,
-                    cstr!(input.as_str()),
-                    ptr::null_mut(),
-                    ptr::null_mut(),
-                );
-                bail_ffmpeg!(ret);
-                Ok(())
-            }
-            DemuxerInput::Reader(input, url) => {
-                let input = input.take().expect("input stream already taken");
-                const BUFFER_SIZE: usize = 4096;
-                let pb = avio_alloc_context(
-                    av_mallocz(BUFFER_SIZE) as *mut libc::c_uchar,
-                    BUFFER_SIZE as libc::c_int,
-                    0,
-                    input.into_raw(),
-                    Some(read_data),
-                    None,
-                    None,
-                );
-                if pb.is_null() {
-                    bail!("failed to allocate avio context");
+        let mut retries = 0;
+        loop {
+            // the context is freed when opening fails, keep its settings for the next attempt
+            let (flags, probesize, analyze) = (
+                (*self.ctx).flags,
+                (*self.ctx).probesize,
+                (*self.ctx).max_analyze_duration,
+            );
+            let ret = self.open_once();
+            let can_retry = matches!(self.input, DemuxerInput::Url(_)) && self.ctx.is_null();
+            match ret {
+                Err(e) if can_retry && retries < self.open_retries => {
+                    retries += 1;
+                    warn!(
+                        "Failed to open input: {}, retrying ({}/{})",
+                        e, retries, self.open_retries
+                    );
+                    std::thread::sleep(self.open_retry_delay);
+                    self.realloc_context(flags, probesize, analyze)?;
                 }
+                ret => return ret,
+            }
+        }
+    }
 
-                (*self.ctx).pb = pb;
-                let ret = avformat_open_input(
-                    &mut self.ctx,
-                    if let Some(url) = url {
-                        cstr!(url.as_str())
-                    } else {
-                        ptr::null_mut()
-                    },
-                    ptr::null_mut(),
-                    ptr::null_mut(),
+    unsafe fn open_once(&mut self) -> Result<()> {
+        let mut options = options_to_dict(self.open_options.clone())?;
+        let ret = self.open_input(&mut options);
+        if !options.is_null() {
+            // options which were not consumed by the demuxer or protocol
+            let mut entry = ptr::null();
+            loop {
+                entry = av_dict_get(
+                    options,
+                    c"".as_ptr(),
+                    entry,
+                    AV_DICT_IGNORE_SUFFIX as libc::c_int,
                 );
-                bail_ffmpeg!(ret);
-                Ok(())
+                if entry.is_null() {
+                    break;
+                }
+                warn!("Unused open option {}", rstr!((*entry).key));
+            }
+            av_dict_free(&mut options);
+        }
+        ret
+    }
+
+    unsafe fn open_input(&mut self, options: *mut *mut AVDictionary) -> Result<()> {
+        type ReadPacket =
+            unsafe extern "C" fn(*mut libc::c_void, *mut libc::c_uchar, libc::c_int) -> libc::c_int;
+        type SeekPacket = unsafe extern "C" fn(*mut libc::c_void, i64, libc::c_int) -> i64;
+
+        let (opaque, read, seek, url): (_, ReadPacket, Option<SeekPacket>, _) =
+            match &mut self.input {
+                DemuxerInput::Url(input) => {
+                    // owned so that it's freed on every return path
+                    let url = CString::new(input.as_str())?;
+                    let ret = avformat_open_input(
+                        &mut self.ctx,
+                        url.as_ptr(),
+                        self.input_format as _,
+                        options,
+                    );
+                    bail_ffmpeg!(ret);
+                    return Ok(());
+                }
+                DemuxerInput::Reader(input, url) => {
+                    let url = url.as_deref().map(CString::new).transpose()?;
+                    let input = Box::new(StreamInput {
+                        reader: input.take().expect("input stream already taken"),
+                        retry: self.read_retry,
+                    });
+                    (
+                        Box::into_raw(input) as *mut libc::c_void,
+                        read_data,
+                        None,
+                        url,
+                    )
+                }
+                DemuxerInput::ReaderSeeker(reader, size, url) => {
+                    let url = url.as_deref().map(CString::new).transpose()?;
+                    let input = Box::new(SeekableInput {
+                        reader: reader.take().expect("input stream already taken"),
+                        size: *size,
+                        retry: self.read_retry,
+                    });
+                    (
+                        Box::into_raw(input) as *mut libc::c_void,
+                        read_seekable_data,
+                        Some(seek_input),
+                        url,
+                    )
+                }
+            };
+
+        const BUFFER_SIZE: usize = 4096;
+        let buffer = av_mallocz(BUFFER_SIZE) as *mut libc::c_uchar;
+        let mut pb = avio_alloc_context(
+            buffer,
+            BUFFER_SIZE as libc::c_int,
+            0,
+            opaque,
+            Some(read),
+            None,
+            seek,
+        );
+        if pb.is_null() {
+            av_free(buffer as *mut _);
+            self.free_opaque(opaque);
+            bail!("failed to allocate avio context");
+        }
+
+        (*self.ctx).pb = pb;
+        let ret = avformat_open_input(
+            &mut self.ctx,
+            url.as_ref().map_or(ptr::null(), |u| u.as_ptr()),
+            self.input_format as _,
+            options,
+        );
+        // custom IO is not freed with the context
+        bail_ffmpeg!(ret, {
+            self.free_io(&mut pb);
+        });
+        Ok(())
+    }
+
+    /// Free the opaque of a custom IO context
+    unsafe fn free_opaque(&self, opaque: *mut libc::c_void) {
+        match self.input {
+            DemuxerInput::Reader(_, _) => drop(Box::from_raw(opaque as *mut StreamInput)),
+            DemuxerInput::ReaderSeeker(_, _, _) => {
+                drop(Box::from_raw(opaque as *mut SeekableInput))
             }
+            DemuxerInput::Url(_) => {}
+        }
+    }
+
+    /// Free a custom IO context with its buffer and opaque
+    unsafe fn free_io(&self, pb: &mut *mut AVIOContext) {
+        if pb.is_null() {
+            return;
         }
+        av_free((**pb).buffer as *mut _);
+        self.free_opaque((**pb).opaque);
+        avio_context_free(pb);
     }
 
     pub unsafe fn probe_input(&mut self) -> Result<DemuxerInfo, Error> {
         self.open()?;
-        if avformat_find_stream_info(self.ctx, ptr::null_mut()) < 0 {
-            return Err(Error::msg("Could not find stream info"));
-        }
+        let ret = avformat_find_stream_info(self.ctx, ptr::null_mut());
+        bail_ffmpeg!(ret, "Could not find stream info");
 
         let mut streams = vec![];
         #[cfg(feature = "avformat_version_greater_than_60_19")]
@@ -163,7 +517,7 @@ impl Demuxer {
         while n_stream < (*self.ctx).nb_streams as usize {
             let stream = *(*self.ctx).streams.add(n_stream);
             n_stream += 1;
-            let lang = av_dict_get((*stream).metadata, cstr!("language"), ptr::null_mut(), 0);
+            let lang = av_dict_get((*stream).metadata, c"language".as_ptr(), ptr::null_mut(), 0);
             let language = if lang.is_null() {
                 "".to_string()
             } else {
@@ -175,13 +529,18 @@ impl Demuxer {
                         stream,
                         index: (*stream).index as usize,
                         codec: (*(*stream).codecpar).codec_id as isize,
+                        codec_tag: (*(*stream).codecpar).codec_tag,
+                        disposition: (*stream).disposition,
                         stream_type: StreamType::Video,
                         width: (*(*stream).codecpar).width as usize,
                         height: (*(*stream).codecpar).height as usize,
                         fps: av_q2d((*stream).avg_frame_rate) as f32,
+                        field_order: (*(*stream).codecpar).field_order.into(),
+                        rotation: Self::stream_rotation(stream),
                         format: (*(*stream).codecpar).format as isize,
                         sample_rate: 0,
                         language,
+                        is_best: false,
                     });
                 }
                 AVMediaType::AVMEDIA_TYPE_AUDIO => {
@@ -189,13 +548,18 @@ impl Demuxer {
                         stream,
                         index: (*stream).index as usize,
                         codec: (*(*stream).codecpar).codec_id as isize,
+                        codec_tag: (*(*stream).codecpar).codec_tag,
+                        disposition: (*stream).disposition,
                         stream_type: StreamType::Audio,
                         width: (*(*stream).codecpar).width as usize,
                         height: (*(*stream).codecpar).height as usize,
                         fps: 0.0,
+                        field_order: FieldOrder::Unknown,
+                        rotation: 0.0,
                         format: (*(*stream).codecpar).format as isize,
                         sample_rate: (*(*stream).codecpar).sample_rate as usize,
                         language,
+                        is_best: false,
                     });
                 }
                 AVMediaType::AVMEDIA_TYPE_SUBTITLE => {
@@ -203,13 +567,37 @@ impl Demuxer {
                         stream,
                         index: (*stream).index as usize,
                         codec: (*(*stream).codecpar).codec_id as isize,
+                        codec_tag: (*(*stream).codecpar).codec_tag,
+                        disposition: (*stream).disposition,
                         stream_type: StreamType::Subtitle,
                         width: 0,
                         height: 0,
                         fps: 0.0,
+                        field_order: FieldOrder::Unknown,
+                        rotation: 0.0,
                         format: 0,
                         sample_rate: 0,
                         language,
+                        is_best: false,
+                    });
+                }
+                AVMediaType::AVMEDIA_TYPE_DATA => {
+                    streams.push(StreamInfo {
+                        stream,
+                        index: (*stream).index as usize,
+                        codec: (*(*stream).codecpar).codec_id as isize,
+                        codec_tag: (*(*stream).codecpar).codec_tag,
+                        disposition: (*stream).disposition,
+                        stream_type: StreamType::Data,
+                        width: 0,
+                        height: 0,
+                        fps: 0.0,
+                        field_order: FieldOrder::Unknown,
+                        rotation: 0.0,
+                        format: 0,
+                        sample_rate: 0,
+                        language,
+                        is_best: false,
                     });
                 }
                 AVMediaType::AVMEDIA_TYPE_ATTACHMENT => {}
@@ -218,7 +606,7 @@ impl Demuxer {
             }
         }
 
-        let info = DemuxerInfo {
+        let mut info = DemuxerInfo {
             duration: (*self.ctx).duration as f32 / AV_TIME_BASE as f32,
             bitrate: (*self.ctx).bit_rate as usize,
             format: rstr!((*(*self.ctx).iformat).name).to_string(),
@@ -227,9 +615,94 @@ impl Demuxer {
             #[cfg(feature = "avformat_version_greater_than_60_19")]
             groups: stream_groups,
         };
+        info.mark_best();
         Ok(info)
     }
 
+    /// Probe the input and decode the first frame of the best video stream,
+    /// eg. to generate a poster image
+    ///
+    /// With `hw_decode` any available hardware decoder is used and the frame is downloaded
+    /// to system memory, the returned frame must be freed by the caller
+    pub unsafe fn read_to_info_and_first_frame(
+        &mut self,
+        hw_decode: bool,
+    ) -> Result<(DemuxerInfo, *mut AVFrame)> {
+        use crate::{get_frame_from_hw, Decoder};
+
+        let info = self.probe_input()?;
+        let video = if let Some(v) = info.best_video() {
+            v
+        } else {
+            bail!("No video stream in input");
+        };
+        let mut decoder = Decoder::new();
+        if hw_decode {
+            decoder.enable_hw_decoder_any();
+        }
+        decoder.setup_decoder(video, None)?;
+
+        let stream_index = video.index as i32;
+        loop {
+            let (mut pkt, _) = self.get_packet()?;
+            if !pkt.is_null() && (*pkt).stream_index != stream_index {
+                av_packet_free(&mut pkt);
+                continue;
+            }
+            // a null packet flushes the decoder at the end of the input
+            let frames = decoder.decode_pkt(pkt);
+            let eof = pkt.is_null();
+            av_packet_free(&mut pkt);
+            let mut frames = frames?.into_iter();
+            if let Some(frame) = frames.next() {
+                for mut f in frames {
+                    av_frame_free(&mut f);
+                }
+                let frame = get_frame_from_hw(frame)?;
+                return Ok((info, frame));
+            }
+            if eof {
+                bail!("No video frame could be decoded");
+            }
+        }
+    }
+
+    /// Get the rotation of a video stream from its display matrix side data
+    unsafe fn stream_rotation(stream: *mut AVStream) -> f32 {
+        #[cfg(feature = "avformat_version_greater_than_60_19")]
+        let matrix = {
+            let par = (*stream).codecpar;
+            let sd = av_packet_side_data_get(
+                (*par).coded_side_data,
+                (*par).nb_coded_side_data,
+                AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+            );
+            if sd.is_null() || (*sd).size < 9 * std::mem::size_of::<i32>() {
+                return 0.0;
+            }
+            (*sd).data as *const i32
+        };
+        #[cfg(not(feature = "avformat_version_greater_than_60_19"))]
+        let matrix = {
+            let mut size = 0;
+            let data = av_stream_get_side_data(
+                stream,
+                AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX,
+                &mut size,
+            );
+            if data.is_null() || (size as usize) < 9 * std::mem::size_of::<i32>() {
+                return 0.0;
+            }
+            data as *const i32
+        };
+        let rotation = av_display_rotation_get(matrix);
+        if rotation.is_nan() {
+            0.0
+        } else {
+            rotation as f32
+        }
+    }
+
     pub unsafe fn get_packet(&mut self) -> Result<(*mut AVPacket, *mut AVStream), Error> {
         let mut pkt = av_packet_alloc();
         let ret = av_read_frame(self.ctx, pkt);
@@ -244,16 +717,281 @@ impl Demuxer {
         let pkg = (pkt, stream);
         Ok(pkg)
     }
+
+    /// Decode all tiles of a [StreamGroupType::TileGrid] group and composite them into a
+    /// single frame of the output image size (eg. HEIC images)
+    ///
+    /// [Demuxer::probe_input] must be called first, packets are read from the input until
+    /// every tile is decoded
+    #[cfg(feature = "avformat_version_greater_than_60_22")]
+    pub unsafe fn decode_tile_grid(&mut self, group: &StreamGroupInfo) -> Result<*mut AVFrame> {
+        use crate::Decoder;
+
+        if !matches!(group.group_type, StreamGroupType::TileGrid { .. }) {
+            bail!("Stream group is not a tile grid");
+        }
+        let grp = group.group;
+        let tg = (*grp).params.tile_grid;
+
+        let mut decoder = Decoder::new();
+        for n in 0..(*grp).nb_streams as usize {
+            decoder.setup_decoder_for_stream(*(*grp).streams.add(n), None)?;
+        }
+
+        // decode a single frame for each tile stream
+        let mut tiles: HashMap<i32, *mut AVFrame> = HashMap::new();
+        let ret = self
+            .read_tiles(&mut decoder, grp, &mut tiles)
+            .and_then(|_| {
+                let layout: Vec<_> = (0..(*tg).nb_tiles as usize)
+                    .map(|t| {
+                        let offset = *(*tg).offsets.add(t);
+                        let stream = *(*grp).streams.add(offset.idx as usize);
+                        (
+                            tiles[&(*stream).index] as *const AVFrame,
+                            offset.horizontal,
+                            offset.vertical,
+                        )
+                    })
+                    .collect();
+                composite_tiles((*tg).coded_width, (*tg).coded_height, &layout)
+            });
+        for (_, mut f) in tiles.drain() {
+            av_frame_free(&mut f);
+        }
+        let mut out = ret?;
+
+        // crop to the output image size
+        (*out).crop_left = (*tg).horizontal_offset as usize;
+        (*out).crop_top = (*tg).vertical_offset as usize;
+        (*out).crop_right = ((*tg).coded_width - (*tg).width - (*tg).horizontal_offset) as usize;
+        (*out).crop_bottom = ((*tg).coded_height - (*tg).height - (*tg).vertical_offset) as usize;
+        let ret = av_frame_apply_cropping(out, AV_FRAME_CROP_UNALIGNED as libc::c_int);
+        bail_ffmpeg!(ret, {
+            av_frame_free(&mut out);
+        });
+        Ok(out)
+    }
+
+    /// Read packets until a frame is decoded for every stream of the tile grid `grp`
+    ///
+    /// Decoded frames are added to `tiles` by stream index, also on error so they can be freed
+    #[cfg(feature = "avformat_version_greater_than_60_22")]
+    unsafe fn read_tiles(
+        &mut self,
+        decoder: &mut crate::Decoder,
+        grp: *const AVStreamGroup,
+        tiles: &mut HashMap<i32, *mut AVFrame>,
+    ) -> Result<()> {
+        while tiles.len() < (*grp).nb_streams as usize {
+            let (mut pkt, _) = self.get_packet()?;
+            if pkt.is_null() {
+                bail!("Input ended before all tiles were decoded");
+            }
+            let idx = (*pkt).stream_index;
+            if let (Some(ctx), false) = (decoder.get_decoder(idx), tiles.contains_key(&idx)) {
+                let mut frames = crate::Decoder::decode_pkt_internal(ctx.context, pkt);
+                if matches!(&frames, Ok(f) if f.is_empty()) {
+                    frames = crate::Decoder::decode_pkt_internal(ctx.context, ptr::null_mut());
+                }
+                let frames = match frames {
+                    Ok(f) => f,
+                    Err(e) => {
+                        av_packet_free(&mut pkt);
+                        return Err(e);
+                    }
+                };
+                for (i, mut f) in frames.into_iter().enumerate() {
+                    if i == 0 {
+                        tiles.insert(idx, f);
+                    } else {
+                        av_frame_free(&mut f);
+                    }
+                }
+            }
+            av_packet_free(&mut pkt);
+        }
+        Ok(())
+    }
+
+    /// List the files attached to the input (eg. fonts), [Demuxer::probe_input] must be called first
+    pub unsafe fn attachments(&self) -> Vec<Attachment> {
+        let mut ret = Vec::new();
+        for n in 0..(*self.ctx).nb_streams as usize {
+            let stream = *(*self.ctx).streams.add(n);
+            let par = (*stream).codecpar;
+            if (*par).codec_type != AVMediaType::AVMEDIA_TYPE_ATTACHMENT {
+                continue;
+            }
+            let get_meta = |key: &CStr| {
+                let e = av_dict_get((*stream).metadata, key.as_ptr(), ptr::null_mut(), 0);
+                if e.is_null() {
+                    String::new()
+                } else {
+                    rstr!((*e).value).to_string()
+                }
+            };
+            let data = if (*par).extradata.is_null() || (*par).extradata_size <= 0 {
+                Vec::new()
+            } else {
+                slice::from_raw_parts((*par).extradata, (*par).extradata_size as usize).to_vec()
+            };
+            ret.push(Attachment {
+                index: (*stream).index as usize,
+                filename: get_meta(c"filename"),
+                mime_type: get_meta(c"mimetype"),
+                data,
+            });
+        }
+        ret
+    }
+
+    /// Read the container level metadata (eg. title, major_brand)
+    pub unsafe fn container_metadata(&self) -> HashMap<String, String> {
+        let mut ret = HashMap::new();
+        if self.ctx.is_null() {
+            return ret;
+        }
+        let mut entry = ptr::null();
+        loop {
+            entry = av_dict_get(
+                (*self.ctx).metadata,
+                c"".as_ptr(),
+                entry,
+                AV_DICT_IGNORE_SUFFIX as libc::c_int,
+            );
+            if entry.is_null() {
+                break;
+            }
+            ret.insert(
+                rstr!((*entry).key).to_string(),
+                rstr!((*entry).value).to_string(),
+            );
+        }
+        ret
+    }
+
+    /// Find the best stream of `stream_type` in the same program as the stream `related_to`
+    /// (see [av_find_best_stream]), eg. the audio of a video in a multi-program TS
+    ///
+    /// Must be called after [Demuxer::probe_input]
+    pub unsafe fn find_related_stream(
+        &self,
+        stream_type: StreamType,
+        related_to: usize,
+    ) -> Option<usize> {
+        if (*self.ctx).nb_streams == 0 {
+            return None;
+        }
+        let media_type = match stream_type {
+            StreamType::Video => AVMediaType::AVMEDIA_TYPE_VIDEO,
+            StreamType::Audio => AVMediaType::AVMEDIA_TYPE_AUDIO,
+            StreamType::Subtitle => AVMediaType::AVMEDIA_TYPE_SUBTITLE,
+            StreamType::Data => AVMediaType::AVMEDIA_TYPE_DATA,
+        };
+        let ret = av_find_best_stream(
+            self.ctx,
+            media_type,
+            -1,
+            related_to as libc::c_int,
+            ptr::null_mut(),
+            0,
+        );
+        if ret < 0 {
+            None
+        } else {
+            Some(ret as usize)
+        }
+    }
+
+    /// Seek to the keyframe at or before `secs` from the start of the input
+    pub unsafe fn seek(&mut self, secs: f32) -> Result<()> {
+        let ts = (secs as f64 * AV_TIME_BASE as f64) as i64;
+        let ret = avformat_seek_file(self.ctx, -1, i64::MIN, ts, ts, 0);
+        bail_ffmpeg!(ret, "Failed to seek");
+        Ok(())
+    }
+
+    /// Seek to a byte offset in the input, the input must be seekable and the
+    /// format must support byte seeking (eg. mpegts)
+    pub unsafe fn seek_byte(&mut self, pos: i64) -> Result<()> {
+        let ret = av_seek_frame(self.ctx, -1, pos, AVSEEK_FLAG_BYTE as libc::c_int);
+        bail_ffmpeg!(ret, "Failed to seek");
+        Ok(())
+    }
+
+    /// Scan the input and build an index of (pts, byte position) for each keyframe of a stream,
+    /// the input is rewound afterwards if it is seekable.
+    ///
+    /// This reads the entire input, [Demuxer::probe_input] must be called first
+    pub unsafe fn build_keyframe_index(&mut self, stream_index: usize) -> Result<Vec<(i64, i64)>> {
+        let mut index = Vec::new();
+        loop {
+            let (mut pkt, _) = self.get_packet()?;
+            if pkt.is_null() {
+                break;
+            }
+            if (*pkt).stream_index as usize == stream_index
+                && (*pkt).flags & AV_PKT_FLAG_KEY as libc::c_int != 0
+            {
+                index.push(((*pkt).pts, (*pkt).pos));
+            }
+            av_packet_free(&mut pkt);
+        }
+
+        self.rewind()?;
+        Ok(index)
+    }
+
+    /// Compute the duration (seconds) by reading all packets and tracking the
+    /// first/last timestamp, for inputs with no duration in the header (raw streams, some TS).
+    ///
+    /// This reads the entire input, the input is rewound afterwards if seekable
+    pub unsafe fn estimate_duration_by_scan(&mut self) -> Result<f32> {
+        let mut start = i64::MAX;
+        let mut end = i64::MIN;
+        loop {
+            let (mut pkt, _) = self.get_packet()?;
+            if pkt.is_null() {
+                break;
+            }
+            let ts = if (*pkt).pts != AV_NOPTS_VALUE {
+                (*pkt).pts
+            } else {
+                (*pkt).dts
+            };
+            if ts != AV_NOPTS_VALUE {
+                let tb = (*pkt).time_base;
+                start = start.min(av_rescale_q(ts, tb, AV_TIME_BASE_Q));
+                end = end.max(av_rescale_q(ts + (*pkt).duration, tb, AV_TIME_BASE_Q));
+            }
+            av_packet_free(&mut pkt);
+        }
+
+        self.rewind()?;
+        if end < start {
+            bail!("No timestamps found in input");
+        }
+        Ok((end - start) as f32 / AV_TIME_BASE as f32)
+    }
+
+    /// Seek back to the start of the input if seekable
+    unsafe fn rewind(&mut self) -> Result<()> {
+        let pb = (*self.ctx).pb;
+        if !pb.is_null() && (*pb).seekable != 0 {
+            let ret = avformat_seek_file(self.ctx, -1, i64::MIN, 0, i64::MAX, 0);
+            bail_ffmpeg!(ret, "Failed to rewind input");
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Demuxer {
     fn drop(&mut self) {
         unsafe {
             if !self.ctx.is_null() {
-                if let DemuxerInput::Reader(_, _) = self.input {
-                    av_free((*(*self.ctx).pb).buffer as *mut _);
-                    drop(SlimBox::<dyn Read>::from_raw((*(*self.ctx).pb).opaque));
-                    avio_context_free(&mut (*self.ctx).pb);
+                if !matches!(self.input, DemuxerInput::Url(_)) {
+                    self.free_io(&mut (*self.ctx).pb);
                 }
                 avformat_free_context(self.ctx);
             }
@@ -261,9 +999,764 @@ impl Drop for Demuxer {
     }
 }
 
+/// Probe a file and return its info without keeping a [Demuxer] open
+///
+/// Stream pointers in the returned info are null
+pub fn probe_file(path: &str) -> Result<DemuxerInfo> {
+    let mut demux = Demuxer::new(path)?;
+    let info = unsafe { demux.probe_input()? };
+    Ok(detach_info(info))
+}
+
+/// Probe a reader and return its info without keeping a [Demuxer] open
+///
+/// Stream pointers in the returned info are null
+pub fn probe_reader<R: Read + 'static>(reader: R) -> Result<DemuxerInfo> {
+    let mut demux = Demuxer::new_custom_io(reader, None)?;
+    let info = unsafe { demux.probe_input()? };
+    Ok(detach_info(info))
+}
+
+/// Clear pointers into the demuxer context which is about to be freed
+fn detach_info(mut info: DemuxerInfo) -> DemuxerInfo {
+    for stream in info.streams.iter_mut() {
+        stream.stream = ptr::null_mut();
+    }
+    #[cfg(feature = "avformat_version_greater_than_60_19")]
+    for group in info.groups.iter_mut() {
+        group.group = ptr::null_mut();
+    }
+    info
+}
+
+/// Copy `tiles` (frame, x, y) into a new frame of `width`x`height`, tiles are clipped to the
+/// frame and must all have the same pixel format
+#[cfg(feature = "avformat_version_greater_than_60_22")]
+unsafe fn composite_tiles(
+    width: i32,
+    height: i32,
+    tiles: &[(*const AVFrame, i32, i32)],
+) -> Result<*mut AVFrame> {
+    let Some(&(first, _, _)) = tiles.first() else {
+        bail!("No tiles to composite");
+    };
+    let format = (*first).format;
+    if tiles.iter().any(|(f, _, _)| (**f).format != format) {
+        bail!("Tile pixel formats do not match");
+    }
+    let pix_fmt: AVPixelFormat = std::mem::transmute(format);
+    let desc = av_pix_fmt_desc_get(pix_fmt);
+    let planes = av_pix_fmt_count_planes(pix_fmt);
+
+    let mut out = av_frame_alloc();
+    if out.is_null() {
+        bail!("Failed to allocate frame");
+    }
+    (*out).width = width;
+    (*out).height = height;
+    (*out).format = format;
+    let ret = av_frame_get_buffer(out, 0);
+    bail_ffmpeg!(ret, {
+        av_frame_free(&mut out);
+    });
+
+    for &(tile, x, y) in tiles {
+        let w = (*tile).width.min(width - x);
+        let h = (*tile).height.min(height - y);
+        if w <= 0 || h <= 0 {
+            continue;
+        }
+        for p in 0..planes.max(0) as usize {
+            // chroma planes are subsampled
+            let (y, rows) = if p == 1 || p == 2 {
+                (y >> (*desc).log2_chroma_h, -((-h) >> (*desc).log2_chroma_h))
+            } else {
+                (y, h)
+            };
+            let x_bytes = if x > 0 {
+                av_image_get_linesize(pix_fmt, x, p as libc::c_int)
+            } else {
+                0
+            };
+            let w_bytes = av_image_get_linesize(pix_fmt, w, p as libc::c_int);
+            let dst =
+                (*out).data[p].offset(y as isize * (*out).linesize[p] as isize + x_bytes as isize);
+            av_image_copy_plane(
+                dst,
+                (*out).linesize[p],
+                (*tile).data[p],
+                (*tile).linesize[p],
+                w_bytes,
+                rows,
+            );
+        }
+    }
+    Ok(out)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{cstr, generate_test_video, setup_test_encoder};
+
+    #[test]
+    fn test_data_stream() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_data_stream.ts";
+            let mut muxer = crate::Muxer::builder()
+                .with_output_path(path, Some("mpegts"))?
+                .build()?;
+            let stream = muxer.add_data_stream(AVCodecID::AV_CODEC_ID_SMPTE_KLV)?;
+            muxer.open(None)?;
+
+            let klv = [0x06u8, 0x0e, 0x2b, 0x34, 0x01, 0x00];
+            for n in 0..10 {
+                muxer.write_timed_metadata((*stream).index, n * 3_000, &klv)?;
+            }
+            muxer.close()?;
+
+            let mut demux = Demuxer::new(path)?;
+            let probe = demux.probe_input()?;
+            assert_eq!(1, probe.streams.len());
+            assert_eq!(StreamType::Data, probe.streams[0].stream_type);
+
+            let mut count = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                assert_eq!(
+                    slice::from_raw_parts((*pkt).data, (*pkt).size as usize),
+                    klv
+                );
+                count += 1;
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(count, 10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_attachments() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_attachments.mkv";
+            let font = b"not really a font".to_vec();
+
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder.open(None)?;
+            let mut muxer = crate::Muxer::builder()
+                .with_output_path(path, None)?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+
+            let stream = avformat_new_stream(muxer.context(), ptr::null());
+            let par = (*stream).codecpar;
+            (*par).codec_type = AVMediaType::AVMEDIA_TYPE_ATTACHMENT;
+            (*par).codec_id = AVCodecID::AV_CODEC_ID_TTF;
+            (*par).extradata =
+                av_mallocz(font.len() + AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+            ptr::copy_nonoverlapping(font.as_ptr(), (*par).extradata, font.len());
+            (*par).extradata_size = font.len() as libc::c_int;
+            av_dict_set(
+                &mut (*stream).metadata,
+                cstr!("filename"),
+                cstr!("font.ttf"),
+                0,
+            );
+            av_dict_set(
+                &mut (*stream).metadata,
+                cstr!("mimetype"),
+                cstr!("font/ttf"),
+                0,
+            );
+
+            muxer.open(None)?;
+            for pts in 0..10 {
+                (*frame).pts = pts;
+                muxer.write_encoded(&mut encoder, frame)?;
+            }
+            muxer.write_encoded(&mut encoder, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            let mut demux = Demuxer::new(path)?;
+            demux.probe_input()?;
+            let attachments = demux.attachments();
+            assert_eq!(1, attachments.len());
+            assert_eq!("font.ttf", attachments[0].filename);
+            assert_eq!("font/ttf", attachments[0].mime_type);
+            assert_eq!(font, attachments[0].data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_open() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_repeated_open.mp4";
+            generate_test_video(path, 10, 10)?;
+            for _ in 0..100 {
+                let mut demux = Demuxer::new(path)?;
+                demux.probe_input()?;
+                assert!(demux.attachments().is_empty());
+                demux.container_metadata();
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_seek_byte() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_seek_byte.ts";
+            generate_test_video(path, 30, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            demux.probe_input()?;
+            demux.seek_byte(188 * 100)?;
+            let (mut pkt, _) = demux.get_packet()?;
+            assert!(!pkt.is_null());
+            assert!((*pkt).pos >= 188 * 100);
+            av_packet_free(&mut pkt);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_codec_tag() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_codec_tag.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let probe = demux.probe_input()?;
+            let video = probe.best_video().expect("no video stream");
+            assert_eq!(video.codec_tag_string(), "avc1");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_field_order() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_field_order.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let probe = demux.probe_input()?;
+            let video = probe.best_video().expect("no video stream");
+            assert_eq!(video.field_order, FieldOrder::Progressive);
+            assert!(!video.field_order.is_interlaced());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_mark_best() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_mark_best.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let mut info = demux.probe_input()?;
+            // duplicate the video stream, ties pick the lowest index
+            let mut dup = info.streams[0].clone();
+            dup.index = 1;
+            info.streams.push(dup);
+            info.mark_best();
+
+            let best: Vec<&StreamInfo> = info
+                .streams
+                .iter()
+                .filter(|s| s.stream_type == StreamType::Video && s.is_best)
+                .collect();
+            assert_eq!(best.len(), 1);
+            assert_eq!(best[0].index, 0);
+            assert_eq!(info.best_video().map(|s| s.index), Some(0));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_protocol_whitelist() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_protocol_whitelist.ts";
+            generate_test_video(path, 30, 10)?;
+            let single = count_packets(&mut Demuxer::new(path)?)?;
+
+            let url = format!("concat:{}|{}", path, path);
+            let mut demux = Demuxer::new(&url)?.with_protocol_whitelist("file,concat");
+            assert_eq!(count_packets(&mut demux)?, single * 2);
+
+            let mut demux = Demuxer::new(&url)?.with_protocol_whitelist("file");
+            assert!(demux.probe_input().is_err());
+
+            let mut demux = Demuxer::new(&url)?.with_protocol_blacklist("concat");
+            assert!(demux.probe_input().is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_format() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_input_format.png";
+            let mut frame = crate::generate_test_frame();
+            crate::save_image(frame, path, crate::ImageOptions::default())?;
+            av_frame_free(&mut frame);
+
+            let format = av_find_input_format(cstr!("image2"));
+            assert!(!format.is_null());
+            let mut demux = Demuxer::new(path)?.with_input_format(format);
+            let info = demux.probe_input()?;
+            assert_eq!(info.format, "image2");
+            let video = info.best_video().expect("no video stream");
+            assert_eq!(video.codec, AVCodecID::AV_CODEC_ID_PNG as isize);
+            assert_eq!(video.width, 1024);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_related_stream() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_related_input.mp4";
+            let path = "test_output/test_related.ts";
+            generate_test_video(input, 30, 10)?;
+            let mut in_demux = Demuxer::new(input)?;
+            let in_info = in_demux.probe_input()?;
+            let in_video = in_info.best_video().expect("no video stream");
+
+            // two programs, each with a video and an audio stream
+            let mut audio_encoders = Vec::new();
+            let mut muxer = crate::Muxer::builder()
+                .with_output_path(path, None)?
+                .build()?;
+            let ctx = muxer.context();
+            for program in 0..2 {
+                let video = muxer.add_copy_stream(in_video.stream)?;
+                let encoder = crate::Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                    .with_sample_rate(44_100)?
+                    .with_sample_format(AVSampleFormat::AV_SAMPLE_FMT_FLTP)
+                    .with_default_channel_layout(2)
+                    .open(None)?;
+                let audio = muxer.add_stream_encoder(&encoder)?;
+                audio_encoders.push(encoder);
+                let p = av_new_program(ctx, program + 1);
+                assert!(!p.is_null());
+                av_program_add_stream_index(ctx, program + 1, (*video).index as _);
+                av_program_add_stream_index(ctx, program + 1, (*audio).index as _);
+            }
+            muxer.open(None)?;
+            loop {
+                let (mut pkt, _) = in_demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                let mut copy = crate::packet_clone_ref(pkt)?;
+                (*pkt).stream_index = 0;
+                muxer.write_packet(pkt)?;
+                (*copy).stream_index = 2;
+                muxer.write_packet(copy)?;
+                av_packet_free(&mut copy);
+                av_packet_free(&mut pkt);
+            }
+            let mut layout = AVChannelLayout::empty();
+            av_channel_layout_default(&mut layout, 2);
+            let mut frame =
+                crate::audio_silence(1024, AVSampleFormat::AV_SAMPLE_FMT_FLTP, &layout, 44_100)?;
+            for n in 0..43 {
+                (*frame).pts = n * 1024;
+                for enc in audio_encoders.iter_mut() {
+                    muxer.write_encoded(enc, frame)?;
+                }
+            }
+            for enc in audio_encoders.iter_mut() {
+                muxer.write_encoded(enc, ptr::null_mut())?;
+            }
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let ctx = demux.ctx;
+            assert_eq!((*ctx).nb_programs, 2);
+            let mut related = Vec::new();
+            for p in 0..(*ctx).nb_programs as usize {
+                let program = *(*ctx).programs.add(p);
+                let streams = slice::from_raw_parts(
+                    (*program).stream_index,
+                    (*program).nb_stream_indexes as usize,
+                );
+                let video = streams
+                    .iter()
+                    .map(|i| *i as usize)
+                    .find(|i| info.streams[*i].stream_type == StreamType::Video)
+                    .expect("no video in program");
+                let audio = demux
+                    .find_related_stream(StreamType::Audio, video)
+                    .expect("no related audio");
+                assert_eq!(info.streams[audio].stream_type, StreamType::Audio);
+                assert!(streams.contains(&(audio as u32)));
+                related.push(audio);
+            }
+            assert_ne!(related[0], related[1]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_file() -> Result<()> {
+        let path = "test_output/test_probe_file.mp4";
+        unsafe {
+            generate_test_video(path, 10, 10)?;
+        }
+        let info = probe_file(path)?;
+        assert_eq!(info.streams.len(), 1);
+        assert!(info.streams[0].stream.is_null());
+        assert!(info.best_video().is_some());
+
+        let info = probe_reader(std::fs::File::open(path)?)?;
+        assert_eq!(info.streams.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_genpts() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_genpts.h264";
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder.open(None)?;
+            // raw elementary streams have no timestamps
+            let mut writer = crate::RawWriter::new(&encoder, std::fs::File::create(path)?)?;
+            for pts in 0..30 {
+                (*frame).pts = pts;
+                writer.write_encoded(&mut encoder, frame)?;
+            }
+            writer.write_encoded(&mut encoder, ptr::null_mut())?;
+            writer.into_inner()?;
+            av_frame_free(&mut frame);
+
+            // returns (packets, packets without pts)
+            let read_pts = |flags: i32| -> Result<(usize, usize)> {
+                let mut demux = Demuxer::new(path)?.with_flags(flags);
+                demux.probe_input()?;
+                let mut count = 0;
+                let mut missing = 0;
+                loop {
+                    let (mut pkt, _) = demux.get_packet()?;
+                    if pkt.is_null() {
+                        break;
+                    }
+                    if (*pkt).pts == AV_NOPTS_VALUE {
+                        missing += 1;
+                    }
+                    count += 1;
+                    av_packet_free(&mut pkt);
+                }
+                Ok((count, missing))
+            };
+
+            let (count, missing) = read_pts(0)?;
+            assert_eq!(count, 30);
+            assert!(missing > 0, "pts generated without AVFMT_FLAG_GENPTS");
+
+            let (count, missing) = read_pts(AVFMT_FLAG_GENPTS as i32)?;
+            assert_eq!(count, 30);
+            assert_eq!(missing, 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_options() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_open_options.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            // seekable is an option of the file protocol
+            let mut demux = Demuxer::new(path)?
+                .with_open_options(HashMap::from([("seekable".to_string(), "0".to_string())]));
+            demux.probe_input()?;
+            assert_eq!((*(*demux.ctx).pb).seekable, 0);
+
+            let mut demux = Demuxer::new(path)?;
+            demux.probe_input()?;
+            assert_ne!((*(*demux.ctx).pb).seekable, 0);
+
+            let demux = Demuxer::new(path)?.with_reconnect();
+            assert_eq!(
+                demux.open_options.get("reconnect").map(|s| s.as_str()),
+                Some("1")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
+    fn test_reconnect() -> Result<()> {
+        unsafe {
+            // long running http inputs are reopened at the current offset when the
+            // connection drops
+            let mut demux =
+                Demuxer::new("https://test-streams.mux.dev/x36xhzz/x36xhzz.m3u8")?.with_reconnect();
+            let info = demux.probe_input()?;
+            assert!(info.best_video().is_some());
+            for _ in 0..100 {
+                let (mut pkt, _) = demux.get_packet()?;
+                assert!(!pkt.is_null());
+                av_packet_free(&mut pkt);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_attached_pic() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_attached_pic.m4a";
+            let mut frame = crate::generate_test_frame();
+            let mut pic_encoder = crate::Encoder::new(AVCodecID::AV_CODEC_ID_PNG)?
+                .with_width((*frame).width)
+                .with_height((*frame).height)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_RGB24)
+                .open(None)?;
+            let mut audio_encoder = crate::Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                .with_sample_rate(44_100)?
+                .with_sample_format(AVSampleFormat::AV_SAMPLE_FMT_FLTP)
+                .with_default_channel_layout(2)
+                .with_bitrate(128_000)
+                .open(None)?;
+
+            let mut muxer = crate::Muxer::builder()
+                .with_output_path(path, Some("mp4"))?
+                .build()?;
+            muxer.add_stream_encoder(&audio_encoder)?;
+            let pic_stream = muxer.add_stream_encoder(&pic_encoder)?;
+            (*pic_stream).disposition = AV_DISPOSITION_ATTACHED_PIC as libc::c_int;
+            muxer.open(None)?;
+
+            let mut pic_pkts = pic_encoder.encode_frame(frame)?;
+            pic_pkts.extend(pic_encoder.encode_frame(ptr::null_mut())?);
+            for mut pkt in pic_pkts {
+                (*pkt).stream_index = (*pic_stream).index;
+                muxer.write_packet(pkt)?;
+                av_packet_free(&mut pkt);
+            }
+
+            let frame_size = (*audio_encoder.codec_context()).frame_size;
+            let mut audio = av_frame_alloc();
+            (*audio).format = AVSampleFormat::AV_SAMPLE_FMT_FLTP as libc::c_int;
+            (*audio).nb_samples = frame_size;
+            (*audio).sample_rate = 44_100;
+            av_channel_layout_default(&mut (*audio).ch_layout, 2);
+            av_frame_get_buffer(audio, 0);
+            av_samples_set_silence(
+                (*audio).extended_data,
+                0,
+                frame_size,
+                2,
+                AVSampleFormat::AV_SAMPLE_FMT_FLTP,
+            );
+            for n in 0..20 {
+                (*audio).pts = n * frame_size as i64;
+                muxer.write_encoded(&mut audio_encoder, audio)?;
+            }
+            muxer.write_encoded(&mut audio_encoder, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut audio);
+            av_frame_free(&mut frame);
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            assert!(info.best_audio().is_some());
+            assert!(info.best_video().is_none());
+            assert!(info
+                .streams
+                .iter()
+                .any(|s| s.stream_type == StreamType::Video && s.is_attached_pic()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_duration_scan() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_duration_scan.ts";
+            generate_test_video(path, 90, 30)?;
+
+            let mut demux = Demuxer::new(path)?;
+            demux.probe_input()?;
+            let duration = demux.estimate_duration_by_scan()?;
+            assert!((duration - 3.0).abs() < 0.1);
+
+            // input should be rewound
+            let (mut pkt, _) = demux.get_packet()?;
+            assert!(!pkt.is_null());
+            av_packet_free(&mut pkt);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_container_metadata() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_container_metadata.mp4";
+            generate_test_video(path, 10, 10)?;
+
+            let mut demux = Demuxer::new(path)?;
+            demux.probe_input()?;
+            let metadata = demux.container_metadata();
+            assert_eq!(
+                metadata.get("major_brand").map(|s| s.as_str()),
+                Some("isom")
+            );
+        }
+        Ok(())
+    }
+
+    /// Reader which returns WouldBlock/Interrupted and short reads
+    struct FlakyReader {
+        data: std::io::Cursor<Vec<u8>>,
+        calls: usize,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            match self.calls % 4 {
+                1 => Err(ErrorKind::WouldBlock.into()),
+                2 => Err(ErrorKind::Interrupted.into()),
+                _ => {
+                    let len = buf.len().min(1000);
+                    self.data.read(&mut buf[..len])
+                }
+            }
+        }
+    }
+
+    unsafe fn count_packets(demux: &mut Demuxer) -> Result<usize> {
+        demux.probe_input()?;
+        let mut count = 0;
+        loop {
+            let (mut pkt, _) = demux.get_packet()?;
+            if pkt.is_null() {
+                break;
+            }
+            count += 1;
+            av_packet_free(&mut pkt);
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_custom_io_transient_reads() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_custom_io_flaky.ts";
+            generate_test_video(path, 30, 10)?;
+
+            let expected = count_packets(&mut Demuxer::new(path)?)?;
+            let reader = FlakyReader {
+                data: std::io::Cursor::new(std::fs::read(path)?),
+                calls: 0,
+            };
+            let mut demux =
+                Demuxer::new_custom_io(reader, None)?.with_read_retry(3, Duration::from_millis(1));
+            assert_eq!(count_packets(&mut demux)?, expected);
+
+            // a reader which never has data fails instead of blocking forever
+            struct BlockedReader;
+            impl Read for BlockedReader {
+                fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                    Err(ErrorKind::WouldBlock.into())
+                }
+            }
+            let mut demux = Demuxer::new_custom_io(BlockedReader, None)?
+                .with_read_retry(3, Duration::from_millis(1));
+            assert!(demux.probe_input().is_err());
+        }
+        Ok(())
+    }
+
+    /// Reader which fails once with a timeout after being armed
+    struct TimeoutReader {
+        data: std::io::Cursor<Vec<u8>>,
+        armed: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl Read for TimeoutReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.armed.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_read_retry() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        unsafe {
+            let path = "test_output/test_read_retry.ts";
+            generate_test_video(path, 600, 30)?;
+            let expected = count_packets(&mut Demuxer::new(path)?)?;
+
+            let armed = Arc::new(AtomicBool::new(false));
+            let reader = TimeoutReader {
+                data: std::io::Cursor::new(std::fs::read(path)?),
+                armed: armed.clone(),
+            };
+            let mut demux =
+                Demuxer::new_custom_io(reader, None)?.with_read_retry(3, Duration::from_millis(1));
+            demux.probe_input()?;
+            armed.store(true, Ordering::SeqCst);
+
+            let mut count = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                count += 1;
+                av_packet_free(&mut pkt);
+            }
+            // the error was hit and reading continued to the end of the input
+            assert!(!armed.load(Ordering::SeqCst));
+            assert_eq!(count, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyframe_index() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_keyframe_index.mp4";
+            generate_test_video(path, 90, 30)?;
+
+            let mut demux = Demuxer::new(path)?;
+            let probe = demux.probe_input()?;
+            let video = probe.best_video().expect("no video stream");
+            let index = demux.build_keyframe_index(video.index)?;
+            assert!(index.len() > 1);
+            assert!(index.windows(2).all(|w| w[0].0 < w[1].0));
+
+            // input should be rewound
+            let (mut pkt, _) = demux.get_packet()?;
+            assert!(!pkt.is_null());
+            av_packet_free(&mut pkt);
+        }
+        Ok(())
+    }
 
     #[cfg(feature = "avformat_version_greater_than_60_19")]
     #[test]
@@ -282,4 +1775,277 @@ mod tests {
         }
         Ok(())
     }
+
+    #[cfg(feature = "avformat_version_greater_than_60_22")]
+    #[test]
+    fn test_composite_tiles() -> Result<()> {
+        unsafe {
+            // 2x2 grid of solid YUV420P tiles, the right column overlaps the frame edge
+            let mut tiles = Vec::new();
+            for n in 0..4 {
+                let mut tile = av_frame_alloc();
+                (*tile).width = 64;
+                (*tile).height = 64;
+                (*tile).format = AVPixelFormat::AV_PIX_FMT_YUV420P as libc::c_int;
+                let ret = av_frame_get_buffer(tile, 0);
+                bail_ffmpeg!(ret, { av_frame_free(&mut tile) });
+                for p in 0..3 {
+                    let rows = if p == 0 { 64 } else { 32 };
+                    let size = (*tile).linesize[p] as usize * rows;
+                    slice::from_raw_parts_mut((*tile).data[p], size).fill(10 * (n + 1) + p as u8);
+                }
+                tiles.push(tile);
+            }
+            let layout: Vec<_> = tiles
+                .iter()
+                .enumerate()
+                .map(|(n, t)| {
+                    (
+                        *t as *const AVFrame,
+                        (n as i32 % 2) * 64,
+                        (n as i32 / 2) * 64,
+                    )
+                })
+                .collect();
+            let mut out = composite_tiles(100, 128, &layout)?;
+            assert_eq!((*out).width, 100);
+            assert_eq!((*out).height, 128);
+
+            let px = |p: usize, x: usize, y: usize| {
+                *(*out).data[p].add(y * (*out).linesize[p] as usize + x)
+            };
+            assert_eq!(px(0, 0, 0), 10);
+            assert_eq!(px(0, 99, 0), 20);
+            assert_eq!(px(0, 0, 127), 30);
+            assert_eq!(px(0, 99, 127), 40);
+            assert_eq!(px(1, 0, 0), 11);
+            assert_eq!(px(2, 49, 63), 42);
+
+            // mismatched pixel formats are rejected
+            (*tiles[3]).format = AVPixelFormat::AV_PIX_FMT_NV12 as libc::c_int;
+            assert!(composite_tiles(128, 128, &layout).is_err());
+            (*tiles[3]).format = AVPixelFormat::AV_PIX_FMT_YUV420P as libc::c_int;
+
+            av_frame_free(&mut out);
+            for mut t in tiles {
+                av_frame_free(&mut t);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "avformat_version_greater_than_60_22")]
+    #[test]
+    #[ignore = "requires network access"]
+    fn test_decode_tile_grid() -> Result<()> {
+        unsafe {
+            let mut demux =
+                Demuxer::new("https://trac.ffmpeg.org/raw-attachment/ticket/11170/IMG_4765.HEIC")?;
+            let probe = demux.probe_input()?;
+            let group = &probe.groups[0];
+            let StreamGroupType::TileGrid { width, height, .. } = group.group_type;
+            let mut frame = demux.decode_tile_grid(group)?;
+            assert_eq!((*frame).width as usize, width);
+            assert_eq!((*frame).height as usize, height);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    /// Seekable reader of a recording which is still being written, reading past the
+    /// written data writes the next chunk
+    struct RecordingReader {
+        data: Vec<u8>,
+        written: usize,
+        pos: usize,
+    }
+
+    impl Read for RecordingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pos >= self.data.len() {
+                return Ok(0);
+            }
+            while self.pos >= self.written {
+                self.written = (self.written + 4096).min(self.data.len());
+            }
+            let end = self.written.min(self.pos + buf.len());
+            buf[..end - self.pos].copy_from_slice(&self.data[self.pos..end]);
+            let len = end - self.pos;
+            self.pos = end;
+            Ok(len)
+        }
+    }
+
+    impl Seek for RecordingReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            let pos = match pos {
+                SeekFrom::Start(p) => p as i64,
+                SeekFrom::Current(p) => self.pos as i64 + p,
+                SeekFrom::End(p) => self.data.len() as i64 + p,
+            };
+            if pos < 0 {
+                return Err(ErrorKind::InvalidInput.into());
+            }
+            self.pos = pos as usize;
+            Ok(self.pos as u64)
+        }
+    }
+
+    #[test]
+    fn test_probe_partial_file() -> Result<()> {
+        unsafe {
+            // mp4 writes the moov at the end, a partial file has no index
+            let full = "test_output/test_probe_partial_full.mp4";
+            let partial = "test_output/test_probe_partial.mp4";
+            crate::generate_test_video(full, 60, 30)?;
+            let data = std::fs::read(full)?;
+            std::fs::write(partial, &data[..data.len() / 2])?;
+            let err = Demuxer::new(partial)?
+                .with_probe_limits(1_000_000, Duration::from_secs(1))
+                .probe_input()
+                .unwrap_err();
+            assert!(!err.to_string().is_empty());
+
+            // every attempt fails as the recording is never finished
+            let start = std::time::Instant::now();
+            let ret = Demuxer::new(partial)?
+                .with_probe_limits(1_000_000, Duration::from_secs(1))
+                .with_open_retry(3, Duration::from_millis(20))
+                .probe_input();
+            assert!(ret.is_err());
+            assert!(start.elapsed() >= Duration::from_millis(60));
+
+            // the rest of the recording is written while it's being probed,
+            // the result is the same as probing the finished recording
+            let expected = Demuxer::new(full)?
+                .with_probe_limits(1_000_000, Duration::from_secs(1))
+                .probe_input()?;
+            let size = data.len() as u64;
+            let reader = RecordingReader {
+                written: data.len() / 2,
+                data,
+                pos: 0,
+            };
+            let mut demux = Demuxer::with_custom_io_and_seek_and_size(reader, size, None)?
+                .with_probe_limits(1_000_000, Duration::from_secs(1));
+            let info = demux.probe_input()?;
+            assert_eq!(info.format, expected.format);
+            assert_eq!(info.duration, expected.duration);
+            assert_eq!(info.streams.len(), expected.streams.len());
+            let video = info.best_video().expect("no video stream");
+            let expected_video = expected.best_video().expect("no video stream");
+            assert_eq!(video.codec, expected_video.codec);
+            assert_eq!(video.width, expected_video.width);
+            assert_eq!(video.height, expected_video.height);
+            assert_eq!(video.fps, expected_video.fps);
+
+            // matroska can be probed from the start of the file
+            let full = "test_output/test_probe_partial_full.mkv";
+            let partial = "test_output/test_probe_partial.mkv";
+            crate::generate_test_video(full, 60, 30)?;
+            let data = std::fs::read(full)?;
+            std::fs::write(partial, &data[..data.len() / 2])?;
+            let mut demux =
+                Demuxer::new(partial)?.with_probe_limits(1_000_000, Duration::from_secs(1));
+            let info = demux.probe_input()?;
+            let video = info.best_video().expect("no video stream");
+            assert_eq!(video.width, 1024);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_info_and_first_frame() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_first_frame.mp4";
+            crate::generate_test_video(path, 30, 10)?;
+            let (info, mut frame) = Demuxer::new(path)?.read_to_info_and_first_frame(false)?;
+            let video = info.best_video().expect("no video stream");
+            assert_eq!(video.width, 1024);
+            assert!(!frame.is_null());
+            assert_eq!((*frame).width, 1024);
+            assert_eq!((*frame).height, 1024);
+            assert!((*frame).hw_frames_ctx.is_null());
+            assert!(!(*frame).data[0].is_null());
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_io_known_size() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_custom_io_size.mp4";
+            generate_test_video(path, 30, 10)?;
+            let expected = count_packets(&mut Demuxer::new(path)?)?;
+
+            let data = std::fs::read(path)?;
+            let size = data.len() as u64;
+            let mut demux =
+                Demuxer::with_custom_io_and_seek_and_size(std::io::Cursor::new(data), size, None)?;
+            let info = demux.probe_input()?;
+            assert_eq!(info.format, "mov,mp4,m4a,3gp,3g2,mj2");
+            assert!(info.best_video().is_some());
+
+            let mut count = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                count += 1;
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(count, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_retry_options() -> Result<()> {
+        unsafe {
+            let mut demux = Demuxer::new("test_output/test_open_retry_options.mp4")?
+                .with_probe_limits(1_000_000, Duration::from_secs(1));
+            demux.set_opt(HashMap::from([(
+                "fpsprobesize".to_string(),
+                "5".to_string(),
+            )]))?;
+            let (flags, probesize, analyze) = (
+                (*demux.ctx).flags,
+                (*demux.ctx).probesize,
+                (*demux.ctx).max_analyze_duration,
+            );
+
+            // as if opening failed
+            avformat_free_context(demux.ctx);
+            demux.realloc_context(flags, probesize, analyze)?;
+            assert_eq!((*demux.ctx).fps_probe_size, 5);
+            assert_eq!((*demux.ctx).probesize, 1_000_000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_io_open_failure() -> Result<()> {
+        use std::sync::Arc;
+
+        unsafe {
+            // the reader is dropped when opening fails
+            let data = Arc::new(vec![0u8; 1024]);
+            let reader = std::io::Cursor::new(ArcBytes(data.clone()));
+            let mut demux = Demuxer::with_custom_io_and_seek_and_size(reader, 1024, None)?;
+            assert!(demux.probe_input().is_err());
+            assert_eq!(Arc::strong_count(&data), 1);
+            drop(demux);
+        }
+        Ok(())
+    }
+
+    struct ArcBytes(std::sync::Arc<Vec<u8>>);
+
+    impl AsRef<[u8]> for ArcBytes {
+        fn as_ref(&self) -> &[u8] {
+            &self.0
+        }
+    }
 }
@@ -1,12 +1,18 @@
-use crate::{bail_ffmpeg, cstr, get_ffmpeg_error_msg, options_to_dict};
+use crate::{bail_ffmpeg, channel_layout_from_str, cstr, options_to_dict, rstr, FfmpegError};
 use anyhow::{bail, Error, Result};
-use ffmpeg_sys_the_third::AVPictureType::AV_PICTURE_TYPE_NONE;
+use ffmpeg_sys_the_third::AVPacketSideDataType::{
+    AV_PKT_DATA_QUALITY_STATS, AV_PKT_DATA_SKIP_SAMPLES,
+};
+use ffmpeg_sys_the_third::AVPictureType::{AV_PICTURE_TYPE_I, AV_PICTURE_TYPE_NONE};
 use ffmpeg_sys_the_third::{
-    av_channel_layout_default, av_d2q, av_inv_q, av_packet_alloc, av_packet_free,
-    avcodec_alloc_context3, avcodec_find_encoder, avcodec_find_encoder_by_name,
-    avcodec_free_context, avcodec_open2, avcodec_receive_packet, avcodec_send_frame,
-    AVChannelLayout, AVCodec, AVCodecContext, AVCodecID, AVFrame, AVPacket, AVPixelFormat,
-    AVRational, AVSampleFormat, AVERROR, AVERROR_EOF,
+    av_channel_layout_default, av_d2q, av_display_rotation_set, av_get_pix_fmt_name, av_inv_q,
+    av_new_packet, av_packet_alloc, av_packet_free, av_packet_get_side_data,
+    av_packet_new_side_data, av_pix_fmt_desc_get, av_q2d, av_rescale_q, av_shrink_packet,
+    avcodec_alloc_context3, avcodec_encode_subtitle, avcodec_find_encoder,
+    avcodec_find_encoder_by_name, avcodec_free_context, avcodec_open2, avcodec_receive_packet,
+    avcodec_send_frame, AVChannelLayout, AVCodec, AVCodecContext, AVCodecID, AVFrame, AVMediaType,
+    AVPacket, AVPictureType, AVPixelFormat, AVRational, AVSampleFormat, AVSubtitle, AVERROR,
+    AVERROR_EOF, AV_PIX_FMT_FLAG_ALPHA, AV_TIME_BASE_Q,
 };
 #[cfg(feature = "avcodec_version_greater_than_61_13")]
 use ffmpeg_sys_the_third::{avcodec_get_supported_config, AVCodecConfig};
@@ -16,12 +22,90 @@ use std::collections::HashMap;
 use std::io::Write;
 use std::{ptr, slice};
 
+/// Quality presets for [Encoder::with_preset]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    Low,
+    Medium,
+    High,
+}
+
+impl Preset {
+    /// Video bits per pixel (per frame) of H.264 at this preset
+    fn bits_per_pixel(&self) -> f64 {
+        match self {
+            Preset::Low => 0.05,
+            Preset::Medium => 0.08,
+            Preset::High => 0.12,
+        }
+    }
+
+    /// Bitrate relative to H.264 for similar quality
+    fn codec_efficiency(codec: AVCodecID) -> f64 {
+        match codec {
+            AVCodecID::AV_CODEC_ID_HEVC | AVCodecID::AV_CODEC_ID_VP9 => 0.65,
+            AVCodecID::AV_CODEC_ID_AV1 => 0.55,
+            AVCodecID::AV_CODEC_ID_VP8 => 1.2,
+            AVCodecID::AV_CODEC_ID_MPEG4 | AVCodecID::AV_CODEC_ID_MPEG2VIDEO => 1.8,
+            _ => 1.0,
+        }
+    }
+
+    /// CRF value of codecs which support constant quality encoding
+    fn crf(&self, codec: AVCodecID) -> Option<i32> {
+        let (low, medium, high) = match codec {
+            AVCodecID::AV_CODEC_ID_H264 => (28, 23, 18),
+            AVCodecID::AV_CODEC_ID_HEVC => (30, 26, 22),
+            AVCodecID::AV_CODEC_ID_VP9 => (40, 33, 28),
+            AVCodecID::AV_CODEC_ID_AV1 => (40, 32, 26),
+            _ => return None,
+        };
+        Some(match self {
+            Preset::Low => low,
+            Preset::Medium => medium,
+            Preset::High => high,
+        })
+    }
+
+    /// Audio bitrate per channel
+    fn audio_bitrate(&self, codec: AVCodecID) -> Option<i64> {
+        let (low, medium, high) = match codec {
+            AVCodecID::AV_CODEC_ID_AAC => (32_000, 64_000, 96_000),
+            AVCodecID::AV_CODEC_ID_OPUS => (24_000, 48_000, 64_000),
+            AVCodecID::AV_CODEC_ID_MP3 => (48_000, 80_000, 128_000),
+            AVCodecID::AV_CODEC_ID_VORBIS => (40_000, 64_000, 96_000),
+            AVCodecID::AV_CODEC_ID_AC3 | AVCodecID::AV_CODEC_ID_EAC3 => (48_000, 64_000, 96_000),
+            _ => return None,
+        };
+        Some(match self {
+            Preset::Low => low,
+            Preset::Medium => medium,
+            Preset::High => high,
+        })
+    }
+}
+
 pub struct Encoder {
     ctx: *mut AVCodecContext,
     codec: *const AVCodec,
     dst_stream_index: Option<i32>,
+    /// EOF was sent to the encoder
+    flushed: bool,
+    /// Force the next frame to be encoded as a keyframe
+    force_keyframe: bool,
+    /// Remaining encoder delay (samples) to signal with [AV_PKT_DATA_SKIP_SAMPLES]
+    skip_samples: i64,
+    /// Time base was set with [Encoder::with_time_base]
+    explicit_time_base: bool,
+    /// Display matrix written to the output stream by the muxer
+    display_matrix: Option<[i32; 9]>,
 }
 
+/// The codec context is owned by the encoder and never shared with other objects, so the
+/// encoder can be moved to another thread. It's not [Sync] as a codec context can't be
+/// used from two threads at once
+unsafe impl Send for Encoder {}
+
 impl Drop for Encoder {
     fn drop(&mut self) {
         unsafe {
@@ -68,6 +152,11 @@ impl Encoder {
                 ctx,
                 codec,
                 dst_stream_index: None,
+                flushed: false,
+                force_keyframe: false,
+                skip_samples: 0,
+                explicit_time_base: false,
+                display_matrix: None,
             })
         }
     }
@@ -82,6 +171,29 @@ impl Encoder {
         self.ctx
     }
 
+    /// List the codec private options (eg. preset/tune/crf for libx264),
+    /// excluding the generic [AVCodecContext] options
+    pub fn list_private_opts(&self) -> Result<Vec<String>> {
+        unsafe {
+            if (*self.ctx).priv_data.is_null() {
+                return Ok(vec![]);
+            }
+            crate::list_opts((*self.ctx).priv_data)
+        }
+    }
+
+    /// Get the expected bitrate (bits/s) of the encoded output, using the max rate
+    /// when no target bitrate is set
+    pub fn estimated_bitrate(&self) -> i64 {
+        unsafe {
+            if (*self.ctx).bit_rate > 0 {
+                (*self.ctx).bit_rate
+            } else {
+                (*self.ctx).rc_max_rate
+            }
+        }
+    }
+
     #[cfg(feature = "avcodec_version_greater_than_61_13")]
     /// List supported configs (see [avcodec_get_supported_config])
     pub unsafe fn list_configs<'a, T>(&mut self, cfg: AVCodecConfig) -> Result<&'a [T], Error> {
@@ -99,6 +211,16 @@ impl Encoder {
         Ok(slice::from_raw_parts(dst, num_dst as usize))
     }
 
+    /// Get the display matrix set with [Encoder::with_display_rotation]
+    pub fn display_matrix(&self) -> Option<&[i32; 9]> {
+        self.display_matrix.as_ref()
+    }
+
+    /// Get the destination stream index assigned with [Encoder::with_stream_index]
+    pub fn stream_index(&self) -> Option<i32> {
+        self.dst_stream_index
+    }
+
     /// Store the destination stream index along with the encoder
     /// AVPacket's created by this encoder will have stream_index assigned to this value
     pub unsafe fn with_stream_index(mut self, index: i32) -> Self {
@@ -106,6 +228,29 @@ impl Encoder {
         self
     }
 
+    /// Enable a codec flag (AV_CODEC_FLAG_*), eg. [AV_CODEC_FLAG_LOW_DELAY]
+    pub unsafe fn with_flag(self, flag: u32) -> Self {
+        (*self.ctx).flags |= flag as libc::c_int;
+        self
+    }
+
+    /// Enable a codec flag2 (AV_CODEC_FLAG2_*), eg. [AV_CODEC_FLAG2_FAST]
+    pub unsafe fn with_flag2(self, flag: u32) -> Self {
+        (*self.ctx).flags2 |= flag as libc::c_int;
+        self
+    }
+
+    /// Set the display rotation in degrees (counterclockwise) of the output stream,
+    /// players rotate the video when presenting it, the frames themselves are not rotated
+    ///
+    /// The rotation is written by [crate::Muxer] when adding a stream for this encoder
+    pub unsafe fn with_display_rotation(mut self, degrees: f64) -> Self {
+        let mut matrix = [0i32; 9];
+        av_display_rotation_set(matrix.as_mut_ptr(), degrees);
+        self.display_matrix = Some(matrix);
+        self
+    }
+
     /// Set the encoder bitrate
     pub unsafe fn with_bitrate(self, bitrate: i64) -> Self {
         (*self.ctx).bit_rate = bitrate;
@@ -114,6 +259,10 @@ impl Encoder {
 
     /// Set the encoder sample rate (audio)
     pub unsafe fn with_sample_rate(self, rate: i32) -> Result<Self> {
+        if self.explicit_time_base {
+            (*self.ctx).sample_rate = rate;
+            return Ok(self);
+        }
         if (*self.ctx).time_base.num != 1 || (*self.ctx).time_base.den != 1 {
             bail!("Cannot assign sample_rate for a video encoder")
         }
@@ -122,6 +271,20 @@ impl Encoder {
         Ok(self)
     }
 
+    /// Set the encoder time base explicitly (eg. 1/90000), frames must use this time base
+    ///
+    /// By default the time base is derived from [Encoder::with_framerate] /
+    /// [Encoder::with_sample_rate], an explicit time base always takes precedence
+    /// regardless of the order these are called in
+    pub unsafe fn with_time_base(mut self, time_base: AVRational) -> Result<Self> {
+        if time_base.num <= 0 || time_base.den <= 0 {
+            bail!("Invalid time base {}/{}", time_base.num, time_base.den);
+        }
+        (*self.ctx).time_base = time_base;
+        self.explicit_time_base = true;
+        Ok(self)
+    }
+
     /// Set the encoder width in pixels
     pub unsafe fn with_width(self, width: i32) -> Self {
         (*self.ctx).width = width;
@@ -146,17 +309,165 @@ impl Encoder {
         self
     }
 
+    /// Set the maximum number of B-frames between non-B-frames (0-16)
+    pub unsafe fn with_max_b_frames(self, frames: i32) -> Result<Self> {
+        if !(0..=16).contains(&frames) {
+            bail!("max_b_frames must be between 0 and 16, got {}", frames);
+        }
+        (*self.ctx).max_b_frames = frames;
+        Ok(self)
+    }
+
+    /// Set the number of reference frames (1-16)
+    pub unsafe fn with_refs(self, refs: i32) -> Result<Self> {
+        if !(1..=16).contains(&refs) {
+            bail!("refs must be between 1 and 16, got {}", refs);
+        }
+        (*self.ctx).refs = refs;
+        Ok(self)
+    }
+
+    /// Set the motion estimation search range (0-1024, 0 = codec default)
+    pub unsafe fn with_me_range(self, range: i32) -> Result<Self> {
+        if !(0..=1024).contains(&range) {
+            bail!("me_range must be between 0 and 1024, got {}", range);
+        }
+        (*self.ctx).me_range = range;
+        Ok(self)
+    }
+
     /// Set the encoder framerate
     pub unsafe fn with_framerate(self, fps: f32) -> Result<Self> {
+        self.with_framerate_q(av_d2q(fps as f64, 90_000))
+    }
+
+    unsafe fn with_framerate_q(self, q: AVRational) -> Result<Self> {
+        if self.explicit_time_base {
+            (*self.ctx).framerate = q;
+            return Ok(self);
+        }
         if (*self.ctx).time_base.num != 1 || (*self.ctx).time_base.den != 1 {
             bail!("Cannot assign framerate for an audio encoder")
         }
-        let q = av_d2q(fps as f64, 90_000);
         (*self.ctx).framerate = q;
         (*self.ctx).time_base = av_inv_q(q);
         Ok(self)
     }
 
+    /// List the framerates supported by the codec,
+    /// an empty list means any framerate is supported
+    pub unsafe fn supported_framerates(&self) -> Result<Vec<AVRational>> {
+        #[cfg(feature = "avcodec_version_greater_than_61_13")]
+        {
+            let mut dst: *const AVRational = ptr::null();
+            let mut num_dst = 0;
+            let ret = avcodec_get_supported_config(
+                self.ctx,
+                self.codec,
+                AVCodecConfig::AV_CODEC_CONFIG_FRAME_RATE,
+                0,
+                ptr::addr_of_mut!(dst) as _,
+                &mut num_dst,
+            );
+            bail_ffmpeg!(ret);
+            if dst.is_null() {
+                return Ok(vec![]);
+            }
+            Ok(slice::from_raw_parts(dst, num_dst as usize).to_vec())
+        }
+        #[cfg(not(feature = "avcodec_version_greater_than_61_13"))]
+        {
+            let mut ret = vec![];
+            let mut rate = (*self.codec).supported_framerates;
+            if rate.is_null() {
+                return Ok(ret);
+            }
+            while (*rate).num != 0 || (*rate).den != 0 {
+                ret.push(*rate);
+                rate = rate.add(1);
+            }
+            Ok(ret)
+        }
+    }
+
+    /// List the pixel formats supported by the codec,
+    /// an empty list means any pixel format is supported
+    pub unsafe fn supported_pix_fmts(&self) -> Result<Vec<AVPixelFormat>> {
+        #[cfg(feature = "avcodec_version_greater_than_61_13")]
+        {
+            let mut dst: *const AVPixelFormat = ptr::null();
+            let mut num_dst = 0;
+            let ret = avcodec_get_supported_config(
+                self.ctx,
+                self.codec,
+                AVCodecConfig::AV_CODEC_CONFIG_PIX_FORMAT,
+                0,
+                ptr::addr_of_mut!(dst) as _,
+                &mut num_dst,
+            );
+            bail_ffmpeg!(ret);
+            if dst.is_null() {
+                return Ok(vec![]);
+            }
+            Ok(slice::from_raw_parts(dst, num_dst as usize).to_vec())
+        }
+        #[cfg(not(feature = "avcodec_version_greater_than_61_13"))]
+        {
+            let mut ret = vec![];
+            let mut fmt = (*self.codec).pix_fmts;
+            if fmt.is_null() {
+                return Ok(ret);
+            }
+            while *fmt != AVPixelFormat::AV_PIX_FMT_NONE {
+                ret.push(*fmt);
+                fmt = fmt.add(1);
+            }
+            Ok(ret)
+        }
+    }
+
+    /// Check that the configured pixel format is supported by the codec
+    pub(crate) unsafe fn check_pix_fmt(&self) -> Result<()> {
+        let pix_fmt = (*self.ctx).pix_fmt;
+        let supported = self.supported_pix_fmts()?;
+        if supported.is_empty() || supported.contains(&pix_fmt) {
+            return Ok(());
+        }
+        let names: Vec<&str> = supported
+            .iter()
+            .map(|f| rstr!(av_get_pix_fmt_name(*f)))
+            .collect();
+        let desc = av_pix_fmt_desc_get(pix_fmt);
+        if !desc.is_null() && (*desc).flags & AV_PIX_FMT_FLAG_ALPHA as u64 != 0 {
+            bail!(
+                "Pixel format {} has an alpha channel which is not supported by codec {}, supported formats: {}",
+                rstr!(av_get_pix_fmt_name(pix_fmt)),
+                rstr!((*self.codec).name),
+                names.join(",")
+            );
+        }
+        bail!(
+            "Pixel format {} is not supported by codec {}, supported formats: {}",
+            rstr!(av_get_pix_fmt_name(pix_fmt)),
+            rstr!((*self.codec).name),
+            names.join(",")
+        );
+    }
+
+    /// Set the encoder framerate to the supported framerate nearest to `fps`
+    pub unsafe fn with_framerate_snapped(self, fps: f32) -> Result<Self> {
+        let nearest = self.supported_framerates()?.into_iter().min_by(|a, b| {
+            let da = (av_q2d(*a) - fps as f64).abs();
+            let db = (av_q2d(*b) - fps as f64).abs();
+            da.total_cmp(&db)
+        });
+        if let Some(q) = nearest {
+            self.with_framerate_q(q)
+        } else {
+            self.with_framerate(fps)
+        }
+    }
+
     /// Set the encoder pixel format
     pub unsafe fn with_pix_fmt(self, fmt: AVPixelFormat) -> Self {
         (*self.ctx).pix_fmt = fmt;
@@ -175,6 +486,12 @@ impl Encoder {
         self
     }
 
+    /// Set the encoder channel layout from a string, eg. "stereo" or "5.1" (audio)
+    pub unsafe fn with_channel_layout_str(self, layout: &str) -> Result<Self> {
+        let layout = channel_layout_from_str(layout)?;
+        Ok(self.with_channel_layout(layout))
+    }
+
     /// Set the encoder channel layout using number of channels (audio)
     pub unsafe fn with_default_channel_layout(self, channels: i32) -> Self {
         let mut layout = AVChannelLayout::empty();
@@ -192,8 +509,66 @@ impl Encoder {
         self
     }
 
+    /// Apply bitrate/quality and GOP defaults for the codec, must be called after
+    /// the video size and framerate or the audio channel layout are set
+    ///
+    /// Encoders with a `crf` option use capped constant quality, others use the target bitrate
+    pub unsafe fn with_preset(self, preset: Preset) -> Result<Self> {
+        let codec_id = (*self.codec).id;
+        match (*self.codec).type_ {
+            AVMediaType::AVMEDIA_TYPE_VIDEO => {
+                let (width, height) = ((*self.ctx).width, (*self.ctx).height);
+                if width <= 0 || height <= 0 {
+                    bail!("Video size must be set before applying a preset");
+                }
+                let fps = if (*self.ctx).framerate.num > 0 && (*self.ctx).framerate.den > 0 {
+                    av_q2d((*self.ctx).framerate)
+                } else {
+                    30.0
+                };
+                let bitrate = width as f64
+                    * height as f64
+                    * fps
+                    * preset.bits_per_pixel()
+                    * Preset::codec_efficiency(codec_id);
+                let bitrate = bitrate as i64;
+                // keyframe every 2 seconds
+                (*self.ctx).gop_size = (fps * 2.0).round() as libc::c_int;
+
+                let has_crf = self.list_private_opts()?.iter().any(|o| o == "crf");
+                match preset.crf(codec_id) {
+                    Some(crf) if has_crf => {
+                        crate::set_opts(
+                            (*self.ctx).priv_data,
+                            HashMap::from([("crf".to_string(), crf.to_string())]),
+                        )?;
+                        (*self.ctx).bit_rate = 0;
+                        (*self.ctx).rc_max_rate = bitrate * 2;
+                        (*self.ctx).rc_buffer_size = (bitrate * 2) as libc::c_int;
+                    }
+                    _ => {
+                        (*self.ctx).bit_rate = bitrate;
+                    }
+                }
+            }
+            AVMediaType::AVMEDIA_TYPE_AUDIO => {
+                let channels = (*self.ctx).ch_layout.nb_channels;
+                if channels <= 0 {
+                    bail!("Channel layout must be set before applying a preset");
+                }
+                if let Some(rate) = preset.audio_bitrate(codec_id) {
+                    // surround channels don't need the full bitrate of the front channels
+                    let scale = if channels > 2 { 0.75 } else { 1.0 };
+                    (*self.ctx).bit_rate = (rate as f64 * channels as f64 * scale) as i64;
+                }
+            }
+            _ => bail!("Presets are only supported for audio and video encoders"),
+        }
+        Ok(self)
+    }
+
     /// Open the encoder so that you can start encoding frames (see [avcodec_open2])
-    pub unsafe fn open(self, options: Option<HashMap<String, String>>) -> Result<Self, Error> {
+    pub unsafe fn open(mut self, options: Option<HashMap<String, String>>) -> Result<Self, Error> {
         assert!(!self.ctx.is_null());
 
         let mut options = if let Some(options) = options {
@@ -201,48 +576,137 @@ impl Encoder {
         } else {
             ptr::null_mut()
         };
+        if (*self.codec).type_ == AVMediaType::AVMEDIA_TYPE_VIDEO
+            && (*self.ctx).pix_fmt != AVPixelFormat::AV_PIX_FMT_NONE
+        {
+            self.check_pix_fmt()?;
+        }
         let ret = avcodec_open2(self.ctx, self.codec, &mut options);
         bail_ffmpeg!(ret);
+
+        // audio encoders with delay (opus/aac) need the padding signaled for gapless playback,
+        // initial_padding is also copied to the muxer stream codec parameters
+        if (*self.codec).type_ == AVMediaType::AVMEDIA_TYPE_AUDIO {
+            self.skip_samples = (*self.ctx).initial_padding as i64;
+        }
         Ok(self)
     }
 
+    /// Attach [AV_PKT_DATA_SKIP_SAMPLES] side data to the packet while there is
+    /// encoder delay remaining to be skipped
+    unsafe fn add_skip_samples(&mut self, pkt: *mut AVPacket) -> Result<(), Error> {
+        let sample_tb = AVRational {
+            num: 1,
+            den: (*self.ctx).sample_rate,
+        };
+        let samples = if (*pkt).duration > 0 {
+            av_rescale_q((*pkt).duration, (*self.ctx).time_base, sample_tb)
+        } else {
+            (*self.ctx).frame_size as i64
+        };
+        let skip = self.skip_samples.min(samples);
+        if skip <= 0 {
+            return Ok(());
+        }
+        let data = av_packet_new_side_data(pkt, AV_PKT_DATA_SKIP_SAMPLES, 10);
+        if data.is_null() {
+            bail!("Failed to allocate skip samples side data");
+        }
+        // u32le skip start, u32le skip end, u8 reason start, u8 reason end
+        let side_data = slice::from_raw_parts_mut(data, 10);
+        side_data.fill(0);
+        side_data[..4].copy_from_slice(&(skip as u32).to_le_bytes());
+        self.skip_samples -= skip;
+        Ok(())
+    }
+
+    /// Force the next frame passed to [Encoder::encode_frame] to be encoded as a keyframe
+    pub fn force_next_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
     /// Encode a frame, returning a number of [AVPacket]
     /// MAKE SURE TIMESTAMP ARE SET CORRECTLY
+    ///
+    /// A null frame flushes the encoder, flushing more than once returns no packets
     pub unsafe fn encode_frame(
         &mut self,
         frame: *mut AVFrame,
     ) -> Result<Vec<*mut AVPacket>, Error> {
         let mut pkgs = Vec::new();
 
-        if !frame.is_null() {
+        if frame.is_null() {
+            if self.flushed {
+                return Ok(pkgs);
+            }
+            self.flushed = true;
+        } else if self.force_keyframe {
+            (*frame).pict_type = AV_PICTURE_TYPE_I;
+            self.force_keyframe = false;
+        } else {
             // always reset pict_type, this can be set by the decoder,
             // but it confuses the encoder
             (*frame).pict_type = AV_PICTURE_TYPE_NONE;
         }
 
-        let mut ret = avcodec_send_frame(self.ctx, frame);
-        if ret < 0 && ret != AVERROR(EAGAIN) {
-            bail!(get_ffmpeg_error_msg(ret));
+        if let Err(e) = self.send_frame(frame, &mut pkgs) {
+            for mut pkt in pkgs {
+                av_packet_free(&mut pkt);
+            }
+            return Err(e);
+        }
+        Ok(pkgs)
+    }
+
+    /// Send a frame to the encoder, receiving packets into `pkgs` until it's accepted
+    unsafe fn send_frame(
+        &mut self,
+        frame: *mut AVFrame,
+        pkgs: &mut Vec<*mut AVPacket>,
+    ) -> Result<(), Error> {
+        loop {
+            let ret = avcodec_send_frame(self.ctx, frame);
+            let again = ret == AVERROR(EAGAIN);
+            if ret < 0 && !again {
+                return Err(FfmpegError::from_ret(ret).into());
+            }
+
+            // drain the encoder, if it was full the frame is sent again afterwards
+            let n_pkts = pkgs.len();
+            self.receive_packets(pkgs)?;
+            if !again {
+                return Ok(());
+            }
+            if pkgs.len() == n_pkts {
+                bail!("Encoder is full but returned no packets");
+            }
         }
+    }
 
-        while ret >= 0 || ret == AVERROR(EAGAIN) {
+    /// Receive all available packets from the encoder
+    unsafe fn receive_packets(&mut self, pkgs: &mut Vec<*mut AVPacket>) -> Result<(), Error> {
+        loop {
             let mut pkt = av_packet_alloc();
-            ret = avcodec_receive_packet(self.ctx, pkt);
+            let ret = avcodec_receive_packet(self.ctx, pkt);
             if ret != 0 {
                 av_packet_free(&mut pkt);
                 if ret == AVERROR(EAGAIN) || ret == AVERROR_EOF {
-                    break;
+                    return Ok(());
                 }
-                bail!(get_ffmpeg_error_msg(ret));
+                return Err(FfmpegError::from_ret(ret).into());
             }
             (*pkt).time_base = (*self.ctx).time_base;
             if let Some(idx) = self.dst_stream_index {
                 (*pkt).stream_index = idx;
             }
+            if self.skip_samples > 0 {
+                if let Err(e) = self.add_skip_samples(pkt) {
+                    av_packet_free(&mut pkt);
+                    return Err(e);
+                }
+            }
             pkgs.push(pkt);
         }
-
-        Ok(pkgs)
     }
 
     /// Encode a single frame and write it to disk
@@ -259,12 +723,100 @@ impl Encoder {
         }
         Ok(())
     }
+
+    /// Encode a subtitle with a subtitle encoder
+    ///
+    /// `sub.pts` is in [AV_TIME_BASE_Q] and the display times are in ms relative to it,
+    /// the packet timestamps are in the encoder time base
+    pub unsafe fn encode_subtitle(&mut self, sub: *const AVSubtitle) -> Result<*mut AVPacket> {
+        const MAX_SUBTITLE_SIZE: libc::c_int = 1024 * 1024;
+        let ms = AVRational { num: 1, den: 1000 };
+
+        let mut pkt = av_packet_alloc();
+        if pkt.is_null() {
+            bail!("Failed to allocate packet");
+        }
+        let ret = av_new_packet(pkt, MAX_SUBTITLE_SIZE);
+        bail_ffmpeg!(ret, {
+            av_packet_free(&mut pkt);
+        });
+        let size = avcodec_encode_subtitle(self.ctx, (*pkt).data, (*pkt).size, sub);
+        bail_ffmpeg!(size, "Failed to encode subtitle", {
+            av_packet_free(&mut pkt);
+        });
+        av_shrink_packet(pkt, size);
+
+        let tb = (*self.ctx).time_base;
+        let start = (*sub).pts + av_rescale_q((*sub).start_display_time as i64, ms, AV_TIME_BASE_Q);
+        let duration = (*sub).end_display_time as i64 - (*sub).start_display_time as i64;
+        (*pkt).time_base = tb;
+        (*pkt).pts = av_rescale_q(start, AV_TIME_BASE_Q, tb);
+        (*pkt).dts = (*pkt).pts;
+        (*pkt).duration = av_rescale_q(duration, ms, tb);
+        if let Some(idx) = self.dst_stream_index {
+            (*pkt).stream_index = idx;
+        }
+        Ok(pkt)
+    }
+}
+
+/// Encoder statistics of a single packet
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketQuality {
+    /// Quality of the frame in lambda units (QP * FF_QP2LAMBDA)
+    pub quality: u32,
+    /// Picture type of the encoded frame
+    pub pict_type: AVPictureType,
+    /// Sum of squared errors per plane, only set when [AV_CODEC_FLAG_PSNR] is enabled
+    pub errors: Vec<u64>,
+}
+
+impl PacketQuality {
+    /// PSNR (dB) of a plane with `pixels` samples, assuming 8-bit samples
+    pub fn psnr(&self, plane: usize, pixels: u64) -> Option<f64> {
+        let err = *self.errors.get(plane)?;
+        if err == 0 {
+            return Some(f64::INFINITY);
+        }
+        Some(10.0 * (255.0 * 255.0 * pixels as f64 / err as f64).log10())
+    }
+}
+
+/// Read the encoder [AV_PKT_DATA_QUALITY_STATS] side data of a packet
+pub unsafe fn packet_quality(pkt: *const AVPacket) -> Option<PacketQuality> {
+    let mut size = 0;
+    let data = av_packet_get_side_data(pkt, AV_PKT_DATA_QUALITY_STATS, &mut size);
+    if data.is_null() || size < 8 {
+        return None;
+    }
+    // u32le quality, u8 pict_type, u8 error count, 2 bytes reserved, u64le errors
+    let data = slice::from_raw_parts(data, size as usize);
+    let quality = u32::from_le_bytes(data[..4].try_into().ok()?);
+    let pict_type = if data[4] <= AVPictureType::AV_PICTURE_TYPE_BI as u8 {
+        std::mem::transmute::<i32, AVPictureType>(data[4] as i32)
+    } else {
+        AV_PICTURE_TYPE_NONE
+    };
+    let errors = data[8..]
+        .chunks_exact(8)
+        .take(data[5] as usize)
+        .map(|e| u64::from_le_bytes(e.try_into().unwrap()))
+        .collect();
+    Some(PacketQuality {
+        quality,
+        pict_type,
+        errors,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::generate_test_frame;
+    use crate::{generate_test_frame, setup_test_encoder, Decoder, Demuxer, Muxer, Scaler};
+    use ffmpeg_sys_the_third::{
+        av_frame_alloc, av_frame_free, av_frame_get_buffer, av_samples_set_silence,
+        AV_CODEC_FLAG2_FAST, AV_CODEC_FLAG_LOW_DELAY, AV_CODEC_FLAG_PSNR, AV_PKT_FLAG_KEY,
+    };
 
     #[test]
     fn test_encode_png() -> Result<(), Error> {
@@ -287,4 +839,454 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_explicit_time_base() -> Result<(), Error> {
+        unsafe {
+            let tb = AVRational {
+                num: 1,
+                den: 90_000,
+            };
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder.with_time_base(tb)?.open(None)?;
+            let ctx = encoder.codec_context();
+            assert_eq!((*ctx).time_base.den, 90_000);
+            assert_eq!((*ctx).framerate.num, 30);
+
+            let mut pkts = Vec::new();
+            for i in 0..30 {
+                (*frame).pts = i * 3000;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+            assert_eq!(pkts.len(), 30);
+            for mut pkt in pkts {
+                assert_eq!((*pkt).time_base.num, 1);
+                assert_eq!((*pkt).time_base.den, 90_000);
+                assert_eq!((*pkt).pts % 3000, 0);
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_codec_flags() -> Result<(), Error> {
+        // returns the reorder delay reported by the opened encoder
+        unsafe fn reorder_delay(low_delay: bool) -> Result<i32, Error> {
+            let mut src_frame = generate_test_frame();
+            let mut frame = Scaler::new().process_frame(
+                src_frame,
+                256,
+                256,
+                AVPixelFormat::AV_PIX_FMT_YUV420P,
+            )?;
+            // the mpeg2 encoder drops frame reordering in low delay mode
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_MPEG2VIDEO)?
+                .with_width(256)
+                .with_height(256)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_YUV420P)
+                .with_framerate(30.0)?
+                .with_flag2(AV_CODEC_FLAG2_FAST);
+            if low_delay {
+                encoder = encoder.with_flag(AV_CODEC_FLAG_LOW_DELAY);
+            }
+            let mut encoder = encoder.open(None)?;
+            let ctx = encoder.codec_context();
+            assert_ne!((*ctx).flags2 & AV_CODEC_FLAG2_FAST as libc::c_int, 0);
+            assert_eq!(
+                (*ctx).flags & AV_CODEC_FLAG_LOW_DELAY as libc::c_int != 0,
+                low_delay
+            );
+
+            (*frame).pts = 0;
+            let mut pkts = encoder.encode_frame(frame)?;
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+            assert!(!pkts.is_empty());
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+            av_frame_free(&mut src_frame);
+            Ok((*ctx).delay)
+        }
+
+        unsafe {
+            assert!(reorder_delay(false)? > 0);
+            assert_eq!(reorder_delay(true)?, 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_twice() -> Result<(), Error> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_PNG)?
+                .with_width((*frame).width)
+                .with_height((*frame).height)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_RGB24)
+                .open(None)?;
+
+            for mut pkt in encoder.encode_frame(frame)? {
+                av_packet_free(&mut pkt);
+            }
+            for mut pkt in encoder.encode_frame(ptr::null_mut())? {
+                av_packet_free(&mut pkt);
+            }
+            let pkts = encoder.encode_frame(ptr::null_mut())?;
+            assert!(pkts.is_empty());
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_no_frame_loss() -> Result<(), Error> {
+        unsafe {
+            let mut src_frame = generate_test_frame();
+            let mut scaler = Scaler::new();
+            let mut frame =
+                scaler.process_frame(src_frame, 256, 256, AVPixelFormat::AV_PIX_FMT_YUV420P)?;
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_RAWVIDEO)?
+                .with_width(256)
+                .with_height(256)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_YUV420P)
+                .with_framerate(30.0)?
+                .open(None)?;
+
+            // send frames without receiving so the encoder holds a packet and a frame
+            let ctx = encoder.codec_context();
+            for pts in 0..2 {
+                (*frame).pts = pts;
+                assert_eq!(avcodec_send_frame(ctx, frame), 0);
+            }
+            (*frame).pts = 2;
+            // the encoder is now full, so the next frame can only be sent after draining
+            assert_eq!(avcodec_send_frame(ctx, frame), AVERROR(EAGAIN));
+
+            let mut pkts = encoder.encode_frame(frame)?;
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+            let pts: Vec<i64> = pkts.iter().map(|p| (**p).pts).collect();
+            assert_eq!(pts, vec![0, 1, 2]);
+
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+            av_frame_free(&mut src_frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_keyframe() -> Result<(), Error> {
+        unsafe {
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder
+                .with_options(|ctx| (*ctx).gop_size = 250)
+                .open(None)?;
+
+            let mut pkts = Vec::new();
+            for pts in 0..30 {
+                if pts == 10 {
+                    encoder.force_next_keyframe();
+                }
+                (*frame).pts = pts;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+
+            let key_pts: Vec<i64> = pkts
+                .iter()
+                .filter(|p| (***p).flags & AV_PKT_FLAG_KEY as libc::c_int != 0)
+                .map(|p| (**p).pts)
+                .collect();
+            assert!(key_pts.contains(&10));
+
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_private_opts() -> Result<(), Error> {
+        let encoder = Encoder::new_with_name("libx264")?;
+        let opts = encoder.list_private_opts()?;
+        assert!(opts.iter().any(|o| o == "preset"));
+        assert!(opts.iter().any(|o| o == "crf"));
+        assert!(!opts.iter().any(|o| o == "b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_audio_skip_samples() -> Result<(), Error> {
+        unsafe {
+            let mut encoder = Encoder::new_with_name("libopus")?
+                .with_sample_rate(48_000)?
+                .with_sample_format(AVSampleFormat::AV_SAMPLE_FMT_S16)
+                .with_default_channel_layout(2)
+                .with_bitrate(64_000)
+                .open(None)?;
+            let ctx = encoder.codec_context();
+            assert!((*ctx).initial_padding > 0);
+
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_S16 as libc::c_int;
+            (*frame).nb_samples = (*ctx).frame_size;
+            (*frame).sample_rate = 48_000;
+            av_channel_layout_default(&mut (*frame).ch_layout, 2);
+            av_frame_get_buffer(frame, 0);
+            av_samples_set_silence(
+                (*frame).extended_data,
+                0,
+                (*frame).nb_samples,
+                2,
+                AVSampleFormat::AV_SAMPLE_FMT_S16,
+            );
+
+            let mut pkts = Vec::new();
+            for n in 0..10 {
+                (*frame).pts = n * (*ctx).frame_size as i64;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+            assert!(!pkts.is_empty());
+
+            let mut size = 0;
+            let data = av_packet_get_side_data(pkts[0], AV_PKT_DATA_SKIP_SAMPLES, &mut size);
+            assert!(!data.is_null());
+            assert_eq!(size, 10);
+            let skip = u32::from_le_bytes(slice::from_raw_parts(data, 4).try_into()?);
+            assert!(skip > 0);
+
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_framerate_snapped() -> Result<(), Error> {
+        unsafe {
+            let encoder = Encoder::new(AVCodecID::AV_CODEC_ID_MPEG2VIDEO)?;
+            let rates = encoder.supported_framerates()?;
+            assert!(!rates.is_empty());
+
+            let encoder = encoder.with_framerate_snapped(29.0)?;
+            let ctx = encoder.codec_context();
+            assert_eq!((*ctx).framerate.num, 30000);
+            assert_eq!((*ctx).framerate.den, 1001);
+
+            // unrestricted codecs keep the requested framerate
+            let encoder =
+                Encoder::new(AVCodecID::AV_CODEC_ID_H264)?.with_framerate_snapped(29.0)?;
+            assert_eq!((*encoder.codec_context()).framerate.num, 29);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_vp9_alpha() -> Result<(), Error> {
+        unsafe {
+            // h264 cannot encode alpha
+            let err = Encoder::new(AVCodecID::AV_CODEC_ID_H264)?
+                .with_width(256)
+                .with_height(256)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_YUVA420P)
+                .with_framerate(30.0)?
+                .open(None)
+                .err()
+                .expect("h264 should not accept yuva420p");
+            assert!(err.to_string().contains("alpha"));
+
+            let encoder = Encoder::new_with_name("libvpx-vp9")?;
+
+            // semi-transparent RGBA source
+            let mut src_frame = generate_test_frame();
+            let mut rgba =
+                Scaler::new().process_frame(src_frame, 256, 256, AVPixelFormat::AV_PIX_FMT_RGBA)?;
+            for y in 0..256 {
+                let line = (*rgba).data[0].add((y * (*rgba).linesize[0]) as usize);
+                for x in 0..256 {
+                    *line.add(x * 4 + 3) = 0x80;
+                }
+            }
+            let mut frame =
+                Scaler::new().process_frame(rgba, 256, 256, AVPixelFormat::AV_PIX_FMT_YUVA420P)?;
+            assert!(!(*frame).data[3].is_null());
+            assert_eq!(*(*frame).data[3], 0x80);
+
+            let mut encoder = encoder
+                .with_width(256)
+                .with_height(256)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_YUVA420P)
+                .with_framerate(30.0)?
+                .open(Some(HashMap::from([
+                    ("deadline".to_string(), "realtime".to_string()),
+                    ("cpu-used".to_string(), "8".to_string()),
+                ])))?;
+
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_vp9_alpha.webm";
+            let mut muxer = Muxer::builder()
+                .with_output_path(path, None)?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            for pts in 0..10 {
+                (*frame).pts = pts;
+                muxer.write_encoded(&mut encoder, frame)?;
+            }
+            muxer.write_encoded(&mut encoder, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut frame);
+            av_frame_free(&mut rgba);
+            av_frame_free(&mut src_frame);
+
+            // the native vp9 decoder drops alpha, use libvpx
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let mut decoder = Decoder::new();
+            decoder.setup_decoder_by_name(
+                info.best_video().expect("no video stream"),
+                "libvpx-vp9",
+                None,
+            )?;
+            let mut frames = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                for mut f in decoder.decode_pkt(pkt)? {
+                    assert_eq!(
+                        (*f).format,
+                        AVPixelFormat::AV_PIX_FMT_YUVA420P as libc::c_int
+                    );
+                    assert!(!(*f).data[3].is_null());
+                    frames += 1;
+                    av_frame_free(&mut f);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(frames, 10);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_tuning() -> Result<(), Error> {
+        unsafe {
+            assert!(Encoder::new(AVCodecID::AV_CODEC_ID_H264)?
+                .with_refs(0)
+                .is_err());
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder
+                .with_refs(4)?
+                .with_max_b_frames(2)?
+                .with_me_range(16)?
+                .open(None)?;
+            assert_eq!((*encoder.codec_context()).refs, 4);
+
+            let mut pkts = Vec::new();
+            for pts in 0..10 {
+                (*frame).pts = pts;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+            assert!(!pkts.is_empty());
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_size() -> Result<(), Error> {
+        unsafe {
+            let encoder = Encoder::new(AVCodecID::AV_CODEC_ID_H264)?.with_bitrate(1_000_000);
+            assert_eq!(encoder.estimated_bitrate(), 1_000_000);
+            assert_eq!(
+                crate::estimate_output_size(encoder.estimated_bitrate(), 10.0),
+                1_250_000
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_packet_quality() -> Result<(), Error> {
+        unsafe {
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder
+                .with_options(|ctx| (*ctx).flags |= AV_CODEC_FLAG_PSNR as libc::c_int)
+                .open(None)?;
+
+            let mut pkts = Vec::new();
+            for pts in 0..10 {
+                (*frame).pts = pts;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+            assert!(!pkts.is_empty());
+
+            for mut pkt in pkts {
+                let q = packet_quality(pkt).expect("no quality stats");
+                assert!(q.quality > 0);
+                assert_ne!(q.pict_type, AV_PICTURE_TYPE_NONE);
+                assert_eq!(q.errors.len(), 3);
+                assert!(q.psnr(0, 256 * 256).expect("no luma error") > 20.0);
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_preset() -> Result<(), Error> {
+        use ffmpeg_sys_the_third::av_opt_get_double;
+
+        unsafe {
+            let encoder = Encoder::new(AVCodecID::AV_CODEC_ID_H264)?
+                .with_width(1280)
+                .with_height(720)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_YUV420P)
+                .with_framerate(30.0)?
+                .with_preset(Preset::Medium)?;
+            let ctx = encoder.codec_context();
+            assert!(encoder.estimated_bitrate() > 0);
+            assert_eq!((*ctx).gop_size, 60);
+            if encoder.list_private_opts()?.iter().any(|o| o == "crf") {
+                let mut crf = 0.0;
+                let ret = av_opt_get_double((*ctx).priv_data, cstr!("crf"), 0, &mut crf);
+                bail_ffmpeg!(ret);
+                assert_eq!(crf, 23.0);
+            } else {
+                assert!((*ctx).bit_rate > 0);
+            }
+            let _encoder = encoder.open(None)?;
+
+            // the video size is needed to pick a bitrate
+            assert!(Encoder::new(AVCodecID::AV_CODEC_ID_H264)?
+                .with_preset(Preset::Medium)
+                .is_err());
+
+            let encoder = Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                .with_sample_rate(48_000)?
+                .with_default_channel_layout(2)
+                .with_preset(Preset::High)?;
+            assert_eq!((*encoder.codec_context()).bit_rate, 192_000);
+        }
+        Ok(())
+    }
 }
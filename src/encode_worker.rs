@@ -0,0 +1,232 @@
+use crate::{bail_ffmpeg, Encoder};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_channel_layout_copy, av_frame_alloc, av_frame_clone, av_frame_copy, av_frame_copy_props,
+    av_frame_free, av_frame_get_buffer, av_packet_free, AVFrame, AVPacket,
+};
+use std::ptr;
+use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TryRecvError};
+use std::thread::JoinHandle;
+
+/// Owned frame/packet pointer which is moved between threads
+struct SendPtr<T>(*mut T);
+
+unsafe impl<T> Send for SendPtr<T> {}
+
+/// Runs an [Encoder] on its own thread so decoding and encoding can overlap
+///
+/// Frames are sent with [EncoderWorker::send_frame] and packets are collected with
+/// [EncoderWorker::recv_packets], [EncoderWorker::finish] flushes the encoder
+pub struct EncoderWorker {
+    frames: Option<SyncSender<SendPtr<AVFrame>>>,
+    packets: Receiver<Result<SendPtr<AVPacket>>>,
+    handle: Option<JoinHandle<Encoder>>,
+}
+
+impl Drop for EncoderWorker {
+    fn drop(&mut self) {
+        // stop the thread and free any packets which were not collected
+        self.frames.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        while let Ok(Ok(mut pkt)) = self.packets.try_recv() {
+            unsafe {
+                av_packet_free(&mut pkt.0);
+            }
+        }
+    }
+}
+
+impl EncoderWorker {
+    /// Start a worker thread for an opened encoder, at most `queue` frames are buffered
+    pub fn new(mut encoder: Encoder, queue: usize) -> Result<Self> {
+        let (frame_tx, frame_rx) = sync_channel::<SendPtr<AVFrame>>(queue.max(1));
+        let (pkt_tx, pkt_rx) = channel();
+        let handle = std::thread::Builder::new()
+            .name("encoder".to_string())
+            .spawn(move || {
+                for mut frame in frame_rx {
+                    let ret = unsafe { encoder.encode_frame(frame.0) };
+                    if !frame.0.is_null() {
+                        unsafe {
+                            av_frame_free(&mut frame.0);
+                        }
+                    }
+                    match ret {
+                        Ok(pkts) => {
+                            for pkt in pkts {
+                                // the receiver is gone, free the packet
+                                if let Err(e) = pkt_tx.send(Ok(SendPtr(pkt))) {
+                                    if let Ok(mut pkt) = e.0 {
+                                        unsafe {
+                                            av_packet_free(&mut pkt.0);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            let _ = pkt_tx.send(Err(e));
+                            break;
+                        }
+                    }
+                }
+                encoder
+            })?;
+        Ok(Self {
+            frames: Some(frame_tx),
+            packets: pkt_rx,
+            handle: Some(handle),
+        })
+    }
+
+    /// Queue a frame for encoding, blocking if the queue is full
+    ///
+    /// The frame is not consumed, its data is copied so the caller can reuse it right away.
+    /// Hardware frames are sent as a new reference, their surface must not be written
+    /// until the frame is encoded
+    pub unsafe fn send_frame(&self, frame: *mut AVFrame) -> Result<()> {
+        if frame.is_null() {
+            bail!("Use finish() to flush the encoder");
+        }
+        let mut frame = Self::copy_frame(frame)?;
+        if let Some(tx) = &self.frames {
+            if let Err(mut e) = tx.send(SendPtr(frame)) {
+                av_frame_free(&mut e.0 .0);
+                bail!("Encoder worker has stopped");
+            }
+        } else {
+            av_frame_free(&mut frame);
+            bail!("Encoder worker is finished");
+        }
+        Ok(())
+    }
+
+    /// Collect the packets which have been encoded so far without blocking
+    pub fn recv_packets(&self) -> Result<Vec<*mut AVPacket>> {
+        let mut ret = Vec::new();
+        loop {
+            match self.packets.try_recv() {
+                Ok(Ok(pkt)) => ret.push(pkt.0),
+                Ok(Err(e)) => {
+                    Self::free_packets(ret);
+                    return Err(e);
+                }
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Flush the encoder and wait for the worker to finish,
+    /// returns the remaining packets and the encoder
+    pub fn finish(mut self) -> Result<(Vec<*mut AVPacket>, Encoder)> {
+        if let Some(tx) = self.frames.take() {
+            // a null frame flushes the encoder, which may fail if the thread already stopped
+            let _ = tx.send(SendPtr(ptr::null_mut()));
+        }
+        let encoder = if let Some(handle) = self.handle.take() {
+            match handle.join() {
+                Ok(e) => e,
+                Err(_) => bail!("Encoder worker panicked"),
+            }
+        } else {
+            bail!("Encoder worker is finished");
+        };
+        let mut ret = Vec::new();
+        for pkt in self.packets.try_iter() {
+            match pkt {
+                Ok(pkt) => ret.push(pkt.0),
+                Err(e) => {
+                    Self::free_packets(ret);
+                    return Err(e);
+                }
+            }
+        }
+        Ok((ret, encoder))
+    }
+
+    /// Copy a software frame into new buffers which are not shared with the caller
+    unsafe fn copy_frame(frame: *const AVFrame) -> Result<*mut AVFrame> {
+        if !(*frame).hw_frames_ctx.is_null() {
+            let frame = av_frame_clone(frame);
+            if frame.is_null() {
+                bail!("Failed to clone frame");
+            }
+            return Ok(frame);
+        }
+        let mut dst = av_frame_alloc();
+        if dst.is_null() {
+            bail!("Failed to allocate frame");
+        }
+        (*dst).format = (*frame).format;
+        (*dst).width = (*frame).width;
+        (*dst).height = (*frame).height;
+        (*dst).nb_samples = (*frame).nb_samples;
+        let ret = av_channel_layout_copy(&mut (*dst).ch_layout, &(*frame).ch_layout);
+        bail_ffmpeg!(ret, { av_frame_free(&mut dst) });
+        let ret = av_frame_get_buffer(dst, 0);
+        bail_ffmpeg!(ret, { av_frame_free(&mut dst) });
+        let ret = av_frame_copy(dst, frame);
+        bail_ffmpeg!(ret, { av_frame_free(&mut dst) });
+        let ret = av_frame_copy_props(dst, frame);
+        bail_ffmpeg!(ret, { av_frame_free(&mut dst) });
+        Ok(dst)
+    }
+
+    fn free_packets(pkts: Vec<*mut AVPacket>) {
+        for mut pkt in pkts {
+            unsafe {
+                av_packet_free(&mut pkt);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frame_plane, frame_plane_mut, setup_test_encoder, test_encoder};
+    use std::slice;
+
+    #[test]
+    fn worker_packet_count() -> Result<()> {
+        unsafe {
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder.open(None)?;
+            let mut sync_packets = Vec::new();
+            for pts in 0..90 {
+                (*frame).pts = pts;
+                sync_packets.extend(encoder.encode_frame(frame)?);
+            }
+            sync_packets.extend(encoder.encode_frame(ptr::null_mut())?);
+
+            let worker = EncoderWorker::new(test_encoder(256, 256)?.open(None)?, 8)?;
+            let picture = frame_plane(frame, 0)?.to_vec();
+            let mut packets = Vec::new();
+            for pts in 0..90 {
+                (*frame).pts = pts;
+                frame_plane_mut(frame, 0)?.copy_from_slice(&picture);
+                worker.send_frame(frame)?;
+                // the worker has its own copy, so the frame can be reused right away
+                frame_plane_mut(frame, 0)?.fill(0);
+                packets.extend(worker.recv_packets()?);
+            }
+            let (rest, _encoder) = worker.finish()?;
+            packets.extend(rest);
+
+            assert_eq!(packets.len(), sync_packets.len());
+            for (a, b) in packets.iter().zip(sync_packets.iter()) {
+                assert_eq!(
+                    slice::from_raw_parts((**a).data, (**a).size as usize),
+                    slice::from_raw_parts((**b).data, (**b).size as usize)
+                );
+            }
+            EncoderWorker::free_packets(packets);
+            EncoderWorker::free_packets(sync_packets);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+}
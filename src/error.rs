@@ -0,0 +1,52 @@
+use crate::get_ffmpeg_error_msg;
+use ffmpeg_sys_the_third::{AVERROR, AVERROR_EOF, AVERROR_INVALIDDATA};
+use std::fmt::{Display, Formatter};
+
+/// Typed error from an ffmpeg return code
+#[derive(Clone, Debug, PartialEq)]
+pub enum FfmpegError {
+    /// End of file / stream has been flushed (AVERROR_EOF)
+    Eof,
+    /// Output is not available in this state, try again (EAGAIN)
+    Again,
+    /// Invalid data found when processing input (AVERROR_INVALIDDATA)
+    InvalidData,
+    /// Any other error code with its message
+    Other(i32, String),
+}
+
+impl FfmpegError {
+    /// Create an error from a negative ffmpeg return code
+    pub fn from_ret(ret: libc::c_int) -> Self {
+        if ret == AVERROR_EOF {
+            Self::Eof
+        } else if ret == AVERROR(libc::EAGAIN) {
+            Self::Again
+        } else if ret == AVERROR_INVALIDDATA {
+            Self::InvalidData
+        } else {
+            Self::Other(ret, get_ffmpeg_error_msg(ret))
+        }
+    }
+
+    /// The ffmpeg error code
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Eof => AVERROR_EOF,
+            Self::Again => AVERROR(libc::EAGAIN),
+            Self::InvalidData => AVERROR_INVALIDDATA,
+            Self::Other(code, _) => *code,
+        }
+    }
+}
+
+impl Display for FfmpegError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Other(_, msg) => write!(f, "{}", msg),
+            e => write!(f, "{}", get_ffmpeg_error_msg(e.code())),
+        }
+    }
+}
+
+impl std::error::Error for FfmpegError {}
@@ -1,9 +1,13 @@
-use crate::{bail_ffmpeg, cstr, rstr, set_opts};
-use anyhow::Error;
+use crate::{bail_ffmpeg, cstr, rstr, set_opts, HwDevice};
+use anyhow::{bail, Error};
 use ffmpeg_sys_the_third::{
-    av_strdup, avfilter_get_by_name, avfilter_graph_alloc, avfilter_graph_alloc_filter,
-    avfilter_graph_config, avfilter_graph_create_filter, avfilter_graph_dump, avfilter_graph_parse,
-    avfilter_inout_alloc, AVFilterContext, AVFilterGraph, AVFrame,
+    av_buffer_ref, av_buffer_unref, av_buffersink_get_frame, av_buffersrc_add_frame_flags,
+    av_buffersrc_parameters_alloc, av_buffersrc_parameters_set, av_frame_alloc, av_frame_free,
+    av_free, av_strdup, avfilter_get_by_name, avfilter_graph_alloc, avfilter_graph_alloc_filter,
+    avfilter_graph_config, avfilter_graph_create_filter, avfilter_graph_dump, avfilter_graph_free,
+    avfilter_graph_parse_ptr, avfilter_inout_alloc, avfilter_inout_free, AVBufferRef,
+    AVFilterContext, AVFilterGraph, AVFrame, AVRational, AVERROR, AVERROR_EOF,
+    AV_BUFFERSRC_FLAG_KEEP_REF,
 };
 use log::debug;
 use std::collections::HashMap;
@@ -11,6 +15,12 @@ use std::ptr;
 
 pub struct Filter {
     graph: *mut AVFilterGraph,
+    /// Buffer source (input) of the graph
+    src: *mut AVFilterContext,
+    /// Buffer sink (output) of the graph
+    sink: *mut AVFilterContext,
+    /// Hardware device used by filters which need one (hwupload, scale_vaapi etc.)
+    hw_device: *mut AVBufferRef,
 }
 
 impl Default for Filter {
@@ -19,57 +29,197 @@ impl Default for Filter {
     }
 }
 
+impl Drop for Filter {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.graph.is_null() {
+                avfilter_graph_free(&mut self.graph);
+            }
+            av_buffer_unref(&mut self.hw_device);
+        }
+    }
+}
+
 impl Filter {
     pub fn new() -> Self {
         Self {
             graph: unsafe { avfilter_graph_alloc() },
+            src: ptr::null_mut(),
+            sink: ptr::null_mut(),
+            hw_device: ptr::null_mut(),
         }
     }
 
-    /// Parse filter from string using [avfilter_graph_parse2]
+    /// Use a hardware device for the filters in the graph, must be set before [Filter::build]
+    pub unsafe fn set_hw_device(&mut self, device: &HwDevice) -> Result<(), Error> {
+        av_buffer_unref(&mut self.hw_device);
+        self.hw_device = device.new_ref()?;
+        Ok(())
+    }
+
+    /// Parse filter from string using [avfilter_graph_parse_ptr]
     ///
     /// https://ffmpeg.org/ffmpeg-filters.html
     pub unsafe fn parse(graph: &str) -> Result<Self, Error> {
-        let ctx = avfilter_graph_alloc();
-        let inputs = avfilter_inout_alloc();
-        let outputs = avfilter_inout_alloc();
+        Self::parse_with_args(graph, None, ptr::null_mut(), None)
+    }
+
+    /// Parse a video filter from string, configuring the buffer source from the properties
+    /// of `frame` along with the `time_base` of its timestamps and the `framerate` of the input.
+    ///
+    /// An accurate time_base/framerate is required by temporal filters (fps, setpts etc.)
+    pub unsafe fn parse_video(
+        graph: &str,
+        frame: *const AVFrame,
+        time_base: AVRational,
+        framerate: AVRational,
+    ) -> Result<Self, Error> {
+        Self::parse_video_with_device(graph, frame, time_base, framerate, None)
+    }
+
+    /// Parse a video filter using a hardware device (eg. `hwupload,scale_vaapi=w=1280:h=720`)
+    ///
+    /// If `frame` is a hardware frame its frames context is passed to the buffer source,
+    /// so the graph can operate on the frames without downloading them
+    pub unsafe fn parse_video_hw(
+        graph: &str,
+        frame: *const AVFrame,
+        time_base: AVRational,
+        framerate: AVRational,
+        device: &HwDevice,
+    ) -> Result<Self, Error> {
+        Self::parse_video_with_device(graph, frame, time_base, framerate, Some(device))
+    }
+
+    unsafe fn parse_video_with_device(
+        graph: &str,
+        frame: *const AVFrame,
+        time_base: AVRational,
+        framerate: AVRational,
+        device: Option<&HwDevice>,
+    ) -> Result<Self, Error> {
+        let sar = if (*frame).sample_aspect_ratio.num == 0 {
+            AVRational { num: 1, den: 1 }
+        } else {
+            (*frame).sample_aspect_ratio
+        };
+        let args = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}/{}:frame_rate={}/{}:pixel_aspect={}/{}",
+            (*frame).width,
+            (*frame).height,
+            (*frame).format,
+            time_base.num,
+            time_base.den,
+            framerate.num,
+            framerate.den,
+            sar.num,
+            sar.den
+        );
+        Self::parse_with_args(graph, Some(&args), (*frame).hw_frames_ctx, device)
+    }
+
+    /// Create a video filter which renders (burns) the subtitles from `subtitle_path` onto
+    /// frames using the `subtitles` filter (libass).
+    ///
+    /// Fonts are loaded using fontconfig, the subtitle file must be readable for the lifetime
+    /// of the filter. Frame timestamps must be relative to the start of the subtitles.
+    pub unsafe fn burn_subtitles(
+        subtitle_path: &str,
+        frame: *const AVFrame,
+        time_base: AVRational,
+        framerate: AVRational,
+    ) -> Result<Self, Error> {
+        let graph = format!("subtitles=filename={}", escape_filter_value(subtitle_path));
+        Self::parse_video(&graph, frame, time_base, framerate)
+    }
+
+    unsafe fn parse_with_args(
+        graph: &str,
+        src_args: Option<&str>,
+        src_frames_ctx: *mut AVBufferRef,
+        device: Option<&HwDevice>,
+    ) -> Result<Self, Error> {
+        let mut ret = Self::new();
+        if let Some(device) = device {
+            ret.set_hw_device(device)?;
+        }
+        let mut inputs = avfilter_inout_alloc();
+        let mut outputs = avfilter_inout_alloc();
         let src = avfilter_get_by_name(cstr!("buffer"));
         let dst = avfilter_get_by_name(cstr!("buffersink"));
         let mut src_ctx = ptr::null_mut();
         let mut dst_ctx = ptr::null_mut();
-        let ret = avfilter_graph_create_filter(
+        let r = avfilter_graph_create_filter(
             &mut src_ctx,
             src,
             cstr!("in"),
+            if let Some(args) = src_args {
+                cstr!(args)
+            } else {
+                ptr::null_mut()
+            },
             ptr::null_mut(),
-            ptr::null_mut(),
-            ctx,
+            ret.graph,
         );
-        bail_ffmpeg!(ret, "Failed to parse graph");
+        bail_ffmpeg!(r, "Failed to parse graph", {
+            avfilter_inout_free(&mut inputs);
+            avfilter_inout_free(&mut outputs);
+        });
+        if !src_frames_ctx.is_null() {
+            let par = av_buffersrc_parameters_alloc();
+            if par.is_null() {
+                avfilter_inout_free(&mut inputs);
+                avfilter_inout_free(&mut outputs);
+                bail!("Failed to allocate buffer source parameters");
+            }
+            // the parameters only borrow the frames context, it's referenced by the filter
+            (*par).hw_frames_ctx = src_frames_ctx;
+            let r = av_buffersrc_parameters_set(src_ctx, par);
+            av_free(par as *mut _);
+            bail_ffmpeg!(r, "Failed to set buffer source parameters", {
+                avfilter_inout_free(&mut inputs);
+                avfilter_inout_free(&mut outputs);
+            });
+        }
 
-        let ret = avfilter_graph_create_filter(
+        let r = avfilter_graph_create_filter(
             &mut dst_ctx,
             dst,
             cstr!("out"),
             ptr::null_mut(),
             ptr::null_mut(),
-            ctx,
+            ret.graph,
         );
-        bail_ffmpeg!(ret, "Failed to parse graph");
+        bail_ffmpeg!(r, "Failed to parse graph", {
+            avfilter_inout_free(&mut inputs);
+            avfilter_inout_free(&mut outputs);
+        });
 
-        (*outputs).name = av_strdup((*dst).name);
-        (*outputs).filter_ctx = dst_ctx;
+        // the buffer source is the output feeding into the graph
+        (*outputs).name = av_strdup(cstr!("in"));
+        (*outputs).filter_ctx = src_ctx;
         (*outputs).pad_idx = 0;
         (*outputs).next = ptr::null_mut();
 
-        (*inputs).name = av_strdup((*src).name);
-        (*inputs).filter_ctx = src_ctx;
+        // the buffer sink is the input the graph feeds into
+        (*inputs).name = av_strdup(cstr!("out"));
+        (*inputs).filter_ctx = dst_ctx;
         (*inputs).pad_idx = 0;
         (*inputs).next = ptr::null_mut();
 
-        let ret = avfilter_graph_parse(ctx, cstr!(graph), inputs, outputs, ptr::null_mut());
-        bail_ffmpeg!(ret, "Failed to parse graph");
-        let mut ret = Self { graph: ctx };
+        let r = avfilter_graph_parse_ptr(
+            ret.graph,
+            cstr!(graph),
+            &mut inputs,
+            &mut outputs,
+            ptr::null_mut(),
+        );
+        avfilter_inout_free(&mut inputs);
+        avfilter_inout_free(&mut outputs);
+        bail_ffmpeg!(r, "Failed to parse graph");
+
+        ret.src = src_ctx;
+        ret.sink = dst_ctx;
         ret.build()?;
         Ok(ret)
     }
@@ -99,6 +249,17 @@ impl Filter {
     }
 
     pub unsafe fn build(&mut self) -> Result<(), Error> {
+        if !self.hw_device.is_null() {
+            for i in 0..(*self.graph).nb_filters as usize {
+                let flt = *(*self.graph).filters.add(i);
+                if (*flt).hw_device_ctx.is_null() {
+                    (*flt).hw_device_ctx = av_buffer_ref(self.hw_device);
+                    if (*flt).hw_device_ctx.is_null() {
+                        bail!("Failed to reference HW device");
+                    }
+                }
+            }
+        }
         let d = rstr!(avfilter_graph_dump(self.graph, ptr::null_mut()));
         debug!("{}", d);
 
@@ -107,7 +268,227 @@ impl Filter {
         Ok(())
     }
 
-    pub unsafe fn process_frame(&mut self, frame: *mut AVFrame) -> Result<*mut AVFrame, Error> {
-        todo!();
+    /// Send a frame through the filter graph, returning any frames which are output
+    ///
+    /// The input frame is not consumed, output frames must be freed by the caller
+    pub unsafe fn process_frame(
+        &mut self,
+        frame: *mut AVFrame,
+    ) -> Result<Vec<*mut AVFrame>, Error> {
+        if self.src.is_null() || self.sink.is_null() {
+            bail!("Filter graph has no buffer source/sink");
+        }
+        let ret = av_buffersrc_add_frame_flags(
+            self.src,
+            frame,
+            AV_BUFFERSRC_FLAG_KEEP_REF as libc::c_int,
+        );
+        bail_ffmpeg!(ret, "Failed to send frame to filter");
+        self.receive_frames()
+    }
+
+    /// Signal EOF to the filter graph, returning any frames which were buffered
+    /// by the filters (eg. fps, tmix)
+    pub unsafe fn flush(&mut self) -> Result<Vec<*mut AVFrame>, Error> {
+        if self.src.is_null() || self.sink.is_null() {
+            bail!("Filter graph has no buffer source/sink");
+        }
+        let ret = av_buffersrc_add_frame_flags(self.src, ptr::null_mut(), 0);
+        bail_ffmpeg!(ret, "Failed to flush filter");
+        self.receive_frames()
+    }
+
+    /// Get all available frames from the buffer sink
+    unsafe fn receive_frames(&mut self) -> Result<Vec<*mut AVFrame>, Error> {
+        let mut frames = Vec::new();
+        loop {
+            let mut out = av_frame_alloc();
+            let ret = av_buffersink_get_frame(self.sink, out);
+            if ret == AVERROR(libc::EAGAIN) || ret == AVERROR_EOF {
+                av_frame_free(&mut out);
+                break;
+            }
+            bail_ffmpeg!(ret, "Failed to get frame from filter", {
+                av_frame_free(&mut out);
+            });
+            frames.push(out);
+        }
+        Ok(frames)
+    }
+}
+
+/// Escape a filter option value for use in a filter graph string
+///
+/// Values are escaped once for the option parser and again for the graph parser
+fn escape_filter_value(value: &str) -> String {
+    fn escape(s: &str, special: &[char]) -> String {
+        let mut ret = String::with_capacity(s.len());
+        for c in s.chars() {
+            if c == '\\' || special.contains(&c) {
+                ret.push('\\');
+            }
+            ret.push(c);
+        }
+        ret
+    }
+    let opt = escape(value, &['\'', ':']);
+    escape(&opt, &['\'', '[', ']', ',', ';'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frame_make_writable, frame_plane, frame_plane_mut, generate_test_frame, Encoder};
+    use ffmpeg_sys_the_third::{av_frame_get_buffer, av_packet_free, AVCodecID, AVPixelFormat};
+
+    #[test]
+    fn filter_setpts() -> Result<(), Error> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut filter = Filter::parse_video(
+                "setpts=2*PTS",
+                frame,
+                AVRational { num: 1, den: 30 },
+                AVRational { num: 30, den: 1 },
+            )?;
+
+            let mut out = Vec::new();
+            for pts in 0..5 {
+                (*frame).pts = pts;
+                out.extend(filter.process_frame(frame)?);
+            }
+            assert_eq!(out.len(), 5);
+            for (i, f) in out.iter_mut().enumerate() {
+                assert_eq!((**f).pts, i as i64 * 2);
+                av_frame_free(f);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn filter_flush() -> Result<(), Error> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).width = 64;
+            (*frame).height = 64;
+            (*frame).format = AVPixelFormat::AV_PIX_FMT_GRAY8 as libc::c_int;
+            av_frame_get_buffer(frame, 0);
+            // tmix emits a blended frame for every input, fps holds the last one until EOF
+            let mut filter = Filter::parse_video(
+                "tmix=frames=2,fps=30",
+                frame,
+                AVRational { num: 1, den: 30 },
+                AVRational { num: 30, den: 1 },
+            )?;
+
+            let mut out = Vec::new();
+            for pts in 0..5 {
+                (*frame).pts = pts;
+                // the filter keeps references to the previous frames
+                frame_make_writable(frame)?;
+                frame_plane_mut(frame, 0)?.fill(pts as u8 * 40);
+                out.extend(filter.process_frame(frame)?);
+            }
+            let n_frames = out.len();
+            out.extend(filter.flush()?);
+            assert!(out.len() > n_frames);
+            assert_eq!(out.len(), 5);
+            for (i, f) in out.iter_mut().enumerate() {
+                // each frame is the average of the current and previous input
+                let expected = (i as i32 * 40 - 20).max(0);
+                let value = frame_plane(*f, 0)?[0] as i32;
+                assert!((value - expected).abs() <= 1, "frame {} was {}", i, value);
+                av_frame_free(f);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn escape_value() {
+        assert_eq!(escape_filter_value("sub.srt"), "sub.srt");
+        assert_eq!(escape_filter_value("C:\\a b.srt"), "C\\\\:\\\\\\\\a b.srt");
+        assert_eq!(escape_filter_value("it's.srt"), "it\\\\\\'s.srt");
+    }
+
+    #[test]
+    fn filter_burn_subtitles() -> Result<(), Error> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let srt = "test_output/test_burn.srt";
+            std::fs::write(srt, "1\n00:00:00,000 --> 00:00:05,000\nHello World\n")?;
+
+            let mut frame = generate_test_frame();
+            let mut filter = Filter::burn_subtitles(
+                srt,
+                frame,
+                AVRational { num: 1, den: 30 },
+                AVRational { num: 30, den: 1 },
+            )?;
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_PNG)?
+                .with_width((*frame).width)
+                .with_height((*frame).height)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_RGB24)
+                .open(None)?;
+
+            let size = ((*frame).linesize[0] * (*frame).height) as usize;
+            let src = std::slice::from_raw_parts((*frame).data[0], size);
+            let mut out = Vec::new();
+            for pts in 0..5 {
+                (*frame).pts = pts;
+                out.extend(filter.process_frame(frame)?);
+            }
+            assert!(!out.is_empty());
+            for f in out.iter_mut() {
+                assert_eq!((**f).format, (*frame).format);
+                let dst = std::slice::from_raw_parts((**f).data[0], size);
+                assert_ne!(src, dst);
+
+                let pkts = encoder.encode_frame(*f)?;
+                assert!(!pkts.is_empty());
+                for mut pkt in pkts {
+                    av_packet_free(&mut pkt);
+                }
+                av_frame_free(f);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "requires a VAAPI device"]
+    fn filter_hw_scale() -> Result<(), Error> {
+        use ffmpeg_sys_the_third::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI;
+
+        unsafe {
+            let device = HwDevice::new(AV_HWDEVICE_TYPE_VAAPI)?;
+            assert_eq!(device.type_name(), "vaapi");
+
+            let mut frame = generate_test_frame();
+            let mut filter = Filter::parse_video_hw(
+                "format=nv12,hwupload,scale_vaapi=w=640:h=360,hwdownload,format=nv12",
+                frame,
+                AVRational { num: 1, den: 30 },
+                AVRational { num: 30, den: 1 },
+                &device,
+            )?;
+            (*frame).pts = 0;
+            let mut out = filter.process_frame(frame)?;
+            out.extend(filter.flush()?);
+            assert_eq!(out.len(), 1);
+            for f in out.iter_mut() {
+                assert_eq!((**f).width, 640);
+                assert_eq!((**f).height, 360);
+                assert_eq!((**f).format, AVPixelFormat::AV_PIX_FMT_NV12 as i32);
+                assert!((**f).hw_frames_ctx.is_null());
+                av_frame_free(f);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
     }
 }
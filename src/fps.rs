@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_d2q, av_frame_clone, av_frame_free, av_inv_q, av_rescale_q, AVFrame, AVRational,
+    AV_NOPTS_VALUE,
+};
+use std::ptr;
+
+/// Frame rate converter which drops or duplicates frames to reach a target frame rate
+///
+/// Output frames have their PTS in [FpsConverter::time_base] (1/fps)
+pub struct FpsConverter {
+    in_time_base: AVRational,
+    out_time_base: AVRational,
+    next_pts: i64,
+    last_pts: i64,
+    last_frame: *mut AVFrame,
+}
+
+impl Drop for FpsConverter {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.last_frame.is_null() {
+                av_frame_free(&mut self.last_frame);
+            }
+        }
+    }
+}
+
+impl FpsConverter {
+    /// Create a new converter for frames with timestamps in `time_base`
+    pub fn new(time_base: AVRational, fps: f32) -> Self {
+        let out_time_base = unsafe { av_inv_q(av_d2q(fps as f64, 90_000)) };
+        Self {
+            in_time_base: time_base,
+            out_time_base,
+            next_pts: 0,
+            last_pts: 0,
+            last_frame: ptr::null_mut(),
+        }
+    }
+
+    /// Time base of the output frames
+    pub fn time_base(&self) -> AVRational {
+        self.out_time_base
+    }
+
+    /// Emit copies of the buffered frame for every output slot up to `end`
+    unsafe fn emit_until(&mut self, end: i64) -> Result<Vec<*mut AVFrame>> {
+        let mut ret = Vec::new();
+        while self.next_pts < end {
+            let out = av_frame_clone(self.last_frame);
+            if out.is_null() {
+                bail!("Failed to clone frame");
+            }
+            (*out).pts = self.next_pts;
+            (*out).time_base = self.out_time_base;
+            ret.push(out);
+            self.next_pts += 1;
+        }
+        av_frame_free(&mut self.last_frame);
+        Ok(ret)
+    }
+
+    /// Process a frame, returning zero or more frames at the target frame rate
+    ///
+    /// The input frame is not consumed, output frames must be freed by the caller
+    pub unsafe fn process_frame(&mut self, frame: *mut AVFrame) -> Result<Vec<*mut AVFrame>> {
+        let ts = if (*frame).pts != AV_NOPTS_VALUE {
+            (*frame).pts
+        } else {
+            (*frame).best_effort_timestamp
+        };
+        if ts == AV_NOPTS_VALUE {
+            bail!("Frame has no timestamp");
+        }
+        let pts = av_rescale_q(ts, self.in_time_base, self.out_time_base);
+
+        let ret = if self.last_frame.is_null() {
+            self.next_pts = pts;
+            vec![]
+        } else {
+            self.emit_until(pts)?
+        };
+        self.last_frame = av_frame_clone(frame);
+        if self.last_frame.is_null() {
+            bail!("Failed to clone frame");
+        }
+        self.last_pts = pts;
+        Ok(ret)
+    }
+
+    /// Flush the last buffered frame
+    pub unsafe fn flush(&mut self) -> Result<Vec<*mut AVFrame>> {
+        if self.last_frame.is_null() {
+            return Ok(vec![]);
+        }
+        self.emit_until(self.last_pts + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_test_frame;
+
+    #[test]
+    fn convert_60_to_30() -> Result<()> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut fps = FpsConverter::new(AVRational { num: 1, den: 60 }, 30.0);
+
+            let mut out = Vec::new();
+            for pts in 0..60 {
+                (*frame).pts = pts;
+                out.extend(fps.process_frame(frame)?);
+            }
+            out.extend(fps.flush()?);
+
+            assert!((29..=31).contains(&out.len()));
+            for (i, f) in out.iter_mut().enumerate() {
+                assert_eq!((**f).pts, i as i64);
+                av_frame_free(f);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,597 @@
+use crate::{bail_ffmpeg, get_frame_duration};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_channel_layout_compare, av_channel_layout_copy, av_frame_alloc, av_frame_apply_cropping,
+    av_frame_free, av_frame_get_buffer, av_frame_make_writable, av_get_bytes_per_sample,
+    av_get_packed_sample_fmt, av_image_get_linesize, av_pix_fmt_count_planes, av_pix_fmt_desc_get,
+    av_sample_fmt_is_planar, av_samples_copy, av_samples_set_silence, AVChannelLayout, AVFrame,
+    AVHWDeviceType, AVHWFramesContext, AVPictureType, AVSampleFormat, AV_FRAME_CROP_UNALIGNED,
+    AV_NOPTS_VALUE,
+};
+#[cfg(feature = "avutil_version_greater_than_58_22")]
+use ffmpeg_sys_the_third::{AV_FRAME_FLAG_INTERLACED, AV_FRAME_FLAG_KEY};
+use std::io::Write;
+use std::mem::transmute;
+use std::slice;
+
+/// Write the raw frame data to `writer`, returning the number of bytes written
+///
+/// Video planes are written sequentially without line padding (eg. raw YUV),
+/// audio is written as planar/packed samples in the frame's sample format (eg. raw PCM)
+pub unsafe fn write_frame_raw<W: Write>(frame: *const AVFrame, writer: &mut W) -> Result<usize> {
+    if !(*frame).hw_frames_ctx.is_null() {
+        bail!("Hardware frames must be downloaded before writing");
+    }
+    let mut written = 0;
+    if (*frame).width > 0 && (*frame).height > 0 {
+        let format = transmute((*frame).format);
+        let desc = av_pix_fmt_desc_get(format);
+        if desc.is_null() {
+            bail!("Unknown pixel format {}", (*frame).format);
+        }
+        let planes = av_pix_fmt_count_planes(format);
+        if planes < 0 {
+            bail!("Invalid pixel format {}", (*frame).format);
+        }
+        for plane in 0..planes as usize {
+            let line_size = av_image_get_linesize(format, (*frame).width, plane as libc::c_int);
+            if line_size < 0 {
+                bail!("Invalid line size for plane {}", plane);
+            }
+            // chroma planes are subsampled
+            let height = if plane == 1 || plane == 2 {
+                -((-(*frame).height) >> (*desc).log2_chroma_h)
+            } else {
+                (*frame).height
+            };
+            for y in 0..height as isize {
+                let line = (*frame).data[plane].offset(y * (*frame).linesize[plane] as isize);
+                writer.write_all(slice::from_raw_parts(line, line_size as usize))?;
+                written += line_size as usize;
+            }
+        }
+    } else if (*frame).nb_samples > 0 {
+        let format = transmute((*frame).format);
+        let bps = av_get_bytes_per_sample(format) as usize;
+        let channels = (*frame).ch_layout.nb_channels as usize;
+        let samples = (*frame).nb_samples as usize;
+        if av_sample_fmt_is_planar(format) == 1 {
+            for ch in 0..channels {
+                let data = *(*frame).extended_data.add(ch);
+                writer.write_all(slice::from_raw_parts(data, samples * bps))?;
+                written += samples * bps;
+            }
+        } else {
+            let data = *(*frame).extended_data;
+            writer.write_all(slice::from_raw_parts(data, samples * bps * channels))?;
+            written += samples * bps * channels;
+        }
+    } else {
+        bail!("Frame has no video or audio data");
+    }
+    Ok(written)
+}
+
+/// Get the peak level (0.0-1.0) of each channel of an audio frame
+///
+/// Supports S16, S32, FLT and DBL sample formats (planar or packed)
+pub unsafe fn get_audio_levels(frame: *const AVFrame) -> Result<Vec<f32>> {
+    let format: AVSampleFormat = transmute((*frame).format);
+    let channels = (*frame).ch_layout.nb_channels.max(0) as usize;
+    let samples = (*frame).nb_samples.max(0) as usize;
+    let planar = av_sample_fmt_is_planar(format) == 1;
+    let packed = av_get_packed_sample_fmt(format);
+    if !matches!(
+        packed,
+        AVSampleFormat::AV_SAMPLE_FMT_S16
+            | AVSampleFormat::AV_SAMPLE_FMT_S32
+            | AVSampleFormat::AV_SAMPLE_FMT_FLT
+            | AVSampleFormat::AV_SAMPLE_FMT_DBL
+    ) {
+        bail!("Unsupported sample format {}", (*frame).format);
+    }
+
+    let mut peaks = vec![0f32; channels];
+    for (ch, peak) in peaks.iter_mut().enumerate() {
+        for i in 0..samples {
+            let (plane, idx) = if planar {
+                (ch, i)
+            } else {
+                (0, i * channels + ch)
+            };
+            let data = *(*frame).extended_data.add(plane);
+            let v = match packed {
+                AVSampleFormat::AV_SAMPLE_FMT_S16 => {
+                    *(data as *const i16).add(idx) as f32 / 32768.0
+                }
+                AVSampleFormat::AV_SAMPLE_FMT_S32 => {
+                    *(data as *const i32).add(idx) as f32 / 2147483648.0
+                }
+                AVSampleFormat::AV_SAMPLE_FMT_FLT => *(data as *const f32).add(idx),
+                _ => *(data as *const f64).add(idx) as f32,
+            };
+            *peak = peak.max(v.abs());
+        }
+    }
+    Ok(peaks)
+}
+
+/// Check if the frame is a keyframe
+pub unsafe fn frame_is_keyframe(frame: *const AVFrame) -> bool {
+    #[cfg(feature = "avutil_version_greater_than_58_22")]
+    return (*frame).flags & AV_FRAME_FLAG_KEY as libc::c_int != 0;
+    #[cfg(not(feature = "avutil_version_greater_than_58_22"))]
+    return (*frame).key_frame != 0;
+}
+
+/// Check if the video frame is interlaced
+pub unsafe fn frame_is_interlaced(frame: *const AVFrame) -> bool {
+    #[cfg(feature = "avutil_version_greater_than_58_22")]
+    return (*frame).flags & AV_FRAME_FLAG_INTERLACED as libc::c_int != 0;
+    #[cfg(not(feature = "avutil_version_greater_than_58_22"))]
+    return (*frame).interlaced_frame != 0;
+}
+
+/// Check if the frame data is stored on a hardware device
+pub unsafe fn frame_is_hardware(frame: *const AVFrame) -> bool {
+    !(*frame).hw_frames_ctx.is_null()
+}
+
+/// Get the device type of a hardware frame, [None] for software frames
+pub unsafe fn frame_hw_device_type(frame: *const AVFrame) -> Option<AVHWDeviceType> {
+    if !frame_is_hardware(frame) {
+        return None;
+    }
+    let frames_ctx = (*(*frame).hw_frames_ctx).data as *const AVHWFramesContext;
+    if frames_ctx.is_null() || (*frames_ctx).device_ctx.is_null() {
+        return None;
+    }
+    Some((*(*frames_ctx).device_ctx).type_)
+}
+
+/// Get the picture type of a video frame (I/P/B etc.)
+pub unsafe fn frame_picture_type(frame: *const AVFrame) -> AVPictureType {
+    (*frame).pict_type
+}
+
+/// Get the decoder's best effort timestamp of the frame, falling back to the pts
+///
+/// Returns [None] if the frame has no timestamp
+pub unsafe fn frame_best_effort_ts(frame: *const AVFrame) -> Option<i64> {
+    if (*frame).best_effort_timestamp != AV_NOPTS_VALUE {
+        Some((*frame).best_effort_timestamp)
+    } else if (*frame).pts != AV_NOPTS_VALUE {
+        Some((*frame).pts)
+    } else {
+        None
+    }
+}
+
+/// Get the number of audio samples (per channel) in the frame, 0 for video frames
+pub unsafe fn frame_nb_samples(frame: *const AVFrame) -> usize {
+    (*frame).nb_samples.max(0) as usize
+}
+
+/// Get the frame duration in the frame time base, 0 if unknown (see [get_frame_duration])
+pub unsafe fn frame_duration(frame: *const AVFrame) -> i64 {
+    get_frame_duration(frame as *mut AVFrame)
+}
+
+/// Allocate an audio frame of `nb_samples` of silence
+pub unsafe fn audio_silence(
+    nb_samples: i32,
+    format: AVSampleFormat,
+    layout: *const AVChannelLayout,
+    sample_rate: i32,
+) -> Result<*mut AVFrame> {
+    let mut frame = av_frame_alloc();
+    if frame.is_null() {
+        bail!("Failed to allocate frame");
+    }
+    (*frame).nb_samples = nb_samples;
+    (*frame).format = format as libc::c_int;
+    (*frame).sample_rate = sample_rate;
+    let ret = av_channel_layout_copy(&mut (*frame).ch_layout, layout);
+    bail_ffmpeg!(ret, {
+        av_frame_free(&mut frame);
+    });
+    let ret = av_frame_get_buffer(frame, 0);
+    bail_ffmpeg!(ret, "Failed to allocate audio buffer", {
+        av_frame_free(&mut frame);
+    });
+    // silence is not zero for unsigned formats
+    av_samples_set_silence(
+        (*frame).extended_data,
+        0,
+        nb_samples,
+        (*frame).ch_layout.nb_channels,
+        format,
+    );
+    Ok(frame)
+}
+
+/// Concatenate the samples of two audio frames with the same format, channel layout
+/// and sample rate into a new frame
+///
+/// The input frames are not consumed, the timestamp is copied from `a`
+pub unsafe fn append_audio(a: *const AVFrame, b: *const AVFrame) -> Result<*mut AVFrame> {
+    if (*a).format != (*b).format
+        || (*a).sample_rate != (*b).sample_rate
+        || av_channel_layout_compare(&(*a).ch_layout, &(*b).ch_layout) != 0
+    {
+        bail!("Audio frames are not compatible");
+    }
+    let format: AVSampleFormat = transmute((*a).format);
+    let channels = (*a).ch_layout.nb_channels;
+    let out = audio_silence(
+        (*a).nb_samples + (*b).nb_samples,
+        format,
+        &(*a).ch_layout,
+        (*a).sample_rate,
+    )?;
+    (*out).pts = (*a).pts;
+    (*out).time_base = (*a).time_base;
+    av_samples_copy(
+        (*out).extended_data,
+        (*a).extended_data as _,
+        0,
+        0,
+        (*a).nb_samples,
+        channels,
+        format,
+    );
+    av_samples_copy(
+        (*out).extended_data,
+        (*b).extended_data as _,
+        (*a).nb_samples,
+        0,
+        (*b).nb_samples,
+        channels,
+        format,
+    );
+    Ok(out)
+}
+
+/// Make sure the frame data is not shared with other frames, copying it if needed
+pub unsafe fn frame_make_writable(frame: *mut AVFrame) -> Result<()> {
+    let ret = av_frame_make_writable(frame);
+    bail_ffmpeg!(ret, "Failed to make frame writable");
+    Ok(())
+}
+
+/// Trim the frame to its picture area using the `crop_*` fields, eg. for hardware decoders
+/// which output padded frames
+///
+/// Only the data pointers and dimensions are changed, no data is copied
+pub unsafe fn frame_apply_cropping(frame: *mut AVFrame) -> Result<()> {
+    let ret = av_frame_apply_cropping(frame, AV_FRAME_CROP_UNALIGNED as libc::c_int);
+    bail_ffmpeg!(ret, "Failed to apply frame cropping");
+    Ok(())
+}
+
+/// Size in bytes of `lines` lines of `linesize` bytes, failing instead of overflowing
+/// on 32-bit targets or very large frames
+pub(crate) fn plane_size(linesize: libc::c_int, lines: libc::c_int) -> Result<usize> {
+    if linesize < 0 || lines < 0 {
+        bail!("Invalid plane size {}x{}", linesize, lines);
+    }
+    match (linesize as usize).checked_mul(lines as usize) {
+        Some(size) if size <= isize::MAX as usize => Ok(size),
+        _ => bail!("Plane size {}x{} is too large", linesize, lines),
+    }
+}
+
+/// Size in bytes of a data plane including line padding
+unsafe fn frame_plane_size(frame: *const AVFrame, plane: usize) -> Result<usize> {
+    if !(*frame).hw_frames_ctx.is_null() {
+        bail!("Hardware frames have no accessible planes");
+    }
+    if (*frame).width > 0 && (*frame).height > 0 {
+        let format = transmute((*frame).format);
+        let desc = av_pix_fmt_desc_get(format);
+        if desc.is_null() {
+            bail!("Unknown pixel format {}", (*frame).format);
+        }
+        if plane >= av_pix_fmt_count_planes(format).max(0) as usize {
+            bail!("Plane {} does not exist", plane);
+        }
+        if (*frame).linesize[plane] < 0 {
+            bail!("Negative line size is not supported");
+        }
+        // chroma planes are subsampled
+        let height = if plane == 1 || plane == 2 {
+            -((-(*frame).height) >> (*desc).log2_chroma_h)
+        } else {
+            (*frame).height
+        };
+        plane_size((*frame).linesize[plane], height)
+    } else if (*frame).nb_samples > 0 {
+        let planes = if av_sample_fmt_is_planar(transmute((*frame).format)) == 1 {
+            (*frame).ch_layout.nb_channels.max(0) as usize
+        } else {
+            1
+        };
+        if plane >= planes {
+            bail!("Plane {} does not exist", plane);
+        }
+        // all audio planes have the same size
+        Ok((*frame).linesize[0] as usize)
+    } else {
+        bail!("Frame has no video or audio data");
+    }
+}
+
+/// Get a data plane of the frame including line padding
+pub unsafe fn frame_plane<'a>(frame: *const AVFrame, plane: usize) -> Result<&'a [u8]> {
+    let size = frame_plane_size(frame, plane)?;
+    let data = *(*frame).extended_data.add(plane);
+    if data.is_null() {
+        bail!("Frame plane {} has no data", plane);
+    }
+    Ok(slice::from_raw_parts(data, size))
+}
+
+/// Get a mutable data plane of the frame including line padding
+///
+/// The frame is made writable first so shared frame buffers are not modified
+pub unsafe fn frame_plane_mut<'a>(frame: *mut AVFrame, plane: usize) -> Result<&'a mut [u8]> {
+    let size = frame_plane_size(frame, plane)?;
+    frame_make_writable(frame)?;
+    let data = *(*frame).extended_data.add(plane);
+    if data.is_null() {
+        bail!("Frame plane {} has no data", plane);
+    }
+    Ok(slice::from_raw_parts_mut(data, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_test_frame, Scaler};
+    use ffmpeg_sys_the_third::{av_channel_layout_default, av_frame_clone, AVPixelFormat};
+
+    #[test]
+    fn write_raw_yuv420p() -> Result<()> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut scaler = Scaler::new();
+            let mut yuv = scaler.process_frame(
+                frame,
+                (*frame).width as u16,
+                (*frame).height as u16,
+                AVPixelFormat::AV_PIX_FMT_YUV420P,
+            )?;
+
+            let mut buf = Vec::new();
+            let written = write_frame_raw(yuv, &mut buf)?;
+            let expected = ((*yuv).width * (*yuv).height * 3 / 2) as usize;
+            assert_eq!(written, expected);
+            assert_eq!(buf.len(), expected);
+
+            av_frame_free(&mut yuv);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn audio_levels() -> Result<()> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_FLTP as libc::c_int;
+            (*frame).nb_samples = 1024;
+            av_channel_layout_default(&mut (*frame).ch_layout, 2);
+            av_frame_get_buffer(frame, 0);
+            for ch in 0..2 {
+                let data = (*frame).data[ch] as *mut f32;
+                for i in 0..1024 {
+                    *data.add(i) = if i % 2 == 0 { 0.5 } else { -0.25 };
+                }
+            }
+            assert_eq!(get_audio_levels(frame)?, vec![0.5, 0.5]);
+            av_frame_free(&mut frame);
+
+            // packed s16 sine
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_S16 as libc::c_int;
+            (*frame).nb_samples = 1024;
+            av_channel_layout_default(&mut (*frame).ch_layout, 1);
+            av_frame_get_buffer(frame, 0);
+            let data = (*frame).data[0] as *mut i16;
+            for i in 0..1024 {
+                let v = (i as f32 / 1024.0 * std::f32::consts::PI * 8.0).sin();
+                *data.add(i) = (v * 16384.0) as i16;
+            }
+            let levels = get_audio_levels(frame)?;
+            assert!((levels[0] - 0.5).abs() < 0.01);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mutable_planes() -> Result<()> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).width = 64;
+            (*frame).height = 64;
+            (*frame).format = AVPixelFormat::AV_PIX_FMT_YUV420P as libc::c_int;
+            av_frame_get_buffer(frame, 0);
+
+            let u = frame_plane_mut(frame, 1)?;
+            assert_eq!(u.len(), (*frame).linesize[1] as usize * 32);
+            u.fill(0x80);
+            assert!(frame_plane(frame, 1)?.iter().all(|b| *b == 0x80));
+            assert!(frame_plane(frame, 3).is_err());
+
+            // writing to a shared frame must not modify the original
+            let mut copy = av_frame_clone(frame);
+            frame_plane_mut(copy, 1)?.fill(0x10);
+            assert!(frame_plane(copy, 1)?.iter().all(|b| *b == 0x10));
+            assert!(frame_plane(frame, 1)?.iter().all(|b| *b == 0x80));
+
+            av_frame_free(&mut copy);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn silence_and_append() -> Result<()> {
+        unsafe {
+            let mut layout = std::mem::zeroed();
+            av_channel_layout_default(&mut layout, 2);
+            for format in [
+                AVSampleFormat::AV_SAMPLE_FMT_S16,
+                AVSampleFormat::AV_SAMPLE_FMT_FLTP,
+            ] {
+                let planar = format == AVSampleFormat::AV_SAMPLE_FMT_FLTP;
+                let mut a = audio_silence(1024, format, &layout, 48_000)?;
+                assert_eq!((*a).nb_samples, 1024);
+                // 1024 samples of 2 channels at 2 (s16) or 4 (fltp) bytes
+                let (planes, size) = if planar { (2, 4096) } else { (1, 4096) };
+                for plane in 0..planes {
+                    assert!(frame_plane(a, plane)?[..size].iter().all(|b| *b == 0));
+                }
+                assert_eq!(get_audio_levels(a)?, vec![0.0, 0.0]);
+
+                // append a loud frame after the silence
+                let mut b = audio_silence(512, format, &layout, 48_000)?;
+                if planar {
+                    for plane in 0..2 {
+                        let data = (*b).data[plane] as *mut f32;
+                        for i in 0..512 {
+                            *data.add(i) = 0.5;
+                        }
+                    }
+                } else {
+                    let data = (*b).data[0] as *mut i16;
+                    for i in 0..1024 {
+                        *data.add(i) = 16384;
+                    }
+                }
+                let mut out = append_audio(a, b)?;
+                assert_eq!((*out).nb_samples, 1536);
+                assert_eq!(get_audio_levels(out)?, vec![0.5, 0.5]);
+                // the first 1024 samples are still silent
+                (*out).nb_samples = 1024;
+                assert_eq!(get_audio_levels(out)?, vec![0.0, 0.0]);
+
+                av_frame_free(&mut out);
+                av_frame_free(&mut b);
+                av_frame_free(&mut a);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn software_frame_is_not_hardware() {
+        unsafe {
+            let mut frame = generate_test_frame();
+            assert!(!frame_is_hardware(frame));
+            assert_eq!(frame_hw_device_type(frame), None);
+            av_frame_free(&mut frame);
+        }
+    }
+
+    #[test]
+    fn plane_size_overflow() {
+        assert_eq!(plane_size(4096, 2160).unwrap(), 4096 * 2160);
+        assert!(plane_size(-1, 10).is_err());
+        assert!(plane_size(10, -1).is_err());
+        // does not fit in a 32-bit usize, but must never panic
+        let size = plane_size(libc::c_int::MAX, libc::c_int::MAX);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(
+            size.unwrap(),
+            libc::c_int::MAX as usize * libc::c_int::MAX as usize
+        );
+        #[cfg(target_pointer_width = "32")]
+        assert!(size.is_err());
+    }
+
+    #[test]
+    fn decoded_audio_accessors() -> Result<()> {
+        use crate::{Decoder, Demuxer, Encoder, Muxer};
+        use ffmpeg_sys_the_third::{av_packet_free, AVCodecID};
+
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            // ADTS has no priming info, so the decoder does not trim the first frame
+            let path = "test_output/test_frame_accessors.aac";
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                .with_sample_rate(48_000)?
+                .with_sample_format(AVSampleFormat::AV_SAMPLE_FMT_FLTP)
+                .with_default_channel_layout(2)
+                .open(None)?;
+            let frame_size = (*encoder.codec_context()).frame_size as usize;
+            let mut muxer = Muxer::builder()
+                .with_output_path(path, None)?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            let mut layout = std::mem::zeroed();
+            av_channel_layout_default(&mut layout, 2);
+            let mut frame = audio_silence(
+                frame_size as i32,
+                AVSampleFormat::AV_SAMPLE_FMT_FLTP,
+                &layout,
+                48_000,
+            )?;
+            for n in 0..10 {
+                (*frame).pts = n * frame_size as i64;
+                muxer.write_encoded(&mut encoder, frame)?;
+            }
+            muxer.write_encoded(&mut encoder, std::ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let audio = info.best_audio().expect("no audio stream");
+            let mut decoder = Decoder::new();
+            decoder.setup_decoder(audio, None)?;
+            let mut decoded = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                let frames = decoder.decode_pkt(pkt)?;
+                for mut f in frames {
+                    assert_eq!(frame_nb_samples(f), frame_size);
+                    assert!(frame_best_effort_ts(f).is_some());
+                    assert!(frame_duration(f) > 0);
+                    decoded += 1;
+                    av_frame_free(&mut f);
+                }
+                if pkt.is_null() {
+                    break;
+                }
+                av_packet_free(&mut pkt);
+            }
+            assert!(decoded > 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn apply_cropping() -> Result<()> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            (*frame).crop_top = 8;
+            (*frame).crop_bottom = 24;
+            (*frame).crop_left = 16;
+            (*frame).crop_right = 4;
+            frame_apply_cropping(frame)?;
+            assert_eq!((*frame).width, 1024 - 16 - 4);
+            assert_eq!((*frame).height, 1024 - 8 - 24);
+            assert_eq!((*frame).crop_top, 0);
+            assert_eq!((*frame).crop_left, 0);
+
+            // invalid crop is rejected
+            (*frame).crop_right = 2048;
+            assert!(frame_apply_cropping(frame).is_err());
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+}
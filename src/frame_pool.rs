@@ -0,0 +1,121 @@
+use crate::bail_ffmpeg;
+use anyhow::Result;
+use ffmpeg_sys_the_third::{
+    av_dict_free, av_frame_alloc, av_frame_free, av_frame_get_buffer, av_frame_is_writable,
+    av_frame_remove_side_data, AVFrame, AVPixelFormat,
+};
+
+/// Pool of video frames which are recycled instead of being freed
+///
+/// Frames taken from the pool must be returned with [FramePool::release]
+pub struct FramePool {
+    frames: Vec<*mut AVFrame>,
+    max_size: usize,
+}
+
+impl Drop for FramePool {
+    fn drop(&mut self) {
+        unsafe {
+            for frame in self.frames.iter_mut() {
+                av_frame_free(frame);
+            }
+        }
+    }
+}
+
+impl FramePool {
+    /// Create a new pool which keeps at most `max_size` unused frames
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            frames: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Number of unused frames in the pool
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Get a video frame with allocated buffers, re-using a pooled frame if one matches
+    pub unsafe fn get_video(
+        &mut self,
+        width: i32,
+        height: i32,
+        format: AVPixelFormat,
+    ) -> Result<*mut AVFrame> {
+        if let Some(pos) = self.frames.iter().position(|f| {
+            (**f).width == width
+                && (**f).height == height
+                && (**f).format == format as libc::c_int
+                && av_frame_is_writable(*f) != 0
+        }) {
+            return Ok(self.frames.swap_remove(pos));
+        }
+
+        let mut frame = av_frame_alloc();
+        (*frame).width = width;
+        (*frame).height = height;
+        (*frame).format = format as libc::c_int;
+        let ret = av_frame_get_buffer(frame, 0);
+        bail_ffmpeg!(ret, {
+            av_frame_free(&mut frame);
+        });
+        Ok(frame)
+    }
+
+    /// Return a frame to the pool, the frame is freed if the pool is full
+    pub unsafe fn release(&mut self, mut frame: *mut AVFrame) {
+        if frame.is_null() {
+            return;
+        }
+        if self.frames.len() >= self.max_size {
+            av_frame_free(&mut frame);
+            return;
+        }
+        // clear props which would otherwise accumulate when copied into again
+        while (*frame).nb_side_data > 0 {
+            av_frame_remove_side_data(frame, (**(*frame).side_data).type_);
+        }
+        av_dict_free(&mut (*frame).metadata);
+        self.frames.push(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_test_frame, Scaler};
+    use std::slice;
+
+    #[test]
+    fn scale_with_pool() -> Result<()> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut scaler = Scaler::new();
+            let mut pool = FramePool::new(4);
+
+            let fmt = AVPixelFormat::AV_PIX_FMT_YUV420P;
+            let mut reference = scaler.process_frame(frame, 128, 128, fmt)?;
+            let plane_size = ((*reference).linesize[0] * (*reference).height) as usize;
+            let ref_plane = slice::from_raw_parts((*reference).data[0], plane_size);
+
+            for _ in 0..1000 {
+                let out = scaler.process_frame_pooled(frame, 128, 128, fmt, &mut pool)?;
+                assert_eq!((*out).linesize[0], (*reference).linesize[0]);
+                let plane = slice::from_raw_parts((*out).data[0], plane_size);
+                assert_eq!(plane, ref_plane);
+                pool.release(out);
+            }
+            assert_eq!(pool.len(), 1);
+
+            av_frame_free(&mut reference);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+}
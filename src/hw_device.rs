@@ -0,0 +1,70 @@
+use crate::{bail_ffmpeg, rstr};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_buffer_ref, av_buffer_unref, av_hwdevice_ctx_create, av_hwdevice_get_type_name, AVBufferRef,
+    AVHWDeviceContext, AVHWDeviceType,
+};
+use std::ffi::CString;
+use std::ptr;
+
+/// A hardware device context (eg. VAAPI/CUDA) which can be shared between filters and codecs
+pub struct HwDevice {
+    ctx: *mut AVBufferRef,
+}
+
+impl Drop for HwDevice {
+    fn drop(&mut self) {
+        unsafe {
+            av_buffer_unref(&mut self.ctx);
+        }
+    }
+}
+
+impl HwDevice {
+    /// Open the default device of a given type
+    pub unsafe fn new(device_type: AVHWDeviceType) -> Result<Self> {
+        Self::open(device_type, None)
+    }
+
+    /// Open a specific device, eg. `/dev/dri/renderD128` for VAAPI
+    pub unsafe fn open(device_type: AVHWDeviceType, device: Option<&str>) -> Result<Self> {
+        let device = device.map(CString::new).transpose()?;
+        let mut ctx = ptr::null_mut();
+        let ret = av_hwdevice_ctx_create(
+            &mut ctx,
+            device_type,
+            device.as_ref().map_or(ptr::null(), |d| d.as_ptr()),
+            ptr::null_mut(),
+            0,
+        );
+        bail_ffmpeg!(ret, "Failed to create HW device");
+        if ctx.is_null() {
+            bail!("Failed to create HW device");
+        }
+        Ok(Self { ctx })
+    }
+
+    /// Get the device type
+    pub fn device_type(&self) -> AVHWDeviceType {
+        unsafe { (*((*self.ctx).data as *const AVHWDeviceContext)).type_ }
+    }
+
+    /// Get the name of the device type (eg. "vaapi")
+    pub fn type_name(&self) -> String {
+        unsafe { rstr!(av_hwdevice_get_type_name(self.device_type())).to_string() }
+    }
+
+    /// Get the device context buffer, the reference is still owned by [HwDevice]
+    pub fn as_ptr(&self) -> *mut AVBufferRef {
+        self.ctx
+    }
+
+    /// Create a new reference to the device context, which must be freed by the caller
+    pub unsafe fn new_ref(&self) -> Result<*mut AVBufferRef> {
+        let ret = av_buffer_ref(self.ctx);
+        if ret.is_null() {
+            bail!("Failed to reference HW device");
+        }
+        Ok(ret)
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{Encoder, Muxer, Scaler};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_frame_free, avcodec_find_best_pix_fmt_of_list, AVCodecID, AVFrame, AVPixelFormat,
+    AV_CODEC_FLAG_QSCALE, FF_QP2LAMBDA,
+};
+use std::collections::HashMap;
+use std::mem::transmute;
+use std::path::Path;
+use std::ptr;
+
+/// Options for [save_image]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImageOptions {
+    /// Quality 0-100, higher is better, the codec default is used when not set
+    pub quality: Option<u8>,
+    /// Encode without loss where the codec supports it (WebP/AVIF), PNG is always lossless
+    pub lossless: bool,
+}
+
+impl ImageOptions {
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = Some(quality.min(100));
+        self
+    }
+
+    pub fn with_lossless(mut self, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self
+    }
+}
+
+/// Encoder selected for an image file extension
+enum ImageCodec {
+    Png,
+    Jpeg,
+    WebP,
+    Avif,
+}
+
+impl ImageCodec {
+    fn from_path(path: &str) -> Result<Self> {
+        let ext = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        Ok(match ext.as_deref() {
+            Some("png") => Self::Png,
+            Some("jpg") | Some("jpeg") => Self::Jpeg,
+            Some("webp") => Self::WebP,
+            Some("avif") => Self::Avif,
+            _ => bail!("Unsupported image extension: {}", path),
+        })
+    }
+
+    fn encoder(&self) -> Result<Encoder> {
+        match self {
+            Self::Png => Encoder::new(AVCodecID::AV_CODEC_ID_PNG),
+            Self::Jpeg => Encoder::new(AVCodecID::AV_CODEC_ID_MJPEG),
+            Self::WebP => Encoder::new_with_name("libwebp"),
+            Self::Avif => Encoder::new_with_name("libaom-av1"),
+        }
+    }
+
+    /// Codec private options for the requested quality
+    fn options(&self, opt: &ImageOptions) -> HashMap<String, String> {
+        let mut ret = HashMap::new();
+        match self {
+            Self::WebP => {
+                if opt.lossless {
+                    ret.insert("lossless".to_string(), "1".to_string());
+                }
+                if let Some(q) = opt.quality {
+                    ret.insert("quality".to_string(), q.to_string());
+                }
+            }
+            Self::Avif => {
+                ret.insert("still-picture".to_string(), "1".to_string());
+                if opt.lossless {
+                    ret.insert("crf".to_string(), "0".to_string());
+                    ret.insert("aom-params".to_string(), "lossless=1".to_string());
+                } else if let Some(q) = opt.quality {
+                    // crf 0-63, lower is better
+                    let crf = (100 - q as u32) * 63 / 100;
+                    ret.insert("crf".to_string(), crf.to_string());
+                }
+            }
+            _ => {}
+        }
+        ret
+    }
+}
+
+/// Encode a single frame as a still image, the codec is picked from the file extension
+/// (`.png`, `.jpg`, `.webp`, `.avif`)
+///
+/// The frame is converted to a pixel format supported by the encoder if needed,
+/// the input frame is not consumed
+pub unsafe fn save_image(frame: *const AVFrame, path: &str, options: ImageOptions) -> Result<()> {
+    let codec = ImageCodec::from_path(path)?;
+    let mut encoder = codec
+        .encoder()?
+        .with_width((*frame).width)
+        .with_height((*frame).height);
+
+    let src_fmt: AVPixelFormat = transmute((*frame).format);
+    let mut fmts = encoder.supported_pix_fmts()?;
+    let dst_fmt = if fmts.is_empty() || fmts.contains(&src_fmt) {
+        src_fmt
+    } else {
+        fmts.push(AVPixelFormat::AV_PIX_FMT_NONE);
+        avcodec_find_best_pix_fmt_of_list(fmts.as_ptr(), src_fmt, 1, ptr::null_mut())
+    };
+    encoder = encoder.with_pix_fmt(dst_fmt);
+
+    if let (ImageCodec::Jpeg, Some(q)) = (&codec, options.quality) {
+        // qscale 2-31, lower is better
+        let qscale = 2 + (100 - q as i32) * 29 / 100;
+        encoder = encoder.with_options(|ctx| {
+            (*ctx).flags |= AV_CODEC_FLAG_QSCALE as libc::c_int;
+            (*ctx).global_quality = qscale * FF_QP2LAMBDA as libc::c_int;
+        });
+    }
+    let mut encoder = encoder.open(Some(codec.options(&options)))?;
+
+    let mut converted = if dst_fmt != src_fmt {
+        Scaler::new().process_frame(
+            frame,
+            (*frame).width as u16,
+            (*frame).height as u16,
+            dst_fmt,
+        )?
+    } else {
+        ptr::null_mut()
+    };
+    let src = if converted.is_null() {
+        frame as *mut AVFrame
+    } else {
+        converted
+    };
+
+    let ret = match codec {
+        // AVIF needs the ISOBMFF container, the other codecs produce a complete file
+        ImageCodec::Avif => write_avif(&mut encoder, src, path),
+        _ => encoder.save_picture(src, path),
+    };
+    if !converted.is_null() {
+        av_frame_free(&mut converted);
+    }
+    ret
+}
+
+unsafe fn write_avif(encoder: &mut Encoder, frame: *mut AVFrame, path: &str) -> Result<()> {
+    let mut muxer = Muxer::builder()
+        .with_output_path(path, Some("avif"))?
+        .with_stream_encoder(encoder)?
+        .build()?;
+    muxer.open(None)?;
+    muxer.write_encoded(encoder, frame)?;
+    muxer.write_encoded(encoder, ptr::null_mut())?;
+    muxer.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate_test_frame, Demuxer};
+
+    #[test]
+    fn save_webp() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test.webp";
+            let mut frame = generate_test_frame();
+            save_image(frame, path, ImageOptions::default().with_quality(80))?;
+            av_frame_free(&mut frame);
+
+            let data = std::fs::read(path)?;
+            assert!(data.len() > 12);
+            assert_eq!(&data[0..4], b"RIFF");
+            assert_eq!(&data[8..12], b"WEBP");
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let video = info.best_video().expect("no video stream");
+            assert_eq!(video.codec, AVCodecID::AV_CODEC_ID_WEBP as isize);
+            assert_eq!(video.width, 1024);
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,36 @@
 use anyhow::Error;
 use ffmpeg_sys_the_third::{
-    av_dict_set, av_frame_alloc, av_frame_copy_props, av_frame_free, av_hwframe_transfer_data,
-    av_make_error_string, av_opt_next, av_opt_set, AVDictionary, AVFrame, AVOption,
-    AV_OPT_SEARCH_CHILDREN,
+    av_dict_free, av_dict_set, av_frame_alloc, av_frame_copy, av_frame_copy_props, av_frame_free,
+    av_frame_get_buffer, av_frame_is_writable, av_frame_remove_side_data, av_frame_unref,
+    av_hwframe_transfer_data, av_make_error_string, av_opt_next, av_opt_set, AVDictionary, AVFrame,
+    AVHWFramesContext, AVOption, AV_OPT_SEARCH_CHILDREN,
 };
 use std::collections::HashMap;
 use std::ptr;
 
 mod audio_fifo;
+mod channel_layout;
+mod concat;
 mod decode;
+mod deinterlace;
 mod demux;
 mod encode;
+mod encode_worker;
+mod error;
 mod filter;
+mod fps;
+mod frame;
+mod frame_pool;
+mod hw_device;
+mod image;
 mod mux;
+mod packet;
+mod raw;
 mod resample;
 mod scale;
 mod stream_info;
+mod subtitle;
+mod thumbnail;
 mod transcode;
 
 #[cfg(not(feature = "avcodec_version_greater_than_59_24"))]
@@ -27,24 +42,28 @@ compile_error!("avutil version too old, <57.24");
 macro_rules! bail_ffmpeg {
     ($x:expr) => {
         if $x < 0 {
-            anyhow::bail!($crate::get_ffmpeg_error_msg($x))
+            return Err(anyhow::Error::new($crate::FfmpegError::from_ret($x)));
         }
     };
     ($x:expr,$clean:block) => {
         if $x < 0 {
             $clean;
-            anyhow::bail!($crate::get_ffmpeg_error_msg($x))
+            return Err(anyhow::Error::new($crate::FfmpegError::from_ret($x)));
         }
     };
     ($x:expr,$msg:expr) => {
         if $x < 0 {
-            anyhow::bail!(format!("{}: {}", $msg, $crate::get_ffmpeg_error_msg($x)))
+            let err = $crate::FfmpegError::from_ret($x);
+            let msg = format!("{}: {}", $msg, err);
+            return Err(anyhow::Error::new(err).context(msg));
         }
     };
     ($x:expr,$msg:expr,$clean:block) => {
         if $x < 0 {
             $clean;
-            anyhow::bail!(format!("{}: {}", $msg, $crate::get_ffmpeg_error_msg($x)))
+            let err = $crate::FfmpegError::from_ret($x);
+            let msg = format!("{}: {}", $msg, err);
+            return Err(anyhow::Error::new(err).context(msg));
         }
     };
 }
@@ -161,6 +180,11 @@ pub fn format_time(secs: f32) -> String {
     }
 }
 
+/// Estimate the output size in bytes of a stream with a given bitrate (bits/s) and duration
+pub fn estimate_output_size(bitrate: i64, duration_secs: f32) -> u64 {
+    (bitrate.max(0) as f64 * duration_secs.max(0.0) as f64 / 8.0) as u64
+}
+
 fn list_opts(ctx: *mut libc::c_void) -> Result<Vec<String>, Error> {
     let mut opt_ptr: *const AVOption = ptr::null_mut();
 
@@ -193,18 +217,61 @@ pub unsafe fn get_frame_from_hw(mut frame: *mut AVFrame) -> Result<*mut AVFrame,
     if (*frame).hw_frames_ctx.is_null() {
         Ok(frame)
     } else {
-        let new_frame = av_frame_alloc();
+        let mut new_frame = av_frame_alloc();
         let ret = av_hwframe_transfer_data(new_frame, frame, 0);
-        bail_ffmpeg!(ret);
+        bail_ffmpeg!(ret, {
+            av_frame_free(&mut new_frame);
+        });
         av_frame_copy_props(new_frame, frame);
         av_frame_free(&mut frame);
         Ok(new_frame)
     }
 }
 
+/// Download a hardware frame into a preallocated CPU frame `dst`
+///
+/// The buffers of `dst` are reused when the size/format match and they are writable,
+/// otherwise new buffers are allocated. Software frames are copied into `dst`
+pub unsafe fn download_hw_into(src: *const AVFrame, dst: *mut AVFrame) -> Result<(), Error> {
+    let format = if (*src).hw_frames_ctx.is_null() {
+        (*src).format
+    } else {
+        let frames_ctx = (*(*src).hw_frames_ctx).data as *const AVHWFramesContext;
+        (*frames_ctx).sw_format as libc::c_int
+    };
+    let reuse = !(*dst).buf[0].is_null()
+        && (*dst).width == (*src).width
+        && (*dst).height == (*src).height
+        && (*dst).format == format
+        && av_frame_is_writable(dst) != 0;
+    if !reuse {
+        av_frame_unref(dst);
+        (*dst).width = (*src).width;
+        (*dst).height = (*src).height;
+        (*dst).format = format;
+        let ret = av_frame_get_buffer(dst, 0);
+        bail_ffmpeg!(ret, "Failed to allocate frame");
+    } else {
+        // remove the properties of the previous frame before copying the new ones
+        while (*dst).nb_side_data > 0 {
+            av_frame_remove_side_data(dst, (*(*(*dst).side_data)).type_);
+        }
+        av_dict_free(&mut (*dst).metadata);
+    }
+    let ret = if (*src).hw_frames_ctx.is_null() {
+        av_frame_copy(dst, src)
+    } else {
+        av_hwframe_transfer_data(dst, src, 0)
+    };
+    bail_ffmpeg!(ret, "Failed to download frame");
+    let ret = av_frame_copy_props(dst, src);
+    bail_ffmpeg!(ret);
+    Ok(())
+}
+
 #[cfg(test)]
 pub unsafe fn generate_test_frame() -> *mut AVFrame {
-    use ffmpeg_sys_the_third::{av_frame_get_buffer, AVPixelFormat};
+    use ffmpeg_sys_the_third::AVPixelFormat;
     use std::mem::transmute;
 
     let frame = av_frame_alloc();
@@ -213,34 +280,103 @@ pub unsafe fn generate_test_frame() -> *mut AVFrame {
     (*frame).format = transmute(AVPixelFormat::AV_PIX_FMT_RGB24);
     av_frame_get_buffer(frame, 0);
 
-    let mut lx = 0;
-    for line in 0..(*frame).height {
-        let c = lx % 3;
-        for y in 0..(*frame).width as usize {
-            let ptr = (*frame).data[0];
-            let offset = (line * (*frame).linesize[0]) as usize + (y * 3);
-            match c {
-                0 => *ptr.add(offset) = 0xff,
-                1 => *ptr.add(offset + 1) = 0xff,
-                2 => *ptr.add(offset + 2) = 0xff,
-                _ => {}
-            }
+    let stride = (*frame).linesize[0] as usize;
+    let width = (*frame).width as usize;
+    let data = frame_plane_mut(frame, 0).expect("test frame has no data");
+    for (line, row) in data.chunks_mut(stride).enumerate() {
+        // each line is a solid red, green or blue
+        let c = line % 3;
+        for px in row[..width * 3].chunks_mut(3) {
+            px[c] = 0xff;
         }
-        lx += 1;
     }
 
     frame
 }
 
+/// Unopened H.264 encoder for `width`x`height` YUV420P frames at 30fps
+#[cfg(test)]
+pub unsafe fn test_encoder(width: u16, height: u16) -> Result<Encoder, Error> {
+    use ffmpeg_sys_the_third::AVCodecID::AV_CODEC_ID_H264;
+    use ffmpeg_sys_the_third::AVPixelFormat::AV_PIX_FMT_YUV420P;
+
+    Encoder::new(AV_CODEC_ID_H264)?
+        .with_width(width as _)
+        .with_height(height as _)
+        .with_pix_fmt(AV_PIX_FMT_YUV420P)
+        .with_bitrate(1_000_000)
+        .with_framerate(30.0)
+}
+
+/// [generate_test_frame] scaled to `width`x`height` YUV420P with a matching [test_encoder]
+#[cfg(test)]
+pub unsafe fn setup_test_encoder(
+    width: u16,
+    height: u16,
+) -> Result<(*mut AVFrame, Encoder), Error> {
+    use ffmpeg_sys_the_third::AVPixelFormat::AV_PIX_FMT_YUV420P;
+
+    std::fs::create_dir_all("test_output")?;
+    let mut src_frame = generate_test_frame();
+    let frame = Scaler::new().process_frame(src_frame, width, height, AV_PIX_FMT_YUV420P);
+    av_frame_free(&mut src_frame);
+    Ok((frame?, test_encoder(width, height)?))
+}
+
+/// Encode a short H.264 test video to `path` with keyframes every `gop` frames
+#[cfg(test)]
+pub unsafe fn generate_test_video(path: &str, frames: i64, gop: i32) -> Result<(), Error> {
+    use ffmpeg_sys_the_third::av_packet_free;
+
+    let (mut frame, encoder) = setup_test_encoder(1024, 1024)?;
+    let mut encoder = encoder
+        .with_options(|ctx| (*ctx).gop_size = gop)
+        .open(None)?;
+
+    let mut muxer = Muxer::builder()
+        .with_output_path(path, None)?
+        .with_stream_encoder(&encoder)?
+        .build()?;
+    muxer.open(None)?;
+    for pts in 0..frames {
+        (*frame).pts = pts;
+        for mut pkt in encoder.encode_frame(frame)? {
+            muxer.write_packet(pkt)?;
+            av_packet_free(&mut pkt);
+        }
+    }
+    for mut pkt in encoder.encode_frame(ptr::null_mut())? {
+        muxer.write_packet(pkt)?;
+        av_packet_free(&mut pkt);
+    }
+    muxer.close()?;
+    av_frame_free(&mut frame);
+    Ok(())
+}
+
 pub use audio_fifo::*;
+pub use channel_layout::*;
+pub use concat::*;
 pub use decode::*;
+pub use deinterlace::*;
 pub use demux::*;
 pub use encode::*;
+pub use encode_worker::*;
+pub use error::*;
 pub use ffmpeg_sys_the_third;
 pub use filter::*;
+pub use fps::*;
+pub use frame::*;
+pub use frame_pool::*;
+pub use hw_device::*;
+pub use image::*;
 use log::log;
 pub use mux::*;
+pub use packet::*;
+pub use raw::*;
 pub use resample::*;
 pub use scale::*;
 pub use stream_info::*;
+pub use subtitle::*;
+pub use thumbnail::*;
 pub use transcode::*;
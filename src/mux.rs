@@ -1,16 +1,27 @@
-use crate::{bail_ffmpeg, cstr, set_opts, Encoder, AVIO_BUFFER_SIZE};
+use crate::{bail_ffmpeg, cstr, rstr, set_opts, Encoder, AVIO_BUFFER_SIZE};
 use anyhow::{bail, Result};
+#[cfg(feature = "avformat_version_greater_than_60_19")]
+use ffmpeg_sys_the_third::av_packet_side_data_new;
+#[cfg(not(feature = "avformat_version_greater_than_60_19"))]
+use ffmpeg_sys_the_third::av_stream_new_side_data;
+use ffmpeg_sys_the_third::AVPacketSideDataType::AV_PKT_DATA_DISPLAYMATRIX;
 use ffmpeg_sys_the_third::{
-    av_free, av_interleaved_write_frame, av_mallocz, av_packet_rescale_ts, av_write_trailer,
+    av_free, av_interleaved_write_frame, av_mallocz, av_new_packet, av_packet_alloc,
+    av_packet_free, av_packet_rescale_ts, av_write_trailer, avcodec_get_name,
     avcodec_parameters_copy, avcodec_parameters_from_context, avformat_alloc_output_context2,
-    avformat_free_context, avformat_new_stream, avformat_write_header, avio_alloc_context,
-    avio_close, avio_context_free, avio_open, AVFormatContext, AVIOContext, AVPacket, AVStream,
-    AVERROR_EOF, AVFMT_GLOBALHEADER, AVFMT_NOFILE, AVIO_FLAG_DIRECT, AVIO_FLAG_WRITE,
-    AV_CODEC_FLAG_GLOBAL_HEADER,
+    avformat_free_context, avformat_new_stream, avformat_query_codec, avformat_write_header,
+    avio_alloc_context, avio_close, avio_closep, avio_context_free, avio_open, AVCodecContext,
+    AVCodecID, AVFormatContext, AVFrame, AVIOContext, AVMediaType, AVPacket, AVRational, AVStream,
+    AVERROR, AVERROR_EOF, AVFMT_GLOBALHEADER, AVFMT_NOFILE, AVIO_FLAG_DIRECT, AVIO_FLAG_WRITE,
+    AV_CODEC_FLAG_GLOBAL_HEADER, AV_NOPTS_VALUE, AV_PKT_FLAG_KEY, FF_COMPLIANCE_NORMAL,
 };
+use log::warn;
 use slimbox::{slimbox_unsize, SlimBox, SlimMut};
 use std::collections::HashMap;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use std::{ptr, slice};
 
 #[cfg(feature = "ff_api_avio_write_nonconst")]
@@ -18,6 +29,15 @@ type WriteDataPtr = *mut u8;
 #[cfg(not(feature = "ff_api_avio_write_nonconst"))]
 type WriteDataPtr = *const u8;
 
+/// How long a writer returning [ErrorKind::WouldBlock] is waited on before the write fails
+const WRITE_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time base of [Muxer::write_timed_metadata] timestamps
+const TIMED_METADATA_TB: AVRational = AVRational {
+    num: 1,
+    den: 90_000,
+};
+
 unsafe extern "C" fn write_data<T>(
     opaque: *mut libc::c_void,
     buffer: WriteDataPtr,
@@ -27,14 +47,37 @@ where
     T: Write + 'static + ?Sized,
 {
     let mut writer: SlimMut<'_, T> = SlimMut::from_raw(opaque);
-    let data = slice::from_raw_parts(buffer, size as usize);
-    match writer.write_all(data) {
-        Ok(_) => size,
-        Err(e) => {
-            eprintln!("write_data {}", e);
-            AVERROR_EOF
+    let mut data = slice::from_raw_parts(buffer, size as usize);
+    let mut blocked_since = None;
+    while !data.is_empty() {
+        match writer.write(data) {
+            Ok(0) => {
+                warn!("write_data: writer accepted no data");
+                return AVERROR_EOF;
+            }
+            Ok(n) => {
+                data = &data[n..];
+                blocked_since = None;
+            }
+            // AVIO does not retry failed writes, so non-blocking writers are waited on
+            // for a while, a stalled writer fails the write and the error is returned
+            // from the next muxer write
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                let since = *blocked_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= WRITE_BLOCK_TIMEOUT {
+                    warn!("write_data: writer blocked for {:?}", WRITE_BLOCK_TIMEOUT);
+                    return AVERROR(libc::EAGAIN);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                warn!("write_data {}", e);
+                return AVERROR_EOF;
+            }
         }
     }
+    size
 }
 
 unsafe extern "C" fn seek_data(opaque: *mut libc::c_void, offset: i64, whence: libc::c_int) -> i64 {
@@ -52,6 +95,37 @@ pub struct Muxer {
     output: MuxerOutput,
     url: Option<String>,
     format: Option<String>,
+    /// Output stream index by encoder context
+    encoder_streams: HashMap<*const AVCodecContext, i32>,
+    /// Pace packet writes to a target bitrate
+    rate_limit: Option<RateLimit>,
+    /// Fill missing packet timestamps from the previous packet
+    derive_timestamps: bool,
+    /// Expected DTS of the next packet by output stream index (stream time base)
+    next_dts: HashMap<i32, i64>,
+}
+
+/// Wall-clock pacing of written packets to a target bitrate
+struct RateLimit {
+    /// Target bitrate (bits/s)
+    bitrate: u64,
+    /// Time of the first packet written
+    start: Option<Instant>,
+    /// Total bytes written
+    bytes: u64,
+}
+
+impl RateLimit {
+    /// Record `size` bytes written and sleep until the target bitrate is met
+    fn wait(&mut self, size: u64) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.bytes += size;
+        let target = Duration::from_secs_f64(self.bytes as f64 * 8.0 / self.bitrate as f64);
+        let elapsed = start.elapsed();
+        if target > elapsed {
+            std::thread::sleep(target - elapsed);
+        }
+    }
 }
 
 pub trait WriteSeek: Seek + Write {}
@@ -61,6 +135,11 @@ pub enum MuxerOutput {
     Url(String),
     WriterSeeker(Option<SlimBox<dyn WriteSeek + 'static>>),
     Writer(Option<SlimBox<dyn Write + 'static>>),
+    /// Mux to a temporary file which is copied to the writer on [Muxer::close]
+    Faststart {
+        path: PathBuf,
+        writer: Option<Box<dyn Write + 'static>>,
+    },
 }
 
 impl TryInto<*mut AVIOContext> for &mut MuxerOutput {
@@ -103,7 +182,7 @@ impl TryInto<*mut AVIOContext> for &mut MuxerOutput {
                     }
                     pb
                 }
-                MuxerOutput::Url(_) => ptr::null_mut(),
+                MuxerOutput::Url(_) | MuxerOutput::Faststart { .. } => ptr::null_mut(),
             })
         }
     }
@@ -114,6 +193,9 @@ pub struct MuxerBuilder {
     output: MuxerOutput,
     url: Option<String>,
     format: Option<String>,
+    encoder_streams: HashMap<*const AVCodecContext, i32>,
+    rate_limit: Option<u64>,
+    derive_timestamps: bool,
 }
 
 impl Default for MuxerBuilder {
@@ -129,6 +211,9 @@ impl MuxerBuilder {
             output: MuxerOutput::Url(String::new()),
             url: None,
             format: None,
+            encoder_streams: HashMap::new(),
+            rate_limit: None,
+            derive_timestamps: false,
         }
     }
 
@@ -164,12 +249,17 @@ impl MuxerBuilder {
         Ok(())
     }
 
-    /// Open the muxer with a destination path
-    pub unsafe fn with_output_path<'a, T>(mut self, dst: T, format: Option<&'a str>) -> Result<Self>
+    /// Open the muxer with a destination path (or URL)
+    pub unsafe fn with_output_path<T>(mut self, dst: T, format: Option<&str>) -> Result<Self>
     where
-        T: Into<&'a str>,
+        T: AsRef<Path>,
     {
-        let path_str = dst.into();
+        let path = dst.as_ref();
+        let path_str = if let Some(s) = path.to_str() {
+            s
+        } else {
+            bail!("Output path {} is not valid UTF-8", path.display());
+        };
         Self::init_ctx(&mut self.ctx, Some(path_str), format)?;
         self.url = Some(path_str.to_string());
         self.output = MuxerOutput::Url(path_str.to_string());
@@ -202,9 +292,43 @@ impl MuxerBuilder {
         Ok(self)
     }
 
+    /// Create a muxer writing MP4/MOV with the moov atom at the start of the file (faststart)
+    /// to a non-seekable writer.
+    ///
+    /// The output is muxed to a temporary file first, which is then copied to the
+    /// writer when the muxer is closed.
+    pub unsafe fn with_output_write_faststart<W>(
+        mut self,
+        writer: W,
+        format: Option<&str>,
+    ) -> Result<Self>
+    where
+        W: Write + 'static,
+    {
+        static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let format = format.unwrap_or("mp4");
+        let path = std::env::temp_dir().join(format!(
+            "ffmpeg-rs-raw-{}-{}.{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+            format
+        ));
+        let path_str = path.to_str().expect("invalid temp path").to_string();
+        Self::init_ctx(&mut self.ctx, Some(&path_str), Some(format))?;
+        self.url = Some(path_str);
+        self.format = Some(format.to_string());
+        self.output = MuxerOutput::Faststart {
+            path,
+            writer: Some(Box::new(writer)),
+        };
+        Ok(self)
+    }
+
     /// Add a stream to the output using an existing encoder
-    pub unsafe fn with_stream_encoder(self, encoder: &Encoder) -> Result<Self> {
-        Self::add_stream_from_encoder(self.ctx, encoder)?;
+    pub unsafe fn with_stream_encoder(mut self, encoder: &Encoder) -> Result<Self> {
+        let stream = Self::add_stream_from_encoder(self.ctx, encoder)?;
+        self.encoder_streams
+            .insert(encoder.codec_context(), (*stream).index);
         Ok(self)
     }
 
@@ -223,6 +347,22 @@ impl MuxerBuilder {
         self
     }
 
+    /// Pace packet writes to `bitrate` (bits/s) using wall-clock time,
+    /// useful when pushing to network outputs which have no backpressure
+    pub fn with_rate_limit(mut self, bitrate: u64) -> Result<Self> {
+        if bitrate == 0 {
+            bail!("Rate limit must be greater than 0");
+        }
+        self.rate_limit = Some(bitrate);
+        Ok(self)
+    }
+
+    /// Fill missing DTS (and PTS) of packets from the previous packet's DTS + duration
+    pub fn with_derive_timestamps(mut self, derive: bool) -> Self {
+        self.derive_timestamps = derive;
+        self
+    }
+
     /// Build the muxer
     pub fn build(self) -> Result<Muxer> {
         if self.ctx.is_null() {
@@ -233,6 +373,14 @@ impl MuxerBuilder {
             output: self.output,
             url: self.url,
             format: self.format,
+            encoder_streams: self.encoder_streams,
+            rate_limit: self.rate_limit.map(|bitrate| RateLimit {
+                bitrate,
+                start: None,
+                bytes: 0,
+            }),
+            derive_timestamps: self.derive_timestamps,
+            next_dts: HashMap::new(),
         })
     }
 
@@ -243,6 +391,7 @@ impl MuxerBuilder {
         if ctx.is_null() {
             bail!("cannot add stream to null ctx");
         }
+        Self::check_encoder_supported(ctx, encoder)?;
         let stream = avformat_new_stream(ctx, encoder.codec());
         if stream.is_null() {
             bail!("unable to allocate stream");
@@ -254,10 +403,65 @@ impl MuxerBuilder {
         let encoder_ctx = encoder.codec_context();
         (*stream).sample_aspect_ratio = (*encoder_ctx).sample_aspect_ratio;
         (*stream).time_base = (*encoder_ctx).time_base;
+        if let Some(matrix) = encoder.display_matrix() {
+            Self::set_display_matrix(stream, matrix)?;
+        }
 
         Ok(stream)
     }
 
+    /// Add display matrix side data to the stream
+    unsafe fn set_display_matrix(stream: *mut AVStream, matrix: &[i32; 9]) -> Result<()> {
+        let size = std::mem::size_of_val(matrix);
+        #[cfg(feature = "avformat_version_greater_than_60_19")]
+        let data = {
+            let par = (*stream).codecpar;
+            let sd = av_packet_side_data_new(
+                &mut (*par).coded_side_data,
+                &mut (*par).nb_coded_side_data,
+                AV_PKT_DATA_DISPLAYMATRIX,
+                size,
+                0,
+            );
+            if sd.is_null() {
+                bail!("Failed to allocate display matrix");
+            }
+            (*sd).data
+        };
+        #[cfg(not(feature = "avformat_version_greater_than_60_19"))]
+        let data = av_stream_new_side_data(stream, AV_PKT_DATA_DISPLAYMATRIX, size as _);
+        if data.is_null() {
+            bail!("Failed to allocate display matrix");
+        }
+        ptr::copy_nonoverlapping(matrix.as_ptr() as *const u8, data, size);
+        Ok(())
+    }
+
+    /// Check that the encoder codec is supported by the output format, and that the
+    /// encoder pixel format is supported by the codec
+    unsafe fn check_encoder_supported(ctx: *mut AVFormatContext, encoder: &Encoder) -> Result<()> {
+        let codec = encoder.codec();
+        let encoder_ctx = encoder.codec_context();
+        if codec.is_null() || encoder_ctx.is_null() {
+            bail!("encoder is not initialized");
+        }
+        let oformat = (*ctx).oformat;
+        if !oformat.is_null()
+            && avformat_query_codec(oformat, (*codec).id, FF_COMPLIANCE_NORMAL as libc::c_int) == 0
+        {
+            bail!(
+                "Codec {} is not supported by output format {}",
+                rstr!((*codec).name),
+                rstr!((*oformat).name)
+            );
+        }
+
+        if (*codec).type_ == AVMediaType::AVMEDIA_TYPE_VIDEO {
+            encoder.check_pix_fmt()?;
+        }
+        Ok(())
+    }
+
     pub(crate) unsafe fn add_copy_stream(
         ctx: *mut AVFormatContext,
         in_stream: *mut AVStream,
@@ -273,6 +477,10 @@ impl MuxerBuilder {
         // copy params from input
         let ret = avcodec_parameters_copy((*stream).codecpar, (*in_stream).codecpar);
         bail_ffmpeg!(ret);
+        // the codec tag is specific to the input container
+        (*(*stream).codecpar).codec_tag = 0;
+        // a hint for the muxer, packets are rescaled from their own time base when written
+        (*stream).time_base = (*in_stream).time_base;
 
         Ok(stream)
     }
@@ -283,9 +491,88 @@ impl Muxer {
         MuxerBuilder::new()
     }
 
+    /// Check if the output format can carry `codec`
+    pub unsafe fn supports_codec(&self, codec: AVCodecID) -> bool {
+        if self.ctx.is_null() || (*self.ctx).oformat.is_null() {
+            return false;
+        }
+        avformat_query_codec(
+            (*self.ctx).oformat,
+            codec,
+            FF_COMPLIANCE_NORMAL as libc::c_int,
+        ) == 1
+    }
+
+    /// Check that all streams can be muxed into the output format before calling [Muxer::open]
+    ///
+    /// All problems are reported in a single error, one per line
+    pub unsafe fn validate(&self) -> Result<()> {
+        if self.ctx.is_null() || (*self.ctx).oformat.is_null() {
+            bail!("Muxer is not initialized");
+        }
+        let oformat = (*self.ctx).oformat;
+        let mut errors = Vec::new();
+        if (*self.ctx).nb_streams == 0 {
+            errors.push("Output has no streams".to_string());
+        }
+        for i in 0..(*self.ctx).nb_streams as usize {
+            let stream = *(*self.ctx).streams.add(i);
+            let par = (*stream).codecpar;
+            let codec_name = rstr!(avcodec_get_name((*par).codec_id));
+            if avformat_query_codec(
+                oformat,
+                (*par).codec_id,
+                FF_COMPLIANCE_NORMAL as libc::c_int,
+            ) == 0
+            {
+                errors.push(format!(
+                    "Stream #{}: codec {} is not supported by output format {}",
+                    i,
+                    codec_name,
+                    rstr!((*oformat).name)
+                ));
+            }
+            match (*par).codec_type {
+                AVMediaType::AVMEDIA_TYPE_VIDEO if (*par).width <= 0 || (*par).height <= 0 => {
+                    errors.push(format!(
+                        "Stream #{}: {} video has no size ({}x{})",
+                        i,
+                        codec_name,
+                        (*par).width,
+                        (*par).height
+                    ));
+                }
+                AVMediaType::AVMEDIA_TYPE_AUDIO
+                    if (*par).sample_rate <= 0 || (*par).ch_layout.nb_channels <= 0 =>
+                {
+                    errors.push(format!(
+                        "Stream #{}: {} audio has no sample rate or channels",
+                        i, codec_name
+                    ));
+                }
+                _ => {}
+            }
+        }
+        if !errors.is_empty() {
+            bail!(errors.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Get the output format flags (AVFMT_*)
+    pub unsafe fn oformat_flags(&self) -> libc::c_int {
+        if self.ctx.is_null() || (*self.ctx).oformat.is_null() {
+            return 0;
+        }
+        (*(*self.ctx).oformat).flags
+    }
+
     /// Add a stream to the output using an existing encoder
     pub unsafe fn add_stream_encoder(&mut self, encoder: &Encoder) -> Result<*mut AVStream> {
-        MuxerBuilder::add_stream_from_encoder(self.ctx, encoder)
+        let stream = MuxerBuilder::add_stream_from_encoder(self.ctx, encoder)?;
+        self.encoder_streams
+            .insert(encoder.codec_context(), (*stream).index);
+        Ok(stream)
     }
 
     /// Add a stream to the output using an existing input stream (copy)
@@ -293,8 +580,68 @@ impl Muxer {
         MuxerBuilder::add_copy_stream(self.ctx, in_stream)
     }
 
+    /// Add a data stream to the output, eg. [AVCodecID::AV_CODEC_ID_TIMED_ID3] for
+    /// HLS/MPEG-TS timed metadata, see [Muxer::write_timed_metadata]
+    pub unsafe fn add_data_stream(&mut self, codec: AVCodecID) -> Result<*mut AVStream> {
+        if self.ctx.is_null() {
+            bail!("cannot add stream to null ctx");
+        }
+        let stream = avformat_new_stream(self.ctx, ptr::null_mut());
+        if stream.is_null() {
+            bail!("unable to allocate stream");
+        }
+        (*(*stream).codecpar).codec_type = AVMediaType::AVMEDIA_TYPE_DATA;
+        (*(*stream).codecpar).codec_id = codec;
+        (*stream).time_base = TIMED_METADATA_TB;
+        Ok(stream)
+    }
+
+    /// Write a timed metadata packet (eg. an ID3 tag) to a data stream
+    ///
+    /// `pts` is in 90kHz units like MPEG-TS timestamps and should be in sync with the
+    /// audio/video packets it relates to
+    pub unsafe fn write_timed_metadata(
+        &mut self,
+        stream_index: i32,
+        pts: i64,
+        data: &[u8],
+    ) -> Result<()> {
+        if stream_index < 0 || stream_index as u32 >= (*self.ctx).nb_streams {
+            bail!("Invalid stream index {}", stream_index);
+        }
+        let stream = *(*self.ctx).streams.add(stream_index as usize);
+        if (*(*stream).codecpar).codec_type != AVMediaType::AVMEDIA_TYPE_DATA {
+            bail!("Stream {} is not a data stream", stream_index);
+        }
+        let mut pkt = av_packet_alloc();
+        if pkt.is_null() {
+            bail!("Failed to allocate packet");
+        }
+        let ret = av_new_packet(pkt, data.len() as libc::c_int);
+        bail_ffmpeg!(ret, { av_packet_free(&mut pkt) });
+        if !data.is_empty() {
+            ptr::copy_nonoverlapping(data.as_ptr(), (*pkt).data, data.len());
+        }
+        (*pkt).stream_index = stream_index;
+        (*pkt).pts = pts;
+        (*pkt).dts = pts;
+        (*pkt).time_base = TIMED_METADATA_TB;
+        (*pkt).flags |= AV_PKT_FLAG_KEY as libc::c_int;
+        let ret = self.write_packet(pkt);
+        av_packet_free(&mut pkt);
+        ret
+    }
+
     /// Initialize the context, usually after it was closed with [Muxer::close]
+    ///
+    /// Faststart muxers can't be re-used as the writer is consumed by [Muxer::close]
     pub unsafe fn init(&mut self) -> Result<()> {
+        if !self.ctx.is_null() {
+            bail!("Muxer is already initialized");
+        }
+        if let MuxerOutput::Faststart { writer: None, .. } = self.output {
+            bail!("Faststart writer was consumed by close, the muxer cannot be re-used");
+        }
         MuxerBuilder::init_ctx(&mut self.ctx, self.url.as_deref(), self.format.as_deref())
     }
 
@@ -316,12 +663,37 @@ impl Muxer {
         Ok(())
     }
 
+    /// Limit how long (in microseconds) packets are buffered while waiting for the other
+    /// streams when interleaving, 0 buffers until every stream has a packet
+    ///
+    /// Must be set before [Muxer::open], a small delta keeps latency and memory low for
+    /// live outputs at the cost of less ideal interleaving
+    pub unsafe fn set_max_interleave_delta(&mut self, usecs: i64) -> Result<()> {
+        if self.ctx.is_null() {
+            bail!("Muxer is not initialized");
+        }
+        if usecs < 0 {
+            bail!("Invalid max interleave delta {}", usecs);
+        }
+        (*self.ctx).max_interleave_delta = usecs;
+        Ok(())
+    }
+
     /// Open the output to start sending packets
     pub unsafe fn open(&mut self, options: Option<HashMap<String, String>>) -> Result<()> {
+        if self.ctx.is_null() {
+            bail!("Muxer is not initialized");
+        }
         // Set options on ctx
         if let Some(opts) = options {
             set_opts((*self.ctx).priv_data, opts)?;
         }
+        if let MuxerOutput::Faststart { .. } = self.output {
+            set_opts(
+                (*self.ctx).priv_data,
+                HashMap::from([("movflags".to_string(), "+faststart".to_string())]),
+            )?;
+        }
 
         if (*(*self.ctx).oformat).flags & AVFMT_NOFILE == 0 {
             (*self.ctx).pb = (&mut self.output).try_into()?;
@@ -336,11 +708,37 @@ impl Muxer {
         }
 
         let ret = avformat_write_header(self.ctx, ptr::null_mut());
-        bail_ffmpeg!(ret);
+        bail_ffmpeg!(ret, {
+            self.release_output();
+        });
 
         Ok(())
     }
 
+    /// Free the AVIO of a failed [Muxer::open] and give the writer back to the output,
+    /// so the muxer can be dropped or opened again
+    unsafe fn release_output(&mut self) {
+        if self.ctx.is_null() || (*self.ctx).pb.is_null() {
+            return;
+        }
+        let pb = (*self.ctx).pb;
+        match &mut self.output {
+            MuxerOutput::Url(_) | MuxerOutput::Faststart { .. } => {
+                avio_closep(&mut (*self.ctx).pb);
+            }
+            MuxerOutput::WriterSeeker(w) => {
+                av_free((*pb).buffer as *mut _);
+                *w = Some(SlimBox::from_raw((*pb).opaque));
+                avio_context_free(&mut (*self.ctx).pb);
+            }
+            MuxerOutput::Writer(w) => {
+                av_free((*pb).buffer as *mut _);
+                *w = Some(SlimBox::from_raw((*pb).opaque));
+                avio_context_free(&mut (*self.ctx).pb);
+            }
+        }
+    }
+
     /// Get [AVFormatContext] pointer
     pub fn context(&self) -> *mut AVFormatContext {
         self.ctx
@@ -349,45 +747,117 @@ impl Muxer {
     /// Write a packet to the output
     pub unsafe fn write_packet(&mut self, pkt: *mut AVPacket) -> Result<()> {
         let stream = *(*self.ctx).streams.add((*pkt).stream_index as usize);
-        av_packet_rescale_ts(pkt, (*pkt).time_base, (*stream).time_base);
+        // packets without a time base are assumed to be in the stream time base,
+        // NOPTS timestamps are left untouched by the rescale
+        if (*pkt).time_base.num > 0 && (*pkt).time_base.den > 0 {
+            av_packet_rescale_ts(pkt, (*pkt).time_base, (*stream).time_base);
+        }
         (*pkt).time_base = (*stream).time_base;
+        if self.derive_timestamps {
+            self.fill_timestamps(pkt);
+        }
 
+        let size = (*pkt).size as u64;
         let ret = av_interleaved_write_frame(self.ctx, pkt);
         bail_ffmpeg!(ret);
+        if let Some(rate_limit) = &mut self.rate_limit {
+            rate_limit.wait(size);
+        }
+        Ok(())
+    }
+
+    /// Fill missing DTS/PTS, `pkt` timestamps must be in the stream time base
+    unsafe fn fill_timestamps(&mut self, pkt: *mut AVPacket) {
+        if (*pkt).dts == AV_NOPTS_VALUE {
+            (*pkt).dts = if let Some(dts) = self.next_dts.get(&(*pkt).stream_index) {
+                *dts
+            } else if (*pkt).pts != AV_NOPTS_VALUE {
+                (*pkt).pts
+            } else {
+                0
+            };
+        }
+        if (*pkt).pts == AV_NOPTS_VALUE {
+            (*pkt).pts = (*pkt).dts;
+        }
+        // keep DTS increasing when the duration is unknown
+        self.next_dts
+            .insert((*pkt).stream_index, (*pkt).dts + (*pkt).duration.max(1));
+    }
+
+    /// Encode a frame and write the resulting packets to the encoder's output stream,
+    /// a null frame flushes the encoder
+    ///
+    /// The encoder must have been added with [Muxer::add_stream_encoder] /
+    /// [MuxerBuilder::with_stream_encoder] or have a stream index assigned
+    pub unsafe fn write_encoded(
+        &mut self,
+        encoder: &mut Encoder,
+        frame: *mut AVFrame,
+    ) -> Result<()> {
+        let stream_index = if let Some(idx) = encoder.stream_index() {
+            idx
+        } else if let Some(idx) = self.encoder_streams.get(&encoder.codec_context()) {
+            *idx
+        } else {
+            bail!("Encoder has no output stream in this muxer");
+        };
+        for mut pkt in encoder.encode_frame(frame)? {
+            (*pkt).stream_index = stream_index;
+            let ret = self.write_packet(pkt);
+            av_packet_free(&mut pkt);
+            ret?;
+        }
         Ok(())
     }
 
     /// Close the output and write the trailer
     /// [Muxer::init] can be used to re-init the muxer
     pub unsafe fn close(&mut self) -> Result<()> {
+        if self.ctx.is_null() {
+            bail!("Muxer is not initialized");
+        }
         let ret = av_write_trailer(self.ctx);
         bail_ffmpeg!(ret);
         self.free_ctx()?;
+        if let MuxerOutput::Faststart { path, writer } = &mut self.output {
+            let mut writer = writer.take().expect("writer already consumed");
+            let mut file = std::fs::File::open(path.as_path())?;
+            std::io::copy(&mut file, &mut writer)?;
+            writer.flush()?;
+            std::fs::remove_file(path.as_path())?;
+        }
         Ok(())
     }
 
     unsafe fn free_ctx(&mut self) -> Result<()> {
         if !self.ctx.is_null() {
             match self.output {
-                MuxerOutput::Url(_) => {
+                MuxerOutput::Url(_) | MuxerOutput::Faststart { .. } => {
                     if !(*self.ctx).pb.is_null() {
                         let ret = avio_close((*self.ctx).pb);
                         bail_ffmpeg!(ret);
                     }
                 }
+                // pb is never allocated for AVFMT_NOFILE formats or if the muxer was not opened
                 MuxerOutput::WriterSeeker(_) => {
-                    av_free((*(*self.ctx).pb).buffer as *mut _);
-                    drop(SlimBox::<dyn WriteSeek>::from_raw((*(*self.ctx).pb).opaque));
-                    avio_context_free(&mut (*self.ctx).pb);
+                    if !(*self.ctx).pb.is_null() {
+                        av_free((*(*self.ctx).pb).buffer as *mut _);
+                        drop(SlimBox::<dyn WriteSeek>::from_raw((*(*self.ctx).pb).opaque));
+                        avio_context_free(&mut (*self.ctx).pb);
+                    }
                 }
                 MuxerOutput::Writer(_) => {
-                    av_free((*(*self.ctx).pb).buffer as *mut _);
-                    drop(SlimBox::<dyn Write>::from_raw((*(*self.ctx).pb).opaque));
-                    avio_context_free(&mut (*self.ctx).pb);
+                    if !(*self.ctx).pb.is_null() {
+                        av_free((*(*self.ctx).pb).buffer as *mut _);
+                        drop(SlimBox::<dyn Write>::from_raw((*(*self.ctx).pb).opaque));
+                        avio_context_free(&mut (*self.ctx).pb);
+                    }
                 }
             }
             avformat_free_context(self.ctx);
             self.ctx = ptr::null_mut();
+            self.encoder_streams.clear();
         }
         Ok(())
     }
@@ -398,37 +868,26 @@ impl Drop for Muxer {
         unsafe {
             self.free_ctx().expect("drop muxer");
         }
+        // cleanup temp file if the muxer was not closed
+        if let MuxerOutput::Faststart { path, .. } = &self.output {
+            let _ = std::fs::remove_file(path);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{generate_test_frame, Scaler};
-    use ffmpeg_sys_the_third::AVCodecID::AV_CODEC_ID_H264;
+    use crate::{generate_test_frame, setup_test_encoder};
     use ffmpeg_sys_the_third::AVPixelFormat::AV_PIX_FMT_YUV420P;
-    use ffmpeg_sys_the_third::{AVFrame, AV_PROFILE_H264_MAIN};
-    use std::path::PathBuf;
+    use ffmpeg_sys_the_third::{
+        av_frame_free, av_packet_clone, av_q2d, AVPixelFormat, AV_PROFILE_H264_MAIN,
+    };
+    use std::sync::{Arc, Mutex};
 
     unsafe fn setup_encoder() -> Result<(*mut AVFrame, Encoder)> {
-        std::fs::create_dir_all("test_output")?;
-        let frame = generate_test_frame();
-
-        // convert frame to YUV
-        let mut scaler = Scaler::new();
-        let frame = scaler.process_frame(
-            frame,
-            (*frame).width as u16,
-            (*frame).height as u16,
-            AV_PIX_FMT_YUV420P,
-        )?;
-
-        let encoder = Encoder::new(AV_CODEC_ID_H264)?
-            .with_width((*frame).width)
-            .with_height((*frame).height)
-            .with_pix_fmt(AV_PIX_FMT_YUV420P)
-            .with_bitrate(1_000_000)
-            .with_framerate(30.0)?
+        let (frame, encoder) = setup_test_encoder(1024, 1024)?;
+        let encoder = encoder
             .with_profile(AV_PROFILE_H264_MAIN)
             .with_level(50)
             .open(None)?;
@@ -464,7 +923,7 @@ mod tests {
             let (frame, encoder) = setup_encoder()?;
 
             let mut muxer = Muxer::builder()
-                .with_output_path(path.to_str().unwrap(), None)?
+                .with_output_path(&path, None)?
                 .with_stream_encoder(&encoder)?
                 .build()?;
             muxer.open(None)?;
@@ -481,7 +940,7 @@ mod tests {
             let (frame, encoder) = setup_encoder()?;
 
             let mut muxer = Muxer::builder()
-                .with_output_path(path.to_str().unwrap(), None)?
+                .with_output_path(&path, None)?
                 .with_stream_encoder(&encoder)?
                 .build()?;
             muxer.open(None)?;
@@ -498,6 +957,64 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn encode_write_encoded() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let (frame, mut encoder) = setup_encoder()?;
+            let mut muxer = Muxer::builder()
+                .with_output_path("test_output/test_write_encoded.mp4", None)?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            for pts in 0..90 {
+                (*frame).pts = pts;
+                muxer.write_encoded(&mut encoder, frame)?;
+            }
+            muxer.write_encoded(&mut encoder, ptr::null_mut())?;
+            muxer.close()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encode_image_sequence() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_PNG)?
+                .with_width((*frame).width)
+                .with_height((*frame).height)
+                .with_pix_fmt(AVPixelFormat::AV_PIX_FMT_RGB24)
+                .open(None)?;
+
+            let mut muxer = Muxer::builder()
+                .with_output_path("test_output/test_image2_%03d.png", Some("image2"))?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            for pts in 0..3 {
+                (*frame).pts = pts;
+                for mut pkt in encoder.encode_frame(frame)? {
+                    muxer.write_packet(pkt)?;
+                    av_packet_free(&mut pkt);
+                }
+            }
+            for mut pkt in encoder.encode_frame(ptr::null_mut())? {
+                muxer.write_packet(pkt)?;
+                av_packet_free(&mut pkt);
+            }
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            for n in 1..=3 {
+                let path = PathBuf::from(format!("test_output/test_image2_{:03}.png", n));
+                assert!(path.exists());
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn encode_custom_io() -> Result<()> {
         std::fs::create_dir_all("test_output")?;
@@ -533,4 +1050,567 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn encoder_format_validation() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            // png does not support yuv420p
+            let encoder = Encoder::new(AVCodecID::AV_CODEC_ID_PNG)?
+                .with_width(256)
+                .with_height(256)
+                .with_pix_fmt(AV_PIX_FMT_YUV420P);
+            let err = Muxer::builder()
+                .with_output_path("test_output/test_invalid_pix_fmt.mkv", None)?
+                .with_stream_encoder(&encoder)
+                .err()
+                .expect("stream should be rejected");
+            assert!(err.to_string().contains("Pixel format yuv420p"));
+
+            // wav cannot contain h264
+            let (mut frame, encoder) = setup_encoder()?;
+            let err = Muxer::builder()
+                .with_output_path("test_output/test_invalid_codec.wav", None)?
+                .with_stream_encoder(&encoder)
+                .err()
+                .expect("stream should be rejected");
+            assert!(err
+                .to_string()
+                .contains("not supported by output format wav"));
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    /// Non-seekable writer sharing its buffer with the test
+    struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+    /// Non-blocking writer which returns [ErrorKind::WouldBlock] on every other call
+    /// and accepts at most 100 bytes per write
+    struct WouldBlockWriter {
+        buf: Arc<Mutex<Vec<u8>>>,
+        blocked: Arc<AtomicUsize>,
+        block: bool,
+    }
+
+    impl Write for WouldBlockWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                self.blocked.fetch_add(1, Ordering::Relaxed);
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(100);
+            self.buf.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Mux `pkts` to `writer` as MPEG-TS
+    unsafe fn mux_packets<W: Write + 'static>(
+        writer: W,
+        encoder: &Encoder,
+        pkts: &[*mut AVPacket],
+    ) -> Result<()> {
+        let mut muxer = Muxer::builder()
+            .with_output_write(writer, Some("mpegts"))?
+            .with_stream_encoder(encoder)?
+            .build()?;
+        muxer.open(None)?;
+        for pkt in pkts {
+            let mut pkt = av_packet_clone(*pkt);
+            let ret = muxer.write_packet(pkt);
+            av_packet_free(&mut pkt);
+            ret?;
+        }
+        muxer.close()
+    }
+
+    #[test]
+    fn write_would_block() -> Result<()> {
+        unsafe {
+            let (mut frame, mut encoder) = setup_encoder()?;
+            let mut pkts = Vec::new();
+            for pts in 0..30 {
+                (*frame).pts = pts;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+
+            let expected = Arc::new(Mutex::new(Vec::new()));
+            mux_packets(SharedWriter(expected.clone()), &encoder, &pkts)?;
+
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            let blocked = Arc::new(AtomicUsize::new(0));
+            let writer = WouldBlockWriter {
+                buf: buf.clone(),
+                blocked: blocked.clone(),
+                block: false,
+            };
+            mux_packets(writer, &encoder, &pkts)?;
+
+            // every byte is written exactly once even though writes were refused
+            assert!(blocked.load(Ordering::Relaxed) > 0);
+            let expected = expected.lock().unwrap();
+            assert!(!expected.is_empty());
+            assert_eq!(*buf.lock().unwrap(), *expected);
+
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writer which never accepts data
+    struct BlockedWriter;
+
+    impl Write for BlockedWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_blocked_fails() -> Result<()> {
+        unsafe {
+            let (mut frame, mut encoder) = setup_encoder()?;
+            let mut pkts = Vec::new();
+            for pts in 0..30 {
+                (*frame).pts = pts;
+                pkts.extend(encoder.encode_frame(frame)?);
+            }
+            pkts.extend(encoder.encode_frame(ptr::null_mut())?);
+
+            // a stalled writer is an error instead of a hang
+            let start = Instant::now();
+            let err = mux_packets(BlockedWriter, &encoder, &pkts)
+                .err()
+                .expect("blocked writer should fail");
+            assert!(start.elapsed() >= WRITE_BLOCK_TIMEOUT);
+            assert!(!err.to_string().is_empty());
+
+            for mut pkt in pkts {
+                av_packet_free(&mut pkt);
+            }
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encode_faststart_non_seek() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let (frame, encoder) = setup_encoder()?;
+
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            let mut muxer = Muxer::builder()
+                .with_output_write_faststart(SharedWriter(buf.clone()), Some("mp4"))?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            write_frames(&mut muxer, encoder, frame)?;
+
+            let data = buf.lock().unwrap();
+            let find = |tag: &[u8]| data.windows(4).position(|w| w == tag);
+            let moov = find(b"moov").expect("no moov");
+            let mdat = find(b"mdat").expect("no mdat");
+            assert!(moov < mdat);
+
+            // the writer was consumed, re-use must fail instead of touching the freed context
+            assert!(muxer.init().is_err());
+            assert!(muxer.open(None).is_err());
+            assert!(muxer.close().is_err());
+        }
+        Ok(())
+    }
+
+    /// Slow writer which counts the bytes written
+    struct SlowWriter(Arc<AtomicUsize>);
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(1));
+            self.0.fetch_add(buf.len(), Ordering::Relaxed);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encode_rate_limit() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let (frame, encoder) = setup_encoder()?;
+
+            let written = Arc::new(AtomicUsize::new(0));
+            let bitrate = 8_000_000;
+            let mut muxer = Muxer::builder()
+                .with_output_write(SlowWriter(written.clone()), Some("mpegts"))?
+                .with_stream_encoder(&encoder)?
+                .with_rate_limit(bitrate)?
+                .build()?;
+            muxer.open(None)?;
+            let start = Instant::now();
+            write_frames(&mut muxer, encoder, frame)?;
+
+            let bytes = written.load(Ordering::Relaxed);
+            assert!(bytes > 0);
+            // payload is paced to the target bitrate, allow for container overhead
+            let min_duration = bytes as f64 * 8.0 / bitrate as f64 * 0.5;
+            assert!(start.elapsed().as_secs_f64() >= min_duration);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn encode_nopts() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let path = "test_output/test_muxer_nopts.mp4";
+            let (mut frame, mut encoder) = setup_encoder()?;
+
+            let mut muxer = Muxer::builder()
+                .with_output_path(path, None)?
+                .with_stream_encoder(&encoder)?
+                .with_derive_timestamps(true)
+                .build()?;
+            muxer.open(None)?;
+            let mut packets = Vec::new();
+            for pts in 0..30 {
+                (*frame).pts = pts;
+                packets.extend(encoder.encode_frame(frame)?);
+            }
+            packets.extend(encoder.encode_frame(ptr::null_mut())?);
+            let written = packets.len();
+            for (i, mut pkt) in packets.into_iter().enumerate() {
+                // only the first packet has timestamps
+                if i > 0 {
+                    (*pkt).pts = AV_NOPTS_VALUE;
+                    (*pkt).dts = AV_NOPTS_VALUE;
+                }
+                (*pkt).duration = 1;
+                muxer.write_packet(pkt)?;
+                av_packet_free(&mut pkt);
+            }
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            let mut demux = crate::Demuxer::new(path)?;
+            demux.probe_input()?;
+            let mut last_dts = None;
+            let mut count = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                assert_ne!((*pkt).dts, AV_NOPTS_VALUE);
+                if let Some(last) = last_dts {
+                    assert!((*pkt).dts > last);
+                }
+                last_dts = Some((*pkt).dts);
+                count += 1;
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(count, written);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn format_capabilities() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let mp4 = Muxer::builder()
+                .with_output_path("test_output/test_capabilities.mp4", None)?
+                .build()?;
+            assert!(mp4.supports_codec(AVCodecID::AV_CODEC_ID_H264));
+            assert!(mp4.supports_codec(AVCodecID::AV_CODEC_ID_AAC));
+            assert_ne!(mp4.oformat_flags() & AVFMT_GLOBALHEADER as libc::c_int, 0);
+
+            let webm = Muxer::builder()
+                .with_output_path("test_output/test_capabilities.webm", None)?
+                .build()?;
+            assert!(webm.supports_codec(AVCodecID::AV_CODEC_ID_VP9));
+            assert!(!webm.supports_codec(AVCodecID::AV_CODEC_ID_H264));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn validate_streams() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_validate_input.mp4";
+            crate::generate_test_video(input, 10, 10)?;
+            let mut demux = crate::Demuxer::new(input)?;
+            let info = demux.probe_input()?;
+            let video = info.best_video().expect("no video stream");
+
+            let mut mkv = Muxer::builder()
+                .with_output_path("test_output/test_validate.mkv", None)?
+                .build()?;
+            assert!(mkv.validate().is_err());
+            mkv.add_copy_stream(video.stream)?;
+            mkv.validate()?;
+
+            let mut webm = Muxer::builder()
+                .with_output_path("test_output/test_validate.webm", None)?
+                .build()?;
+            webm.add_copy_stream(video.stream)?;
+            let err = webm.validate().unwrap_err().to_string();
+            assert!(err.contains("Stream #0"), "{}", err);
+            assert!(err.contains("h264"), "{}", err);
+            assert!(err.contains("webm"), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn output_path_buf() -> Result<()> {
+        std::fs::create_dir_all("test_output")?;
+        unsafe {
+            let path = PathBuf::from("test_output").join("test_path_buf.mkv");
+            let muxer = Muxer::builder().with_output_path(path, None)?.build()?;
+            assert!(muxer.supports_codec(AVCodecID::AV_CODEC_ID_H264));
+
+            #[cfg(unix)]
+            {
+                use std::ffi::OsStr;
+                use std::os::unix::ffi::OsStrExt;
+                let path = Path::new(OsStr::from_bytes(b"test_output/\xff.mkv"));
+                let err = Muxer::builder().with_output_path(path, None).err();
+                assert!(err.expect("non UTF-8 path").to_string().contains("UTF-8"));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn remux_copy_timestamps() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_remux_ts_input.mp4";
+            let output = "test_output/test_remux_ts.mkv";
+            crate::generate_test_video(input, 30, 10)?;
+
+            // read all packet timestamps (pts, dts) in seconds
+            let read_ts = |path: &str| -> Result<Vec<(f64, Option<f64>)>> {
+                let mut demux = crate::Demuxer::new(path)?;
+                demux.probe_input()?;
+                let mut ret = Vec::new();
+                loop {
+                    let (mut pkt, _) = demux.get_packet()?;
+                    if pkt.is_null() {
+                        break;
+                    }
+                    let tb = av_q2d((*pkt).time_base);
+                    let dts = if (*pkt).dts != AV_NOPTS_VALUE {
+                        Some((*pkt).dts as f64 * tb)
+                    } else {
+                        None
+                    };
+                    ret.push(((*pkt).pts as f64 * tb, dts));
+                    av_packet_free(&mut pkt);
+                }
+                Ok(ret)
+            };
+
+            let mut demux = crate::Demuxer::new(input)?;
+            let info = demux.probe_input()?;
+            let mut muxer = Muxer::builder().with_output_path(output, None)?.build()?;
+            for stream in &info.streams {
+                muxer.add_copy_stream(stream.stream)?;
+            }
+            muxer.open(None)?;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                muxer.write_packet(pkt)?;
+                av_packet_free(&mut pkt);
+            }
+            muxer.close()?;
+
+            let in_ts = read_ts(input)?;
+            let out_ts = read_ts(output)?;
+            assert_eq!(in_ts.len(), out_ts.len());
+            for (a, b) in in_ts.iter().zip(out_ts.iter()) {
+                // mkv has millisecond precision
+                assert!((a.0 - b.0).abs() < 0.002, "pts {} != {}", a.0, b.0);
+            }
+            let dts: Vec<f64> = out_ts.iter().filter_map(|t| t.1).collect();
+            assert!(dts.windows(2).all(|w| w[0] < w[1]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn open_failure_restores_writer() -> Result<()> {
+        unsafe {
+            let (mut frame, encoder) = setup_encoder()?;
+            let buf = Arc::new(Mutex::new(Vec::new()));
+            // webm does not allow h264, writing the header fails
+            let mut muxer = Muxer::builder()
+                .with_output_write(SharedWriter(buf.clone()), Some("webm"))?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            assert!(muxer.open(None).is_err());
+            assert!((*muxer.context()).pb.is_null());
+            assert!(matches!(muxer.output, MuxerOutput::Writer(Some(_))));
+
+            // opening again fails the same way instead of panicking on the consumed writer
+            assert!(muxer.open(None).is_err());
+            drop(muxer);
+            assert_eq!(Arc::strong_count(&buf), 1);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn timed_id3_metadata() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_timed_id3.ts";
+            // ID3v2.4 tag with a single TIT2 (title) frame
+            let mut id3 = b"ID3\x04\x00\x00\x00\x00\x00\x16".to_vec();
+            id3.extend_from_slice(b"TIT2\x00\x00\x00\x0c\x00\x00\x03Now Playing");
+
+            let mut muxer = Muxer::builder().with_output_path(path, None)?.build()?;
+            let stream = muxer.add_data_stream(AVCodecID::AV_CODEC_ID_TIMED_ID3)?;
+            let index = (*stream).index;
+            muxer.open(None)?;
+            for n in 0..3 {
+                muxer.write_timed_metadata(index, n * 90_000, &id3)?;
+            }
+            assert!(muxer.write_timed_metadata(index + 1, 0, &id3).is_err());
+            muxer.close()?;
+
+            let mut demux = crate::Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let data = info
+                .streams
+                .iter()
+                .find(|s| s.stream_type == crate::StreamType::Data)
+                .expect("no data stream");
+            assert_eq!(data.codec, AVCodecID::AV_CODEC_ID_TIMED_ID3 as isize);
+            let mut packets = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                if (*pkt).stream_index == data.index as i32 {
+                    let payload = slice::from_raw_parts((*pkt).data, (*pkt).size as usize);
+                    assert_eq!(payload, id3.as_slice());
+                    packets += 1;
+                }
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(packets, 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_interleave_delta() -> Result<()> {
+        use crate::audio_silence;
+        use ffmpeg_sys_the_third::{av_channel_layout_default, AVChannelLayout, AVSampleFormat};
+
+        unsafe {
+            let (mut frame, mut video) = setup_encoder()?;
+            let mut audio = Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                .with_sample_rate(48_000)?
+                .with_sample_format(AVSampleFormat::AV_SAMPLE_FMT_FLTP)
+                .with_default_channel_layout(2)
+                .open(None)?;
+            let mut muxer = Muxer::builder()
+                .with_output_path("test_output/test_interleave_delta.mkv", None)?
+                .with_stream_encoder(&video)?
+                .with_stream_encoder(&audio)?
+                .build()?;
+            assert!(muxer.set_max_interleave_delta(-1).is_err());
+            muxer.set_max_interleave_delta(100_000)?;
+            assert_eq!((*muxer.context()).max_interleave_delta, 100_000);
+            muxer.open(None)?;
+
+            let mut layout = AVChannelLayout::empty();
+            av_channel_layout_default(&mut layout, 2);
+            let mut samples =
+                audio_silence(1024, AVSampleFormat::AV_SAMPLE_FMT_FLTP, &layout, 48_000)?;
+            // 1 second of audio and video
+            let mut audio_pts = 0;
+            for pts in 0..30 {
+                (*frame).pts = pts;
+                muxer.write_encoded(&mut video, frame)?;
+                while audio_pts < (pts + 1) * 1600 {
+                    (*samples).pts = audio_pts;
+                    muxer.write_encoded(&mut audio, samples)?;
+                    audio_pts += 1024;
+                }
+            }
+            muxer.write_encoded(&mut video, ptr::null_mut())?;
+            muxer.write_encoded(&mut audio, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut samples);
+            av_frame_free(&mut frame);
+
+            let mut demux = crate::Demuxer::new("test_output/test_interleave_delta.mkv")?;
+            let info = demux.probe_input()?;
+            assert!(info.best_video().is_some());
+            assert!(info.best_audio().is_some());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn display_rotation() -> Result<()> {
+        unsafe {
+            let (mut frame, encoder) = setup_encoder()?;
+            let mut encoder = encoder.with_display_rotation(90.0);
+            let path = "test_output/test_display_rotation.mp4";
+            let mut muxer = Muxer::builder()
+                .with_output_path(path, None)?
+                .with_stream_encoder(&encoder)?
+                .build()?;
+            muxer.open(None)?;
+            for pts in 0..10 {
+                (*frame).pts = pts;
+                muxer.write_encoded(&mut encoder, frame)?;
+            }
+            muxer.write_encoded(&mut encoder, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            let mut demux = crate::Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let video = info.best_video().expect("no video stream");
+            assert!((video.rotation - 90.0).abs() < 0.01, "{}", video.rotation);
+        }
+        Ok(())
+    }
 }
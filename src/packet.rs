@@ -0,0 +1,169 @@
+use crate::bail_ffmpeg;
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_new_packet, av_packet_alloc, av_packet_clone, av_packet_copy_props, av_packet_free, av_q2d,
+    AVPacket, AV_NOPTS_VALUE,
+};
+use std::ptr;
+
+/// Create a new packet referencing the same data buffer as `pkt` (see [av_packet_clone])
+///
+/// Properties (timestamps, stream index, flags) are copied so they can be changed
+/// independently, but the payload is shared and must not be written to
+pub unsafe fn packet_clone_ref(pkt: *const AVPacket) -> Result<*mut AVPacket> {
+    let ret = av_packet_clone(pkt);
+    if ret.is_null() {
+        bail!("Failed to clone packet");
+    }
+    Ok(ret)
+}
+
+/// Create a new packet with a copy of the payload and properties of `pkt`
+///
+/// Nothing is shared with the source packet, so either can be modified freely
+pub unsafe fn packet_deep_clone(pkt: *const AVPacket) -> Result<*mut AVPacket> {
+    let mut ret = av_packet_alloc();
+    if ret.is_null() {
+        bail!("Failed to allocate packet");
+    }
+    let r = av_new_packet(ret, (*pkt).size);
+    bail_ffmpeg!(r, {
+        av_packet_free(&mut ret);
+    });
+    if (*pkt).size > 0 {
+        ptr::copy_nonoverlapping((*pkt).data, (*ret).data, (*pkt).size as usize);
+    }
+    let r = av_packet_copy_props(ret, pkt);
+    bail_ffmpeg!(r, {
+        av_packet_free(&mut ret);
+    });
+    Ok(ret)
+}
+
+/// Convert a timestamp in the packet time base to seconds
+unsafe fn packet_ts_secs(pkt: *const AVPacket, ts: i64) -> Option<f64> {
+    let tb = (*pkt).time_base;
+    if ts == AV_NOPTS_VALUE || tb.num <= 0 || tb.den <= 0 {
+        return None;
+    }
+    Some(ts as f64 * av_q2d(tb))
+}
+
+/// Get the packet pts in seconds, using the packet time base
+///
+/// Returns [None] if the pts or time base is not set
+pub unsafe fn packet_pts_secs(pkt: *const AVPacket) -> Option<f64> {
+    packet_ts_secs(pkt, (*pkt).pts)
+}
+
+/// Get the packet dts in seconds, using the packet time base
+pub unsafe fn packet_dts_secs(pkt: *const AVPacket) -> Option<f64> {
+    packet_ts_secs(pkt, (*pkt).dts)
+}
+
+/// Get the packet duration in seconds, [None] if unknown
+pub unsafe fn packet_duration_secs(pkt: *const AVPacket) -> Option<f64> {
+    if (*pkt).duration <= 0 {
+        return None;
+    }
+    packet_ts_secs(pkt, (*pkt).duration)
+}
+
+/// Get the byte position of the packet in the input, [None] if unknown
+pub unsafe fn packet_byte_pos(pkt: *const AVPacket) -> Option<u64> {
+    if (*pkt).pos < 0 {
+        None
+    } else {
+        Some((*pkt).pos as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::slice;
+
+    unsafe fn test_packet() -> *mut AVPacket {
+        let pkt = av_packet_alloc();
+        av_new_packet(pkt, 16);
+        slice::from_raw_parts_mut((*pkt).data, 16).fill(1);
+        (*pkt).pts = 100;
+        (*pkt).stream_index = 1;
+        pkt
+    }
+
+    #[test]
+    fn clone_ref_and_deep_clone() -> Result<()> {
+        unsafe {
+            let mut pkt = test_packet();
+
+            let mut shallow = packet_clone_ref(pkt)?;
+            assert_eq!((*shallow).data, (*pkt).data);
+            (*shallow).stream_index = 2;
+            (*shallow).pts = 200;
+            assert_eq!((*pkt).stream_index, 1);
+            assert_eq!((*pkt).pts, 100);
+
+            let mut deep = packet_deep_clone(pkt)?;
+            assert_ne!((*deep).data, (*pkt).data);
+            assert_eq!((*deep).size, 16);
+            assert_eq!((*deep).pts, 100);
+            (*deep).stream_index = 3;
+            *(*deep).data = 9;
+            assert_eq!((*pkt).stream_index, 1);
+            assert_eq!(*(*pkt).data, 1);
+
+            av_packet_free(&mut deep);
+            av_packet_free(&mut shallow);
+            av_packet_free(&mut pkt);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn demuxed_packet_times() -> Result<()> {
+        use crate::Demuxer;
+
+        unsafe {
+            let path = "test_output/test_packet_times.mp4";
+            crate::generate_test_video(path, 30, 10)?;
+            let mut demux = Demuxer::new(path)?;
+            demux.probe_input()?;
+
+            let mut last_dts = None;
+            let mut last_pos = None;
+            let mut pts = Vec::new();
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                let dts = packet_dts_secs(pkt).expect("no dts");
+                if let Some(last) = last_dts {
+                    assert!(dts > last, "{} <= {}", dts, last);
+                }
+                last_dts = Some(dts);
+                let pos = packet_byte_pos(pkt).expect("no byte position");
+                if let Some(last) = last_pos {
+                    assert!(pos > last);
+                }
+                last_pos = Some(pos);
+                let duration = packet_duration_secs(pkt).expect("no duration");
+                assert!((duration - 1.0 / 30.0).abs() < 0.001);
+                pts.push(packet_pts_secs(pkt).expect("no pts"));
+                av_packet_free(&mut pkt);
+            }
+
+            // pts are reordered by B-frames, but increase in presentation order
+            assert_eq!(pts.len(), 30);
+            pts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            assert!(pts.windows(2).all(|w| w[1] > w[0]));
+
+            // no time base
+            let mut pkt = test_packet();
+            assert_eq!(packet_pts_secs(pkt), None);
+            av_packet_free(&mut pkt);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{bail_ffmpeg, cstr, Encoder};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_bsf_alloc, av_bsf_free, av_bsf_get_by_name, av_bsf_init, av_bsf_receive_packet,
+    av_bsf_send_packet, av_packet_alloc, av_packet_clone, av_packet_free,
+    avcodec_parameters_from_context, AVBSFContext, AVCodecContext, AVCodecID, AVFrame, AVPacket,
+    AVERROR, AVERROR_EOF,
+};
+use std::io::Write;
+use std::{ptr, slice};
+
+/// Sample rates by ADTS sampling frequency index
+const ADTS_SAMPLE_RATES: [i32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Write encoded packets as a raw elementary stream (eg. `.h264`, `.aac`) without a muxer
+///
+/// H.264/HEVC packets are converted to Annex-B and AAC packets are written with ADTS headers
+pub struct RawWriter<W: Write> {
+    writer: W,
+    /// Bitstream filter applied before writing
+    bsf: *mut AVBSFContext,
+    /// ADTS header fields (profile, sample rate index, channels)
+    adts: Option<(u8, u8, u8)>,
+}
+
+impl<W: Write> Drop for RawWriter<W> {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.bsf.is_null() {
+                av_bsf_free(&mut self.bsf);
+            }
+        }
+    }
+}
+
+impl<W: Write> RawWriter<W> {
+    /// Create a raw writer for packets produced by `encoder`
+    pub unsafe fn new(encoder: &Encoder, writer: W) -> Result<Self> {
+        let ctx = encoder.codec_context();
+        let mut ret = Self {
+            writer,
+            bsf: ptr::null_mut(),
+            adts: None,
+        };
+        match (*ctx).codec_id {
+            AVCodecID::AV_CODEC_ID_H264 if Self::is_mp4_extradata(ctx) => {
+                ret.bsf = Self::init_bsf(ctx, "h264_mp4toannexb")?;
+            }
+            AVCodecID::AV_CODEC_ID_HEVC if Self::is_mp4_extradata(ctx) => {
+                ret.bsf = Self::init_bsf(ctx, "hevc_mp4toannexb")?;
+            }
+            AVCodecID::AV_CODEC_ID_AAC => {
+                let sr_index = if let Some(i) = ADTS_SAMPLE_RATES
+                    .iter()
+                    .position(|r| *r == (*ctx).sample_rate)
+                {
+                    i as u8
+                } else {
+                    bail!("Unsupported AAC sample rate {}", (*ctx).sample_rate);
+                };
+                // ADTS profile is the audio object type - 1, default to AAC-LC
+                let profile = if (0..4).contains(&(*ctx).profile) {
+                    (*ctx).profile as u8
+                } else {
+                    1
+                };
+                ret.adts = Some((profile, sr_index, (*ctx).ch_layout.nb_channels as u8));
+            }
+            _ => {}
+        }
+        Ok(ret)
+    }
+
+    /// Check if the codec extradata is in MP4 (avcC/hvcC) format instead of Annex-B
+    unsafe fn is_mp4_extradata(ctx: *const AVCodecContext) -> bool {
+        (*ctx).extradata_size > 0 && *(*ctx).extradata == 1
+    }
+
+    unsafe fn init_bsf(ctx: *const AVCodecContext, name: &str) -> Result<*mut AVBSFContext> {
+        let filter = av_bsf_get_by_name(cstr!(name));
+        if filter.is_null() {
+            bail!("Bitstream filter {} not found", name);
+        }
+        let mut bsf = ptr::null_mut();
+        let ret = av_bsf_alloc(filter, &mut bsf);
+        bail_ffmpeg!(ret);
+
+        let ret = avcodec_parameters_from_context((*bsf).par_in, ctx);
+        bail_ffmpeg!(ret, { av_bsf_free(&mut bsf) });
+        (*bsf).time_base_in = (*ctx).time_base;
+
+        let ret = av_bsf_init(bsf);
+        bail_ffmpeg!(ret, "Failed to init bitstream filter", {
+            av_bsf_free(&mut bsf)
+        });
+        Ok(bsf)
+    }
+
+    /// Write the packet payload, the packet is not consumed
+    pub unsafe fn write_packet(&mut self, pkt: *mut AVPacket) -> Result<()> {
+        if self.bsf.is_null() {
+            return self.write_payload(pkt);
+        }
+
+        let mut in_pkt = av_packet_clone(pkt);
+        if in_pkt.is_null() {
+            bail!("Failed to clone packet");
+        }
+        let ret = av_bsf_send_packet(self.bsf, in_pkt);
+        av_packet_free(&mut in_pkt);
+        bail_ffmpeg!(ret);
+
+        loop {
+            let mut out_pkt = av_packet_alloc();
+            let ret = av_bsf_receive_packet(self.bsf, out_pkt);
+            if ret == AVERROR(libc::EAGAIN) || ret == AVERROR_EOF {
+                av_packet_free(&mut out_pkt);
+                break;
+            }
+            bail_ffmpeg!(ret, { av_packet_free(&mut out_pkt) });
+            let ret = self.write_payload(out_pkt);
+            av_packet_free(&mut out_pkt);
+            ret?;
+        }
+        Ok(())
+    }
+
+    unsafe fn write_payload(&mut self, pkt: *const AVPacket) -> Result<()> {
+        if (*pkt).size <= 0 {
+            return Ok(());
+        }
+        let data = slice::from_raw_parts((*pkt).data, (*pkt).size as usize);
+        if let Some((profile, sr_index, channels)) = self.adts {
+            // 7 byte ADTS header without CRC
+            let len = data.len() + 7;
+            if len > 0x1fff {
+                bail!("AAC packet too large for ADTS");
+            }
+            let header = [
+                0xff,
+                0xf1,
+                (profile << 6) | (sr_index << 2) | (channels >> 2),
+                ((channels & 3) << 6) | (len >> 11) as u8,
+                ((len >> 3) & 0xff) as u8,
+                (((len & 7) << 5) as u8) | 0x1f,
+                0xfc,
+            ];
+            self.writer.write_all(&header)?;
+        }
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    /// Encode a frame and write the resulting packets, a null frame flushes the encoder
+    pub unsafe fn write_encoded(
+        &mut self,
+        encoder: &mut Encoder,
+        frame: *mut AVFrame,
+    ) -> Result<()> {
+        for mut pkt in encoder.encode_frame(frame)? {
+            let ret = self.write_packet(pkt);
+            av_packet_free(&mut pkt);
+            ret?;
+        }
+        Ok(())
+    }
+
+    /// Flush the writer and return the inner writer
+    pub fn into_inner(mut self) -> Result<W> {
+        self.writer.flush()?;
+        unsafe {
+            if !self.bsf.is_null() {
+                av_bsf_free(&mut self.bsf);
+            }
+            let writer = ptr::read(&self.writer);
+            std::mem::forget(self);
+            Ok(writer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{setup_test_encoder, Demuxer};
+    use ffmpeg_sys_the_third::av_frame_free;
+
+    #[test]
+    fn write_raw_h264() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let path = "test_output/test_raw.h264";
+            let (mut frame, encoder) = setup_test_encoder(256, 256)?;
+            let mut encoder = encoder.open(None)?;
+
+            let mut writer = RawWriter::new(&encoder, std::fs::File::create(path)?)?;
+            for pts in 0..30 {
+                (*frame).pts = pts;
+                writer.write_encoded(&mut encoder, frame)?;
+            }
+            writer.write_encoded(&mut encoder, ptr::null_mut())?;
+            writer.into_inner()?;
+            av_frame_free(&mut frame);
+
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            assert_eq!(info.format, "h264");
+            let video = info.best_video().expect("no video stream");
+            assert_eq!(video.codec, AVCodecID::AV_CODEC_ID_H264 as isize);
+            assert_eq!(video.width, 256);
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,11 @@
-use crate::bail_ffmpeg;
-use anyhow::Error;
+use crate::{bail_ffmpeg, set_opts};
+use anyhow::{bail, Error};
 use ffmpeg_sys_the_third::{
     av_channel_layout_default, av_frame_alloc, av_frame_copy_props, av_frame_free,
-    swr_alloc_set_opts2, swr_convert_frame, swr_free, swr_init, AVChannelLayout, AVFrame,
-    AVSampleFormat, SwrContext,
+    swr_alloc_set_opts2, swr_convert_frame, swr_free, swr_init, swr_set_matrix, AVChannelLayout,
+    AVFrame, AVSampleFormat, SwrContext,
 };
+use std::collections::HashMap;
 use std::mem::transmute;
 use std::ptr;
 
@@ -12,7 +13,14 @@ pub struct Resample {
     format: AVSampleFormat,
     sample_rate: u32,
     channels: usize,
+    options: HashMap<String, String>,
+    matrix: Option<Vec<f64>>,
     ctx: *mut SwrContext,
+    /// Number of frames converted
+    frames: u64,
+    /// Time spent converting frames
+    #[cfg(feature = "metrics")]
+    elapsed: std::time::Duration,
 }
 
 impl Drop for Resample {
@@ -31,10 +39,41 @@ impl Resample {
             format,
             channels,
             sample_rate: rate,
+            options: HashMap::new(),
+            matrix: None,
             ctx: ptr::null_mut(),
+            frames: 0,
+            #[cfg(feature = "metrics")]
+            elapsed: std::time::Duration::ZERO,
         }
     }
 
+    /// Number of frames converted by this resampler
+    pub fn frames_processed(&self) -> u64 {
+        self.frames
+    }
+
+    /// Total time spent converting frames
+    #[cfg(feature = "metrics")]
+    pub fn processing_time(&self) -> std::time::Duration {
+        self.elapsed
+    }
+
+    /// Set [SwrContext] options which are applied before init (eg. resampler=soxr, dither_method)
+    pub fn with_options(mut self, options: HashMap<String, String>) -> Self {
+        self.options.extend(options);
+        self
+    }
+
+    /// Set a custom channel mixing matrix (see [swr_set_matrix])
+    ///
+    /// Coefficients are ordered by output channel, `matrix[out * in_channels + in]` is the
+    /// weight of input channel `in` in output channel `out`
+    pub fn with_matrix(mut self, matrix: &[f64]) -> Self {
+        self.matrix = Some(matrix.to_vec());
+        self
+    }
+
     unsafe fn setup_swr(&mut self, frame: *mut AVFrame) -> Result<(), Error> {
         if !self.ctx.is_null() {
             return Ok(());
@@ -55,6 +94,35 @@ impl Resample {
         );
         bail_ffmpeg!(ret);
 
+        // a half configured context must not be reused by the next frame
+        if let Err(e) = self.init_swr(frame) {
+            swr_free(&mut self.ctx);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Apply options and the mixing matrix to the allocated context and init it
+    unsafe fn init_swr(&mut self, frame: *mut AVFrame) -> Result<(), Error> {
+        if !self.options.is_empty() {
+            set_opts(self.ctx as *mut libc::c_void, self.options.clone())?;
+        }
+
+        if let Some(matrix) = &self.matrix {
+            let in_channels = (*frame).ch_layout.nb_channels as usize;
+            if matrix.len() != in_channels * self.channels {
+                bail!(
+                    "Mixing matrix must have {} coefficients ({}x{}), got {}",
+                    in_channels * self.channels,
+                    self.channels,
+                    in_channels,
+                    matrix.len()
+                );
+            }
+            let ret = swr_set_matrix(self.ctx, matrix.as_ptr(), in_channels as libc::c_int);
+            bail_ffmpeg!(ret);
+        }
+
         let ret = swr_init(self.ctx);
         bail_ffmpeg!(ret);
 
@@ -62,6 +130,9 @@ impl Resample {
     }
 
     /// Resample an audio frame
+    ///
+    /// Works directly on raw frames so no extra reference is created, the input frame
+    /// is not consumed and the returned frame must be freed by the caller
     pub unsafe fn process_frame(&mut self, frame: *mut AVFrame) -> Result<*mut AVFrame, Error> {
         if !(*frame).hw_frames_ctx.is_null() {
             anyhow::bail!("Hardware frames are not supported in this software re-sampler");
@@ -69,16 +140,171 @@ impl Resample {
         self.setup_swr(frame)?;
 
         let mut out_frame = av_frame_alloc();
+        if out_frame.is_null() {
+            bail!("Failed to allocate frame");
+        }
         av_frame_copy_props(out_frame, frame);
         (*out_frame).sample_rate = self.sample_rate as libc::c_int;
         (*out_frame).format = transmute(self.format);
         av_channel_layout_default(&mut (*out_frame).ch_layout, self.channels as libc::c_int);
 
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
         let ret = swr_convert_frame(self.ctx, out_frame, frame);
+        #[cfg(feature = "metrics")]
+        {
+            self.elapsed += start.elapsed();
+        }
         bail_ffmpeg!(ret, {
             av_frame_free(&mut out_frame);
         });
+        self.frames += 1;
 
         Ok(out_frame)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg_sys_the_third::av_frame_get_buffer;
+
+    #[test]
+    fn resample_with_options() -> Result<(), Error> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_FLT as libc::c_int;
+            (*frame).sample_rate = 48_000;
+            (*frame).nb_samples = 1024;
+            av_channel_layout_default(&mut (*frame).ch_layout, 2);
+            av_frame_get_buffer(frame, 0);
+
+            let mut resample =
+                Resample::new(AVSampleFormat::AV_SAMPLE_FMT_S16, 44_100, 2).with_options(
+                    HashMap::from([("dither_method".to_string(), "triangular".to_string())]),
+                );
+            let mut out_frame = resample.process_frame(frame)?;
+            assert_eq!((*out_frame).sample_rate, 44_100);
+            assert_eq!(
+                (*out_frame).format,
+                AVSampleFormat::AV_SAMPLE_FMT_S16 as libc::c_int
+            );
+
+            av_frame_free(&mut out_frame);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resample_downmix_matrix() -> Result<(), Error> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_FLTP as libc::c_int;
+            (*frame).sample_rate = 48_000;
+            (*frame).nb_samples = 1024;
+            av_channel_layout_default(&mut (*frame).ch_layout, 6);
+            av_frame_get_buffer(frame, 0);
+
+            // each input channel holds a distinct constant
+            let input = [0.1f32, 0.2, 0.3, 0.4, 0.05, 0.15];
+            for (ch, v) in input.iter().enumerate() {
+                let plane = (*frame).data[ch] as *mut f32;
+                std::slice::from_raw_parts_mut(plane, 1024).fill(*v);
+            }
+
+            // FL, FR, FC, LFE, SL, SR => FL, FR
+            #[rustfmt::skip]
+            let matrix = [
+                1.0, 0.0, 0.707, 0.0, 0.5, 0.0,
+                0.0, 1.0, 0.707, 0.0, 0.0, 0.5,
+            ];
+
+            // a matrix of the wrong size fails and leaves no context behind
+            let mut resample = Resample::new(AVSampleFormat::AV_SAMPLE_FMT_FLTP, 48_000, 2)
+                .with_matrix(&matrix[..6]);
+            assert!(resample.process_frame(frame).is_err());
+            assert!(resample.ctx.is_null());
+
+            let mut resample =
+                Resample::new(AVSampleFormat::AV_SAMPLE_FMT_FLTP, 48_000, 2).with_matrix(&matrix);
+            let mut out_frame = resample.process_frame(frame)?;
+            assert_eq!((*out_frame).ch_layout.nb_channels, 2);
+            assert!((*out_frame).nb_samples > 0);
+
+            for (ch, weights) in matrix.chunks(6).enumerate() {
+                let expected: f64 = weights.iter().zip(input).map(|(w, v)| w * v as f64).sum();
+                let plane = (*out_frame).data[ch] as *const f32;
+                let samples = std::slice::from_raw_parts(plane, (*out_frame).nb_samples as usize);
+                for s in samples {
+                    assert!(
+                        (*s as f64 - expected).abs() < 1e-4,
+                        "channel {}: {} != {}",
+                        ch,
+                        s,
+                        expected
+                    );
+                }
+            }
+
+            av_frame_free(&mut out_frame);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resample_frame_counter() -> Result<(), Error> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_S16 as libc::c_int;
+            (*frame).sample_rate = 44_100;
+            (*frame).nb_samples = 1024;
+            av_channel_layout_default(&mut (*frame).ch_layout, 2);
+            av_frame_get_buffer(frame, 0);
+
+            let mut resample = Resample::new(AVSampleFormat::AV_SAMPLE_FMT_FLTP, 48_000, 2);
+            assert_eq!(resample.frames_processed(), 0);
+            for _ in 0..10 {
+                let mut out_frame = resample.process_frame(frame)?;
+                av_frame_free(&mut out_frame);
+            }
+            assert_eq!(resample.frames_processed(), 10);
+            #[cfg(feature = "metrics")]
+            assert!(resample.processing_time() > std::time::Duration::ZERO);
+
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn resample_raw_frame_rate() -> Result<(), Error> {
+        unsafe {
+            let mut frame = av_frame_alloc();
+            (*frame).format = AVSampleFormat::AV_SAMPLE_FMT_FLTP as libc::c_int;
+            (*frame).sample_rate = 48_000;
+            (*frame).nb_samples = 960;
+            av_channel_layout_default(&mut (*frame).ch_layout, 2);
+            av_frame_get_buffer(frame, 0);
+
+            let mut resample = Resample::new(AVSampleFormat::AV_SAMPLE_FMT_FLTP, 24_000, 2);
+            let mut samples = 0;
+            for _ in 0..10 {
+                let mut out_frame = resample.process_frame(frame)?;
+                assert_eq!((*out_frame).sample_rate, 24_000);
+                assert_eq!((*out_frame).ch_layout.nb_channels, 2);
+                // never more than the input duration at the output rate
+                assert!((*out_frame).nb_samples <= 480);
+                samples += (*out_frame).nb_samples;
+                av_frame_free(&mut out_frame);
+            }
+            // only the resampler filter delay is held back
+            assert!(samples <= 4_800);
+            assert!(samples >= 4_800 - 64, "got {} samples", samples);
+
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+}
@@ -1,11 +1,12 @@
 use std::mem::transmute;
 use std::ptr;
 
-use crate::{bail_ffmpeg, rstr};
+use crate::{bail_ffmpeg, rstr, FramePool};
 use anyhow::{bail, Error};
 use ffmpeg_sys_the_third::{
-    av_frame_alloc, av_frame_copy_props, av_frame_free, av_get_pix_fmt_name, sws_freeContext,
-    sws_getContext, sws_scale_frame, AVFrame, AVPixelFormat, SwsContext, SWS_BILINEAR,
+    av_frame_alloc, av_frame_apply_cropping, av_frame_copy_props, av_frame_free, av_frame_ref,
+    av_get_pix_fmt_name, av_pix_fmt_desc_get, sws_freeContext, sws_getContext, sws_scale_frame,
+    AVFrame, AVPixelFormat, SwsContext, AV_FRAME_CROP_UNALIGNED, SWS_BILINEAR,
 };
 use log::trace;
 
@@ -13,7 +14,25 @@ pub struct Scaler {
     width: u16,
     height: u16,
     format: AVPixelFormat,
+    /// Source size/format the context was created for
+    src: (libc::c_int, libc::c_int, libc::c_int),
     ctx: *mut SwsContext,
+    /// Number of times a context was created
+    setups: usize,
+    /// Number of frames converted
+    frames: u64,
+    /// Time spent converting frames
+    #[cfg(feature = "metrics")]
+    elapsed: std::time::Duration,
+}
+
+/// A rectangle in pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
 }
 
 impl Drop for Scaler {
@@ -38,8 +57,39 @@ impl Scaler {
             width: 0,
             height: 0,
             format: AVPixelFormat::AV_PIX_FMT_YUV420P,
+            src: (0, 0, 0),
             ctx: ptr::null_mut(),
+            setups: 0,
+            frames: 0,
+            #[cfg(feature = "metrics")]
+            elapsed: std::time::Duration::ZERO,
+        }
+    }
+
+    /// Number of frames converted by this scaler
+    pub fn frames_processed(&self) -> u64 {
+        self.frames
+    }
+
+    /// Total time spent converting frames
+    #[cfg(feature = "metrics")]
+    pub fn processing_time(&self) -> std::time::Duration {
+        self.elapsed
+    }
+
+    /// Convert a frame with the current context, updating the counters
+    unsafe fn scale_frame(&mut self, dst: *mut AVFrame, src: *const AVFrame) -> libc::c_int {
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let ret = sws_scale_frame(self.ctx, dst, src);
+        #[cfg(feature = "metrics")]
+        {
+            self.elapsed += start.elapsed();
         }
+        if ret >= 0 {
+            self.frames += 1;
+        }
+        ret
     }
 
     unsafe fn setup_scaler(
@@ -49,7 +99,9 @@ impl Scaler {
         height: u16,
         format: AVPixelFormat,
     ) -> Result<(), Error> {
+        let src = ((*frame).width, (*frame).height, (*frame).format);
         if !self.ctx.is_null()
+            && self.src == src
             && self.width == width
             && self.height == height
             && self.format == format
@@ -89,15 +141,18 @@ impl Scaler {
             rstr!(av_get_pix_fmt_name(format))
         );
 
+        self.setups += 1;
+        self.src = src;
         self.width = width;
         self.height = height;
         self.format = format;
         Ok(())
     }
 
+    /// Scale/convert a frame, the input frame is not modified
     pub unsafe fn process_frame(
         &mut self,
-        frame: *mut AVFrame,
+        frame: *const AVFrame,
         width: u16,
         height: u16,
         format: AVPixelFormat,
@@ -114,20 +169,143 @@ impl Scaler {
             av_frame_free(&mut dst_frame);
         });
 
-        let ret = sws_scale_frame(self.ctx, dst_frame, frame);
+        let ret = self.scale_frame(dst_frame, frame);
         bail_ffmpeg!(ret, {
             av_frame_free(&mut dst_frame);
         });
 
         Ok(dst_frame)
     }
+
+    /// Convert the pixel format of a frame without resizing (eg. NV12 to YUV420P after
+    /// downloading a hardware frame)
+    ///
+    /// The context is re-used as long as the input size and format do not change
+    pub unsafe fn convert_format(
+        &mut self,
+        frame: *const AVFrame,
+        format: AVPixelFormat,
+    ) -> Result<*mut AVFrame, Error> {
+        let (width, height) = match (
+            u16::try_from((*frame).width),
+            u16::try_from((*frame).height),
+        ) {
+            (Ok(w), Ok(h)) => (w, h),
+            _ => bail!(
+                "Frame size {}x{} is not supported",
+                (*frame).width,
+                (*frame).height
+            ),
+        };
+        self.process_frame(frame, width, height, format)
+    }
+
+    /// Convert a frame to 8-bit grayscale ([AVPixelFormat::AV_PIX_FMT_GRAY8]), a single
+    /// luma plane
+    pub unsafe fn to_grayscale(
+        &mut self,
+        frame: *const AVFrame,
+        width: u16,
+        height: u16,
+    ) -> Result<*mut AVFrame, Error> {
+        self.process_frame(frame, width, height, AVPixelFormat::AV_PIX_FMT_GRAY8)
+    }
+
+    /// Scale/convert a region of a frame, the input frame is not modified
+    ///
+    /// The region offset must be aligned to the chroma subsampling of the input
+    pub unsafe fn process_region(
+        &mut self,
+        frame: *const AVFrame,
+        src_rect: Rect,
+        width: u16,
+        height: u16,
+        format: AVPixelFormat,
+    ) -> Result<*mut AVFrame, Error> {
+        if !(*frame).hw_frames_ctx.is_null() {
+            bail!("Hardware frames are not supported in this software scalar");
+        }
+        if src_rect.width == 0
+            || src_rect.height == 0
+            || src_rect.x as libc::c_int + src_rect.width as libc::c_int > (*frame).width
+            || src_rect.y as libc::c_int + src_rect.height as libc::c_int > (*frame).height
+        {
+            bail!("Region {:?} is outside of the frame", src_rect);
+        }
+        let desc = av_pix_fmt_desc_get(transmute((*frame).format));
+        if desc.is_null() {
+            bail!("Unknown pixel format {}", (*frame).format);
+        }
+        // chroma planes can only be offset by whole samples
+        let align_x = 1 << (*desc).log2_chroma_w;
+        let align_y = 1 << (*desc).log2_chroma_h;
+        if src_rect.x % align_x != 0 || src_rect.y % align_y != 0 {
+            bail!(
+                "Region offset {}x{} must be a multiple of {}x{}",
+                src_rect.x,
+                src_rect.y,
+                align_x,
+                align_y
+            );
+        }
+
+        // crop a new reference to the source frame
+        let mut view = av_frame_alloc();
+        let ret = av_frame_ref(view, frame);
+        bail_ffmpeg!(ret, {
+            av_frame_free(&mut view);
+        });
+        (*view).crop_left = src_rect.x as usize;
+        (*view).crop_top = src_rect.y as usize;
+        (*view).crop_right = ((*frame).width as usize) - (src_rect.x + src_rect.width) as usize;
+        (*view).crop_bottom = ((*frame).height as usize) - (src_rect.y + src_rect.height) as usize;
+        let ret = av_frame_apply_cropping(view, AV_FRAME_CROP_UNALIGNED as libc::c_int);
+        bail_ffmpeg!(ret, {
+            av_frame_free(&mut view);
+        });
+
+        let ret = self.process_frame(view, width, height, format);
+        av_frame_free(&mut view);
+        ret
+    }
+
+    /// Scale a frame into a frame taken from a [FramePool],
+    /// the returned frame should be given back with [FramePool::release]
+    pub unsafe fn process_frame_pooled(
+        &mut self,
+        frame: *const AVFrame,
+        width: u16,
+        height: u16,
+        format: AVPixelFormat,
+        pool: &mut FramePool,
+    ) -> Result<*mut AVFrame, Error> {
+        if !(*frame).hw_frames_ctx.is_null() {
+            bail!("Hardware frames are not supported in this software scalar");
+        }
+
+        self.setup_scaler(frame, width, height, format)?;
+
+        let dst_frame = pool.get_video(width as libc::c_int, height as libc::c_int, format)?;
+        let ret = av_frame_copy_props(dst_frame, frame);
+        bail_ffmpeg!(ret, {
+            pool.release(dst_frame);
+        });
+
+        let ret = self.scale_frame(dst_frame, frame);
+        bail_ffmpeg!(ret, {
+            pool.release(dst_frame);
+        });
+
+        Ok(dst_frame)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::generate_test_frame;
-    use ffmpeg_sys_the_third::{av_frame_free, AVPixelFormat};
+    use crate::{frame_plane, frame_plane_mut, write_frame_raw};
+    use ffmpeg_sys_the_third::{av_frame_free, av_frame_get_buffer, AVPixelFormat};
 
     #[test]
     fn scale_rgb24_yuv420() {
@@ -162,4 +340,190 @@ mod tests {
             av_frame_free(&mut frame);
         }
     }
+
+    #[test]
+    fn scale_region() -> Result<(), Error> {
+        unsafe {
+            let mut src_frame = generate_test_frame();
+            let mut frame = Scaler::new().process_frame(
+                src_frame,
+                512,
+                512,
+                AVPixelFormat::AV_PIX_FMT_YUV420P,
+            )?;
+
+            let mut scaler = Scaler::new();
+            for (x, y) in [(0, 0), (256, 256)] {
+                let rect = Rect {
+                    x,
+                    y,
+                    width: 256,
+                    height: 256,
+                };
+                let mut region = scaler.process_region(
+                    frame,
+                    rect,
+                    128,
+                    128,
+                    AVPixelFormat::AV_PIX_FMT_YUV420P,
+                )?;
+
+                // copy the region into a new frame and scale that
+                let mut cropped = av_frame_alloc();
+                (*cropped).width = 256;
+                (*cropped).height = 256;
+                (*cropped).format = AVPixelFormat::AV_PIX_FMT_YUV420P as libc::c_int;
+                av_frame_get_buffer(cropped, 0);
+                for plane in 0..3 {
+                    let shift = if plane == 0 { 0 } else { 1 };
+                    let (px, py, size) =
+                        ((x >> shift) as usize, (y >> shift) as usize, 256 >> shift);
+                    let src_stride = (*frame).linesize[plane] as usize;
+                    let dst_stride = (*cropped).linesize[plane] as usize;
+                    let src = frame_plane(frame, plane)?;
+                    let dst = frame_plane_mut(cropped, plane)?;
+                    for line in 0..size {
+                        let s = (py + line) * src_stride + px;
+                        dst[line * dst_stride..line * dst_stride + size]
+                            .copy_from_slice(&src[s..s + size]);
+                    }
+                }
+                let mut expected = Scaler::new().process_frame(
+                    cropped,
+                    128,
+                    128,
+                    AVPixelFormat::AV_PIX_FMT_YUV420P,
+                )?;
+
+                assert_eq!((*region).width, 128);
+                assert_eq!((*region).height, 128);
+                for plane in 0..3 {
+                    let size = if plane == 0 { 128 } else { 64 };
+                    for line in 0..size {
+                        let a = (*region).linesize[plane] as usize * line;
+                        let b = (*expected).linesize[plane] as usize * line;
+                        assert_eq!(
+                            frame_plane(region, plane)?[a..a + size],
+                            frame_plane(expected, plane)?[b..b + size]
+                        );
+                    }
+                }
+                av_frame_free(&mut expected);
+                av_frame_free(&mut cropped);
+                av_frame_free(&mut region);
+            }
+
+            // offsets must be aligned to the chroma planes
+            let rect = Rect {
+                x: 1,
+                y: 0,
+                width: 16,
+                height: 16,
+            };
+            assert!(scaler
+                .process_region(frame, rect, 16, 16, AVPixelFormat::AV_PIX_FMT_YUV420P)
+                .is_err());
+
+            av_frame_free(&mut frame);
+            av_frame_free(&mut src_frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn convert_format_reuses_context() -> Result<(), Error> {
+        unsafe {
+            let mut src_frame = generate_test_frame();
+            let mut nv12 =
+                Scaler::new().process_frame(src_frame, 256, 256, AVPixelFormat::AV_PIX_FMT_NV12)?;
+
+            let mut scaler = Scaler::new();
+            for _ in 0..10 {
+                let mut out = scaler.convert_format(nv12, AVPixelFormat::AV_PIX_FMT_YUV420P)?;
+                assert_eq!((*out).width, 256);
+                assert_eq!((*out).height, 256);
+                assert_eq!(
+                    (*out).format,
+                    AVPixelFormat::AV_PIX_FMT_YUV420P as libc::c_int
+                );
+                av_frame_free(&mut out);
+            }
+            assert_eq!(scaler.setups, 1);
+            assert_eq!(scaler.frames_processed(), 10);
+
+            // a new source format needs a new context
+            let mut out = scaler.convert_format(src_frame, AVPixelFormat::AV_PIX_FMT_YUV420P)?;
+            assert_eq!((*out).width, 1024);
+            assert_eq!(scaler.setups, 2);
+
+            av_frame_free(&mut out);
+            av_frame_free(&mut nv12);
+            av_frame_free(&mut src_frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn scale_grayscale() -> Result<(), Error> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let mut scaler = Scaler::new();
+            let mut gray = scaler.to_grayscale(frame, 512, 512)?;
+            assert_eq!((*gray).width, 512);
+            assert_eq!((*gray).height, 512);
+            assert_eq!(
+                (*gray).format,
+                AVPixelFormat::AV_PIX_FMT_GRAY8 as libc::c_int
+            );
+            assert!((*gray).data[1].is_null());
+            assert!(frame_plane(gray, 0)?.len() >= 512 * 512);
+            assert!(frame_plane(gray, 1).is_err());
+
+            let mut buf = Vec::new();
+            assert_eq!(write_frame_raw(gray, &mut buf)?, 512 * 512);
+            av_frame_free(&mut gray);
+
+            // 16-bit output has a single plane of 2 bytes per pixel
+            let mut gray16 =
+                scaler.process_frame(frame, 256, 256, AVPixelFormat::AV_PIX_FMT_GRAY16LE)?;
+            assert!((*gray16).data[1].is_null());
+            let mut buf = Vec::new();
+            assert_eq!(write_frame_raw(gray16, &mut buf)?, 256 * 256 * 2);
+            av_frame_free(&mut gray16);
+
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn scale_const_frame() -> Result<(), Error> {
+        unsafe {
+            let mut frame = generate_test_frame();
+            let expected = frame_plane(frame, 0)?.to_vec();
+
+            let src: *const AVFrame = frame;
+            let mut scaler = Scaler::new();
+            let mut out_frame =
+                scaler.process_frame(src, 256, 256, AVPixelFormat::AV_PIX_FMT_YUV420P)?;
+            assert_eq!((*out_frame).width, 256);
+            assert_eq!((*out_frame).height, 256);
+            assert_eq!(
+                (*out_frame).format,
+                AVPixelFormat::AV_PIX_FMT_YUV420P as libc::c_int
+            );
+
+            // the input frame is left untouched
+            assert_eq!((*src).width, 1024);
+            assert_eq!(
+                (*src).format,
+                AVPixelFormat::AV_PIX_FMT_RGB24 as libc::c_int
+            );
+            assert_eq!(frame_plane(src, 0)?, expected.as_slice());
+
+            av_frame_free(&mut out_frame);
+            av_frame_free(&mut frame);
+        }
+        Ok(())
+    }
 }
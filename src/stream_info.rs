@@ -1,14 +1,16 @@
-use crate::{format_time, rstr};
+use crate::{estimate_output_size, format_time, rstr, Encoder};
 #[cfg(feature = "avformat_version_greater_than_60_19")]
 use ffmpeg_sys_the_third::AVStreamGroup;
 use ffmpeg_sys_the_third::{
-    av_get_pix_fmt_name, av_get_sample_fmt_name, avcodec_get_name, AVMediaType, AVStream,
+    av_get_pix_fmt_name, av_get_sample_fmt_name, avcodec_get_name, AVFieldOrder, AVMediaType,
+    AVStream, AV_DISPOSITION_ATTACHED_PIC,
 };
 
 use std::fmt::{Display, Formatter};
 use std::intrinsics::transmute;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DemuxerInfo {
     /// Average bitrate of the media
     pub bitrate: usize,
@@ -28,7 +30,7 @@ impl DemuxerInfo {
     pub fn best_stream(&self, t: StreamType) -> Option<&StreamInfo> {
         self.streams
             .iter()
-            .filter(|a| a.stream_type == t)
+            .filter(|a| a.stream_type == t && !a.is_attached_pic())
             .reduce(|acc, channel| {
                 if channel.best_metric() > acc.best_metric() {
                     channel
@@ -38,6 +40,19 @@ impl DemuxerInfo {
             })
     }
 
+    /// Set [StreamInfo::is_best] on the best video, audio and subtitle stream
+    ///
+    /// Ties are resolved by picking the stream with the lowest index
+    pub fn mark_best(&mut self) {
+        let best: Vec<usize> = [StreamType::Video, StreamType::Audio, StreamType::Subtitle]
+            .into_iter()
+            .filter_map(|t| self.best_stream(t).map(|s| s.index))
+            .collect();
+        for s in self.streams.iter_mut() {
+            s.is_best = best.contains(&s.index);
+        }
+    }
+
     pub fn best_video(&self) -> Option<&StreamInfo> {
         self.best_stream(StreamType::Video)
     }
@@ -50,6 +65,14 @@ impl DemuxerInfo {
         self.best_stream(StreamType::Subtitle)
     }
 
+    /// Estimate the output size in bytes of all encoders over the duration of the media
+    pub fn estimate_output_size(&self, encoders: &[&Encoder]) -> u64 {
+        encoders
+            .iter()
+            .map(|e| estimate_output_size(e.estimated_bitrate(), self.duration))
+            .sum()
+    }
+
     pub unsafe fn is_best_stream(&self, stream: *mut AVStream) -> bool {
         match (*(*stream).codecpar).codec_type {
             AVMediaType::AVMEDIA_TYPE_VIDEO => {
@@ -91,10 +114,13 @@ impl Display for DemuxerInfo {
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum StreamType {
     Video,
     Audio,
     Subtitle,
+    /// Data / timed metadata (KLV, ID3)
+    Data,
 }
 
 impl Display for StreamType {
@@ -106,12 +132,51 @@ impl Display for StreamType {
                 StreamType::Video => "video",
                 StreamType::Audio => "audio",
                 StreamType::Subtitle => "subtitle",
+                StreamType::Data => "data",
             }
         )
     }
 }
 
+/// Video field order, see [AVFieldOrder]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FieldOrder {
+    #[default]
+    Unknown,
+    Progressive,
+    /// Top field coded and displayed first
+    TopFirst,
+    /// Bottom field coded and displayed first
+    BottomFirst,
+    /// Top field coded first, bottom displayed first
+    TopCodedBottomDisplayed,
+    /// Bottom field coded first, top displayed first
+    BottomCodedTopDisplayed,
+}
+
+impl FieldOrder {
+    /// Check if the stream is known to be interlaced
+    pub fn is_interlaced(&self) -> bool {
+        !matches!(self, FieldOrder::Unknown | FieldOrder::Progressive)
+    }
+}
+
+impl From<AVFieldOrder> for FieldOrder {
+    fn from(value: AVFieldOrder) -> Self {
+        match value {
+            AVFieldOrder::AV_FIELD_PROGRESSIVE => FieldOrder::Progressive,
+            AVFieldOrder::AV_FIELD_TT => FieldOrder::TopFirst,
+            AVFieldOrder::AV_FIELD_BB => FieldOrder::BottomFirst,
+            AVFieldOrder::AV_FIELD_TB => FieldOrder::TopCodedBottomDisplayed,
+            AVFieldOrder::AV_FIELD_BT => FieldOrder::BottomCodedTopDisplayed,
+            _ => FieldOrder::Unknown,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StreamInfo {
     /// Stream index
     pub index: usize,
@@ -119,6 +184,12 @@ pub struct StreamInfo {
     pub stream_type: StreamType,
     /// Stream codec
     pub codec: isize,
+    /// Codec tag (fourcc)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub codec_tag: u32,
+    /// Stream disposition flags (AV_DISPOSITION_*)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub disposition: i32,
     /// Pixel format / Sample format
     pub format: isize,
 
@@ -128,13 +199,24 @@ pub struct StreamInfo {
     pub height: usize,
     /// Video FPS
     pub fps: f32,
+    /// Video field order
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub field_order: FieldOrder,
+    /// Video display rotation in degrees (counterclockwise) from the display matrix,
+    /// players rotate the decoded frames by this angle
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rotation: f32,
 
     /// Audio sample rate
     pub sample_rate: usize,
     /// Subtitle / Audio language
     pub language: String,
+    /// This is the best stream of its type, set by [DemuxerInfo::mark_best]
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub is_best: bool,
 
     // private stream pointer
+    #[cfg_attr(feature = "serde", serde(skip, default = "std::ptr::null_mut"))]
     pub(crate) stream: *mut AVStream,
 }
 
@@ -146,8 +228,31 @@ impl StreamInfo {
             StreamType::Video => self.width as f32 * self.height as f32 * self.fps,
             StreamType::Audio => self.sample_rate as f32,
             StreamType::Subtitle => 999. - self.index as f32,
+            StreamType::Data => 999. - self.index as f32,
         }
     }
+
+    /// Check if the stream is an attached picture (eg. album art) rather than a video track
+    pub fn is_attached_pic(&self) -> bool {
+        self.disposition & AV_DISPOSITION_ATTACHED_PIC as i32 != 0
+    }
+
+    /// Render the codec tag as a fourcc string, eg. `avc1`
+    ///
+    /// Non-printable characters are rendered as `[n]`
+    pub fn codec_tag_string(&self) -> String {
+        self.codec_tag
+            .to_le_bytes()
+            .iter()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || b" .-_".contains(c) {
+                    (*c as char).to_string()
+                } else {
+                    format!("[{}]", c)
+                }
+            })
+            .collect()
+    }
 }
 
 impl Display for StreamInfo {
@@ -184,12 +289,31 @@ impl Display for StreamInfo {
                 "{} #{}: codec={},lang={}",
                 self.stream_type, self.index, codec_name, self.language
             ),
+            StreamType::Data => write!(
+                f,
+                "{} #{}: codec={}",
+                self.stream_type, self.index, codec_name
+            ),
         }
     }
 }
 
+/// File attached to the container (eg. fonts in mkv)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    /// Stream index
+    pub index: usize,
+    /// Attached file name
+    pub filename: String,
+    /// Attached file mime-type
+    pub mime_type: String,
+    /// File contents
+    pub data: Vec<u8>,
+}
+
 #[cfg(feature = "avformat_version_greater_than_60_19")]
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum StreamGroupType {
     TileGrid {
         tiles: usize,
@@ -202,13 +326,48 @@ pub enum StreamGroupType {
 
 #[cfg(feature = "avformat_version_greater_than_60_19")]
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StreamGroupInfo {
     pub index: usize,
     pub group_type: StreamGroupType,
 
     // private pointer
+    #[cfg_attr(feature = "serde", serde(skip, default = "std::ptr::null_mut"))]
     pub(crate) group: *mut AVStreamGroup,
 }
 
 #[cfg(feature = "avformat_version_greater_than_60_19")]
 unsafe impl Send for StreamGroupInfo {}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serde_round_trip() -> anyhow::Result<()> {
+        unsafe {
+            let path = "test_output/test_serde.mp4";
+            crate::generate_test_video(path, 10, 10)?;
+            let mut demux = crate::Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+
+            let json = serde_json::to_string(&info)?;
+            let info2: DemuxerInfo = serde_json::from_str(&json)?;
+            assert_eq!(info.duration, info2.duration);
+            assert_eq!(info.streams.len(), info2.streams.len());
+            assert_eq!(json, serde_json::to_string(&info2)?);
+
+            // output from older versions has no field_order
+            let mut value = serde_json::to_value(&info)?;
+            for stream in value["streams"].as_array_mut().unwrap() {
+                stream.as_object_mut().unwrap().remove("field_order");
+            }
+            let info3: DemuxerInfo = serde_json::from_value(value)?;
+            assert!(info3
+                .streams
+                .iter()
+                .all(|s| s.field_order == FieldOrder::Unknown));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+use crate::{bail_ffmpeg, plane_size, rstr, Encoder, Muxer, StreamInfo, StreamType};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_frame_alloc, av_frame_free, av_frame_get_buffer, av_mallocz, av_packet_free, av_rescale_q,
+    avcodec_alloc_context3, avcodec_decode_subtitle2, avcodec_descriptor_get, avcodec_find_decoder,
+    avcodec_free_context, avcodec_get_name, avcodec_open2, avcodec_parameters_to_context,
+    avsubtitle_free, AVCodecContext, AVCodecID, AVFrame, AVPacket, AVPixelFormat, AVRational,
+    AVStream, AVSubtitle, AVSubtitleType, AV_CODEC_PROP_BITMAP_SUB, AV_CODEC_PROP_TEXT_SUB,
+    AV_NOPTS_VALUE, AV_TIME_BASE_Q,
+};
+use std::{mem, ptr, slice};
+
+/// Convert a subtitle stream to another subtitle codec (eg. SRT to WebVTT/ASS)
+///
+/// Text subtitles can only be converted to other text formats and bitmap subtitles to other
+/// bitmap formats, rendering text to bitmaps is not supported
+pub struct SubtitleTranscoder {
+    decoder: *mut AVCodecContext,
+    encoder: Encoder,
+    in_index: i32,
+    in_time_base: AVRational,
+    out_index: Option<i32>,
+}
+
+impl Drop for SubtitleTranscoder {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.decoder.is_null() {
+                avcodec_free_context(&mut self.decoder);
+            }
+        }
+    }
+}
+
+impl SubtitleTranscoder {
+    /// Create a transcoder for a subtitle stream of a [crate::Demuxer] to `codec`
+    pub unsafe fn new(in_stream: &StreamInfo, codec: AVCodecID) -> Result<Self> {
+        if in_stream.stream_type != StreamType::Subtitle {
+            bail!("Stream {} is not a subtitle stream", in_stream.index);
+        }
+        if in_stream.stream.is_null() {
+            bail!("Stream {} is not attached to a demuxer", in_stream.index);
+        }
+        let stream = in_stream.stream;
+        let in_codec = (*(*stream).codecpar).codec_id;
+        let text_in = Self::has_prop(in_codec, AV_CODEC_PROP_TEXT_SUB);
+        let text_out = Self::has_prop(codec, AV_CODEC_PROP_TEXT_SUB);
+        if text_in != text_out {
+            bail!(
+                "Cannot convert {} subtitles to {}, text and bitmap subtitles are not compatible",
+                rstr!(avcodec_get_name(in_codec)),
+                rstr!(avcodec_get_name(codec))
+            );
+        }
+
+        let encoder = Encoder::new(codec)?;
+        let mut decoder = Self::open_decoder(stream)?;
+        let encoder = match Self::open_encoder(encoder, decoder) {
+            Ok(e) => e,
+            Err(e) => {
+                avcodec_free_context(&mut decoder);
+                return Err(e);
+            }
+        };
+        Ok(Self {
+            decoder,
+            encoder,
+            in_index: (*stream).index,
+            in_time_base: (*stream).time_base,
+            out_index: None,
+        })
+    }
+
+    unsafe fn has_prop(codec: AVCodecID, prop: u32) -> bool {
+        let desc = avcodec_descriptor_get(codec);
+        !desc.is_null() && (*desc).props & prop as libc::c_int != 0
+    }
+
+    unsafe fn open_decoder(stream: *mut AVStream) -> Result<*mut AVCodecContext> {
+        let codec = avcodec_find_decoder((*(*stream).codecpar).codec_id);
+        if codec.is_null() {
+            bail!(
+                "Failed to find decoder: {}",
+                rstr!(avcodec_get_name((*(*stream).codecpar).codec_id))
+            );
+        }
+        let mut ctx = avcodec_alloc_context3(codec);
+        if ctx.is_null() {
+            bail!("Failed to alloc context");
+        }
+        let ret = avcodec_parameters_to_context(ctx, (*stream).codecpar);
+        bail_ffmpeg!(ret, "Failed to copy codec parameters to context", {
+            avcodec_free_context(&mut ctx);
+        });
+        // used to convert the packet timestamps to the subtitle pts
+        (*ctx).pkt_timebase = (*stream).time_base;
+        let ret = avcodec_open2(ctx, codec, ptr::null_mut());
+        bail_ffmpeg!(ret, "Failed to open subtitle decoder", {
+            avcodec_free_context(&mut ctx);
+        });
+        Ok(ctx)
+    }
+
+    unsafe fn open_encoder(encoder: Encoder, decoder: *const AVCodecContext) -> Result<Encoder> {
+        // text encoders need the ASS header produced by the decoder
+        let header = if (*decoder).subtitle_header.is_null() {
+            ptr::null_mut()
+        } else {
+            let size = (*decoder).subtitle_header_size.max(0) as usize;
+            let header = av_mallocz(size + 1) as *mut u8;
+            if header.is_null() {
+                bail!("Failed to allocate subtitle header");
+            }
+            ptr::copy_nonoverlapping((*decoder).subtitle_header, header, size);
+            header
+        };
+        encoder
+            .with_time_base(AV_TIME_BASE_Q)?
+            .with_width((*decoder).width)
+            .with_height((*decoder).height)
+            .with_options(|ctx| {
+                (*ctx).subtitle_header = header;
+                (*ctx).subtitle_header_size = (*decoder).subtitle_header_size;
+            })
+            .open(None)
+    }
+
+    /// Get the subtitle encoder
+    pub fn encoder(&self) -> &Encoder {
+        &self.encoder
+    }
+
+    /// Add the output stream to the muxer, must be called before [Muxer::open]
+    pub unsafe fn add_to_muxer(&mut self, muxer: &mut Muxer) -> Result<*mut AVStream> {
+        let stream = muxer.add_stream_encoder(&self.encoder)?;
+        self.out_index = Some((*stream).index);
+        Ok(stream)
+    }
+
+    /// Decode and re-encode a subtitle packet, the packet is not consumed
+    ///
+    /// Packets of other streams are ignored, returns [None] when no subtitle was decoded
+    pub unsafe fn transcode_packet(&mut self, pkt: *mut AVPacket) -> Result<Option<*mut AVPacket>> {
+        if pkt.is_null() || (*pkt).stream_index != self.in_index {
+            return Ok(None);
+        }
+        let mut sub: AVSubtitle = mem::zeroed();
+        let mut got_sub = 0;
+        let ret = avcodec_decode_subtitle2(self.decoder, &mut sub, &mut got_sub, pkt);
+        bail_ffmpeg!(ret, "Failed to decode subtitle");
+        if got_sub == 0 {
+            return Ok(None);
+        }
+
+        // move the display start into the pts so the packet starts with the subtitle
+        let ms = AVRational { num: 1, den: 1000 };
+        if sub.pts == AV_NOPTS_VALUE {
+            sub.pts = av_rescale_q((*pkt).pts, self.in_time_base, AV_TIME_BASE_Q);
+        }
+        sub.pts += av_rescale_q(sub.start_display_time as i64, ms, AV_TIME_BASE_Q);
+        sub.end_display_time -= sub.start_display_time;
+        sub.start_display_time = 0;
+
+        let ret = self.encoder.encode_subtitle(&sub);
+        avsubtitle_free(&mut sub);
+        let out = ret?;
+        if let Some(idx) = self.out_index {
+            (*out).stream_index = idx;
+        }
+        Ok(Some(out))
+    }
+
+    /// Transcode a packet and write it to the muxer, the packet is not consumed
+    pub unsafe fn write_packet(&mut self, muxer: &mut Muxer, pkt: *mut AVPacket) -> Result<()> {
+        if self.out_index.is_none() {
+            bail!("Subtitle stream was not added to the muxer");
+        }
+        if let Some(mut out) = self.transcode_packet(pkt)? {
+            let ret = muxer.write_packet(out);
+            av_packet_free(&mut out);
+            ret?;
+        }
+        Ok(())
+    }
+}
+
+/// Render the bitmap rects of a decoded subtitle (eg. PGS/DVB/DVD) into an RGBA frame of
+/// `width`x`height`, each rect is drawn at its position using its palette and the rest of
+/// the frame is transparent
+///
+/// Text rects are ignored, the returned frame must be freed by the caller
+pub unsafe fn subtitle_to_rgba(
+    sub: *const AVSubtitle,
+    width: i32,
+    height: i32,
+) -> Result<*mut AVFrame> {
+    if width <= 0 || height <= 0 {
+        bail!("Invalid overlay size {}x{}", width, height);
+    }
+    let mut frame = av_frame_alloc();
+    if frame.is_null() {
+        bail!("Failed to allocate frame");
+    }
+    (*frame).width = width;
+    (*frame).height = height;
+    (*frame).format = AVPixelFormat::AV_PIX_FMT_RGBA as libc::c_int;
+    let ret = av_frame_get_buffer(frame, 0);
+    bail_ffmpeg!(ret, "Failed to allocate overlay", {
+        av_frame_free(&mut frame);
+    });
+    let stride = (*frame).linesize[0] as usize;
+    let dst = match plane_size((*frame).linesize[0], height) {
+        Ok(size) => slice::from_raw_parts_mut((*frame).data[0], size),
+        Err(e) => {
+            av_frame_free(&mut frame);
+            return Err(e);
+        }
+    };
+    dst.fill(0);
+    (*frame).pts = (*sub).pts;
+
+    for i in 0..(*sub).num_rects as usize {
+        let rect = *(*sub).rects.add(i);
+        if (*rect).type_ != AVSubtitleType::SUBTITLE_BITMAP
+            || (*rect).data[0].is_null()
+            || (*rect).data[1].is_null()
+        {
+            continue;
+        }
+        // palette entries are native endian 0xAARRGGBB
+        let palette = slice::from_raw_parts(
+            (*rect).data[1] as *const u32,
+            (*rect).nb_colors.max(0) as usize,
+        );
+        let src_stride = (*rect).linesize[0] as usize;
+        for y in 0..(*rect).h.max(0) {
+            let dy = (*rect).y + y;
+            if dy < 0 || dy >= height {
+                continue;
+            }
+            let line = (*rect).data[0].add(y as usize * src_stride);
+            for x in 0..(*rect).w.max(0) {
+                let dx = (*rect).x + x;
+                if dx < 0 || dx >= width {
+                    continue;
+                }
+                let argb = match palette.get(*line.add(x as usize) as usize) {
+                    Some(c) if c >> 24 != 0 => *c,
+                    _ => continue,
+                };
+                let offset = dy as usize * stride + dx as usize * 4;
+                dst[offset..offset + 4].copy_from_slice(&[
+                    (argb >> 16) as u8,
+                    (argb >> 8) as u8,
+                    argb as u8,
+                    (argb >> 24) as u8,
+                ]);
+            }
+        }
+    }
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Demuxer, Transcoder};
+
+    #[test]
+    fn srt_to_webvtt() -> Result<()> {
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let srt = "test_output/test_subtitle.srt";
+            let mkv = "test_output/test_subtitle_srt.mkv";
+            let out = "test_output/test_subtitle.vtt";
+            std::fs::write(
+                srt,
+                "1\n00:00:01,000 --> 00:00:02,500\nHello\n\n2\n00:00:03,000 --> 00:00:04,000\nWorld\n",
+            )?;
+
+            // SRT in a matroska container
+            let mut remux = Transcoder::new(srt, mkv)?;
+            let info = remux.prepare()?;
+            for s in info.streams {
+                remux.copy_stream(s)?;
+            }
+            remux.run(None)?;
+
+            let mut demux = Demuxer::new(mkv)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_subtitle().expect("no subtitle stream");
+            assert_eq!(stream.codec, AVCodecID::AV_CODEC_ID_SUBRIP as isize);
+
+            let mut transcoder = SubtitleTranscoder::new(stream, AVCodecID::AV_CODEC_ID_WEBVTT)?;
+            let mut muxer = Muxer::builder().with_output_path(out, None)?.build()?;
+            transcoder.add_to_muxer(&mut muxer)?;
+            muxer.open(None)?;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                let ret = transcoder.write_packet(&mut muxer, pkt);
+                av_packet_free(&mut pkt);
+                ret?;
+            }
+            muxer.close()?;
+
+            let mut demux = Demuxer::new(out)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_subtitle().expect("no subtitle stream");
+            assert_eq!(stream.codec, AVCodecID::AV_CODEC_ID_WEBVTT as isize);
+
+            let text = std::fs::read_to_string(out)?;
+            assert!(text.starts_with("WEBVTT"));
+            assert!(text.contains("00:01.000 --> 00:02.500"));
+            assert!(text.contains("Hello"));
+            assert!(text.contains("World"));
+
+            // text subtitles cannot be rendered to bitmaps
+            let mut demux = Demuxer::new(mkv)?;
+            let info = demux.probe_input()?;
+            let stream = info.best_subtitle().expect("no subtitle stream");
+            assert!(SubtitleTranscoder::new(stream, AVCodecID::AV_CODEC_ID_DVD_SUBTITLE).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bitmap_subtitle_overlay() -> Result<()> {
+        use ffmpeg_sys_the_third::{av_malloc, AVSubtitleRect};
+
+        unsafe {
+            let (width, height) = (320, 240);
+            let (rect_w, rect_h) = (64, 16);
+
+            // a white box on a transparent background
+            let rect = av_mallocz(mem::size_of::<AVSubtitleRect>()) as *mut AVSubtitleRect;
+            (*rect).type_ = AVSubtitleType::SUBTITLE_BITMAP;
+            (*rect).x = 100;
+            (*rect).y = 200;
+            (*rect).w = rect_w;
+            (*rect).h = rect_h;
+            (*rect).nb_colors = 4;
+            (*rect).linesize[0] = rect_w;
+            (*rect).data[0] = av_malloc((rect_w * rect_h) as usize) as *mut u8;
+            slice::from_raw_parts_mut((*rect).data[0], (rect_w * rect_h) as usize).fill(1);
+            (*rect).data[1] = av_mallocz(4 * 4) as *mut u8;
+            let palette = slice::from_raw_parts_mut((*rect).data[1] as *mut u32, 4);
+            palette.copy_from_slice(&[0x0000_0000, 0xffff_ffff, 0xff00_0000, 0xff80_8080]);
+            let mut sub: AVSubtitle = mem::zeroed();
+            sub.end_display_time = 1000;
+            sub.num_rects = 1;
+            sub.rects = av_mallocz(mem::size_of::<*mut AVSubtitleRect>()) as *mut _;
+            *sub.rects = rect;
+
+            let mut encoder = Encoder::new(AVCodecID::AV_CODEC_ID_DVD_SUBTITLE)?
+                .with_width(width)
+                .with_height(height)
+                .open(None)?;
+            let mut pkt = encoder.encode_subtitle(&sub)?;
+            avsubtitle_free(&mut sub);
+
+            // decode the bitmap subtitle again
+            let codec = avcodec_find_decoder(AVCodecID::AV_CODEC_ID_DVD_SUBTITLE);
+            let mut ctx = avcodec_alloc_context3(codec);
+            (*ctx).width = width;
+            (*ctx).height = height;
+            let ret = avcodec_open2(ctx, codec, ptr::null_mut());
+            bail_ffmpeg!(ret);
+            let mut decoded: AVSubtitle = mem::zeroed();
+            let mut got_sub = 0;
+            let ret = avcodec_decode_subtitle2(ctx, &mut decoded, &mut got_sub, pkt);
+            av_packet_free(&mut pkt);
+            avcodec_free_context(&mut ctx);
+            bail_ffmpeg!(ret);
+            assert_eq!(got_sub, 1);
+            assert!(decoded.num_rects > 0);
+
+            let mut overlay = subtitle_to_rgba(&decoded, width, height)?;
+            avsubtitle_free(&mut decoded);
+            assert_eq!((*overlay).width, width);
+            assert_eq!(
+                (*overlay).format,
+                AVPixelFormat::AV_PIX_FMT_RGBA as libc::c_int
+            );
+            let stride = (*overlay).linesize[0] as usize;
+            let data = slice::from_raw_parts((*overlay).data[0], stride * height as usize);
+            let alpha = |x: usize, y: usize| data[y * stride + x * 4 + 3];
+            assert_eq!(alpha(0, 0), 0);
+            assert_ne!(
+                alpha(100 + rect_w as usize / 2, 200 + rect_h as usize / 2),
+                0
+            );
+            av_frame_free(&mut overlay);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{bail_ffmpeg, plane_size, Decoder, Demuxer, Scaler};
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_frame_alloc, av_frame_free, av_frame_get_buffer, av_packet_free, avcodec_flush_buffers,
+    AVDiscard, AVFrame, AVPixelFormat,
+};
+use std::{ptr, slice};
+
+/// Generate a contact sheet of `count` evenly spaced thumbnails from the best video
+/// stream of `input`, composited into a grid of `tile_w`x`tile_h` tiles.
+///
+/// Only keyframes are decoded, the returned frame is RGB24 and must be freed by the caller
+pub unsafe fn generate_contact_sheet(
+    input: &str,
+    count: usize,
+    tile_w: u16,
+    tile_h: u16,
+) -> Result<*mut AVFrame> {
+    if count == 0 || tile_w == 0 || tile_h == 0 {
+        bail!("Invalid contact sheet size");
+    }
+    let mut demux = Demuxer::new(input)?;
+    let info = demux.probe_input()?;
+    let video = if let Some(v) = info.best_video() {
+        v
+    } else {
+        bail!("No video stream in input");
+    };
+    let stream_index = video.index as i32;
+
+    let mut decoder = Decoder::new();
+    let decoder_ctx = decoder.setup_decoder(video, None)?.context;
+    (*decoder_ctx).skip_frame = AVDiscard::AVDISCARD_NONKEY;
+
+    let cols = (count as f32).sqrt().ceil() as usize;
+    let rows = count.div_ceil(cols);
+    let sheet_w = cols
+        .checked_mul(tile_w as usize)
+        .and_then(|w| libc::c_int::try_from(w).ok());
+    let sheet_h = rows
+        .checked_mul(tile_h as usize)
+        .and_then(|h| libc::c_int::try_from(h).ok());
+    let (sheet_w, sheet_h) = if let (Some(w), Some(h)) = (sheet_w, sheet_h) {
+        (w, h)
+    } else {
+        bail!("Contact sheet is too large");
+    };
+    let mut sheet = av_frame_alloc();
+    (*sheet).width = sheet_w;
+    (*sheet).height = sheet_h;
+    (*sheet).format = AVPixelFormat::AV_PIX_FMT_RGB24 as libc::c_int;
+    let ret = av_frame_get_buffer(sheet, 0);
+    bail_ffmpeg!(ret, "Failed to allocate contact sheet", {
+        av_frame_free(&mut sheet);
+    });
+    let size = match plane_size((*sheet).linesize[0], (*sheet).height) {
+        Ok(s) => s,
+        Err(e) => {
+            av_frame_free(&mut sheet);
+            return Err(e);
+        }
+    };
+    slice::from_raw_parts_mut((*sheet).data[0], size).fill(0);
+
+    let positions: Vec<f32> = (0..count)
+        .map(|i| info.duration * (i as f32 + 0.5) / count as f32)
+        .collect();
+    if let Err(e) = draw_tiles(
+        &mut demux,
+        &mut decoder,
+        stream_index,
+        &positions,
+        sheet,
+        cols,
+        tile_w,
+        tile_h,
+    ) {
+        av_frame_free(&mut sheet);
+        return Err(e);
+    }
+    Ok(sheet)
+}
+
+/// Decode a keyframe at each position and copy it into the grid
+#[allow(clippy::too_many_arguments)]
+unsafe fn draw_tiles(
+    demux: &mut Demuxer,
+    decoder: &mut Decoder,
+    stream_index: i32,
+    positions: &[f32],
+    sheet: *mut AVFrame,
+    cols: usize,
+    tile_w: u16,
+    tile_h: u16,
+) -> Result<()> {
+    let mut scaler = Scaler::new();
+    let sheet_stride = (*sheet).linesize[0] as usize;
+    let row_len = tile_w as usize * 3;
+    for (i, pos) in positions.iter().enumerate() {
+        let mut frame = if let Some(f) = next_keyframe(demux, decoder, stream_index, *pos)? {
+            f
+        } else {
+            continue;
+        };
+        let tile = scaler.process_frame(frame, tile_w, tile_h, AVPixelFormat::AV_PIX_FMT_RGB24);
+        av_frame_free(&mut frame);
+        let mut tile = tile?;
+
+        let x = (i % cols) * row_len;
+        let y = (i / cols) * tile_h as usize;
+        let tile_stride = (*tile).linesize[0] as usize;
+        for line in 0..tile_h as usize {
+            ptr::copy_nonoverlapping(
+                (*tile).data[0].add(line * tile_stride),
+                (*sheet).data[0].add((y + line) * sheet_stride + x),
+                row_len,
+            );
+        }
+        av_frame_free(&mut tile);
+    }
+    Ok(())
+}
+
+/// Seek to `pos` and decode the next keyframe of the stream
+unsafe fn next_keyframe(
+    demux: &mut Demuxer,
+    decoder: &mut Decoder,
+    stream_index: i32,
+    pos: f32,
+) -> Result<Option<*mut AVFrame>> {
+    demux.seek(pos)?;
+    loop {
+        let (mut pkt, _) = demux.get_packet()?;
+        if pkt.is_null() {
+            return Ok(None);
+        }
+        if (*pkt).stream_index != stream_index {
+            av_packet_free(&mut pkt);
+            continue;
+        }
+        let frames = decoder.decode_pkt(pkt);
+        av_packet_free(&mut pkt);
+        let mut frames = frames?.into_iter();
+        if let Some(frame) = frames.next() {
+            for mut f in frames {
+                av_frame_free(&mut f);
+            }
+            // drop any buffered state before the next seek
+            if let Some(ctx) = decoder.get_decoder(stream_index) {
+                avcodec_flush_buffers(ctx.context);
+            }
+            return Ok(Some(frame));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate_test_video;
+
+    #[test]
+    fn contact_sheet_3x3() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_contact_sheet.mp4";
+            generate_test_video(path, 90, 10)?;
+
+            let mut sheet = generate_contact_sheet(path, 9, 160, 90)?;
+            assert_eq!((*sheet).width, 480);
+            assert_eq!((*sheet).height, 270);
+            assert_eq!(
+                (*sheet).format,
+                AVPixelFormat::AV_PIX_FMT_RGB24 as libc::c_int
+            );
+            av_frame_free(&mut sheet);
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
 use crate::{
-    Decoder, Demuxer, DemuxerInfo, Encoder, Muxer, Resample, Scaler, StreamInfo, StreamType,
+    Decoder, Demuxer, DemuxerInfo, Encoder, FpsConverter, Muxer, Resample, Scaler, StreamInfo,
+    StreamType,
 };
-use anyhow::Result;
-use ffmpeg_sys_the_third::{av_frame_free, av_packet_free};
-use std::collections::HashMap;
+use anyhow::{bail, Result};
+use ffmpeg_sys_the_third::{
+    av_frame_free, av_packet_free, av_q2d, av_rescale_q, AVCodecID, AVFrame, AVMediaType, AVPacket,
+    AVPixelFormat, AVRational, AVSampleFormat, AVStream, AV_NOPTS_VALUE,
+};
+use std::collections::{HashMap, HashSet};
 use std::ptr;
 
 /// A common transcoder task taking an input file
@@ -13,39 +17,156 @@ pub struct Transcoder {
     decoder: Decoder,
     scalers: HashMap<i32, Scaler>,
     resampler: HashMap<i32, Resample>,
+    fps: HashMap<i32, FpsConverter>,
+    /// First audio pts (encoder time base) and samples sent per input stream
+    audio_samples: HashMap<i32, (i64, i64)>,
     encoders: HashMap<i32, Encoder>,
     copy_stream: HashMap<i32, i32>,
     muxer: Muxer,
+    stats: HashMap<i32, StreamStats>,
+    limit: Option<Limit>,
+}
+
+/// Limit how much of the input is transcoded
+#[derive(Clone, Debug, PartialEq)]
+pub enum LimitKind {
+    /// Stop after this many seconds of each stream
+    Duration(f32),
+    /// Stop after this many video frames
+    Frames(u64),
+}
+
+/// Limit state of each input stream
+struct Limit {
+    kind: LimitKind,
+    /// Video frames seen per input stream
+    frames: HashMap<i32, u64>,
+    /// Input streams which have reached the limit
+    reached: HashSet<i32>,
+    /// Input streams which the limit does not apply to
+    unlimited: HashSet<i32>,
+}
+
+impl Limit {
+    /// Check if the frame/packet with timestamp `ts` is past the limit
+    unsafe fn check(&mut self, stream: *mut AVStream, ts: i64) -> bool {
+        let index = (*stream).index;
+        if self.reached.contains(&index) {
+            return true;
+        }
+        let reached = match self.kind {
+            LimitKind::Duration(secs) => {
+                if ts == AV_NOPTS_VALUE {
+                    return false;
+                }
+                let start = if (*stream).start_time != AV_NOPTS_VALUE {
+                    (*stream).start_time
+                } else {
+                    0
+                };
+                (ts - start) as f64 * av_q2d((*stream).time_base) >= secs as f64
+            }
+            LimitKind::Frames(n) => {
+                if (*(*stream).codecpar).codec_type != AVMediaType::AVMEDIA_TYPE_VIDEO {
+                    self.unlimited.insert(index);
+                    return false;
+                }
+                let count = self.frames.entry(index).or_default();
+                *count += 1;
+                *count > n
+            }
+        };
+        if reached {
+            self.reached.insert(index);
+        }
+        reached
+    }
+
+    /// Check if all the given input streams are done
+    fn is_done<'a>(&self, mut streams: impl Iterator<Item = &'a i32>) -> bool {
+        streams.all(|i| self.reached.contains(i) || self.unlimited.contains(i))
+    }
+}
+
+/// Output statistics of a single stream
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamStats {
+    /// Total size of all packets written
+    pub bytes: u64,
+    /// Number of packets written
+    pub packets: u64,
 }
 
 impl Transcoder {
     pub unsafe fn new(input: &str, output: &str) -> Result<Self> {
-        let muxer = Muxer::builder().with_output_path(output, None)?.build()?;
+        Self::new_with_muxer_format(input, output, None)
+    }
+
+    /// Create a transcoder with an explicit output format (eg. "matroska"),
+    /// instead of guessing the format from the output extension
+    pub unsafe fn new_with_format(input: &str, output: &str, format: &str) -> Result<Self> {
+        Self::new_with_muxer_format(input, output, Some(format))
+    }
+
+    unsafe fn new_with_muxer_format(
+        input: &str,
+        output: &str,
+        format: Option<&str>,
+    ) -> Result<Self> {
+        let muxer = Muxer::builder().with_output_path(output, format)?.build()?;
 
         Ok(Self {
             demuxer: Demuxer::new(input)?,
             decoder: Decoder::new(),
             scalers: HashMap::new(),
             resampler: HashMap::new(),
+            fps: HashMap::new(),
+            audio_samples: HashMap::new(),
             encoders: HashMap::new(),
             copy_stream: HashMap::new(),
             muxer,
+            stats: HashMap::new(),
+            limit: None,
         })
     }
 
+    /// Only transcode part of the input, the output is flushed once the limit is reached
+    pub fn with_limit(mut self, limit: LimitKind) -> Self {
+        self.limit = Some(Limit {
+            kind: limit,
+            frames: HashMap::new(),
+            reached: HashSet::new(),
+            unlimited: HashSet::new(),
+        });
+        self
+    }
+
     /// Prepare the transcoder by probing the input
     pub unsafe fn prepare(&mut self) -> Result<DemuxerInfo> {
         self.demuxer.probe_input()
     }
 
+    /// Check that an input stream is not already transcoded/copied,
+    /// each input stream can only be mapped to one output stream
+    fn check_unmapped(&self, src_index: i32) -> Result<()> {
+        if self.encoders.contains_key(&src_index) || self.copy_stream.contains_key(&src_index) {
+            bail!("Input stream {} is already mapped", src_index);
+        }
+        Ok(())
+    }
+
     /// Create a transcoded stream in the output given an input stream and
     /// a pre-configured output encoder
+    ///
+    /// Can be called for any number of input streams (eg. multiple audio tracks),
+    /// each creating a new output stream
     pub unsafe fn transcode_stream(
         &mut self,
         in_stream: &StreamInfo,
         encoder_out: Encoder,
     ) -> Result<()> {
         let src_index = in_stream.index as i32;
+        self.check_unmapped(src_index)?;
         let dst_stream = self.muxer.add_stream_encoder(&encoder_out)?;
         let out_ctx = encoder_out.codec_context();
 
@@ -83,80 +204,510 @@ impl Transcoder {
         Ok(())
     }
 
+    /// Convert the frame rate of a transcoded video stream to the framerate of its encoder
+    /// by dropping or duplicating frames
+    ///
+    /// Must be called after [Transcoder::transcode_stream]
+    pub unsafe fn convert_fps(&mut self, in_stream: &StreamInfo) -> Result<()> {
+        let src_index = in_stream.index as i32;
+        let enc = if let Some(enc) = self.encoders.get(&src_index) {
+            enc
+        } else {
+            bail!("Stream {} is not transcoded", src_index);
+        };
+        let fps = av_q2d((*enc.codec_context()).framerate) as f32;
+        if fps <= 0.0 {
+            bail!("Encoder has no framerate");
+        }
+        self.fps.insert(
+            src_index,
+            FpsConverter::new((*in_stream.stream).time_base, fps),
+        );
+        Ok(())
+    }
+
     /// Copy a stream from the input to the output
     pub unsafe fn copy_stream(&mut self, in_stream: StreamInfo) -> Result<()> {
+        self.check_unmapped(in_stream.index as i32)?;
         let dst_stream = self.muxer.add_copy_stream(in_stream.stream)?;
         self.copy_stream
             .insert(in_stream.index as i32, (*dst_stream).index);
         Ok(())
     }
 
-    /// Process the next packet, called by [run]
-    unsafe fn next(&mut self) -> Result<bool> {
-        let (mut pkt, stream) = self.demuxer.get_packet()?;
+    /// Get the output statistics by output stream index
+    pub fn stats(&self) -> &HashMap<i32, StreamStats> {
+        &self.stats
+    }
 
-        // flush
-        if pkt.is_null() {
-            for enc in self.encoders.values_mut() {
-                for mut new_pkt in enc.encode_frame(ptr::null_mut())? {
-                    self.muxer.write_packet(new_pkt)?;
-                    av_packet_free(&mut new_pkt);
+    /// Write a packet to the muxer, recording its size in the stream stats
+    unsafe fn write_packet(
+        muxer: &mut Muxer,
+        stats: &mut HashMap<i32, StreamStats>,
+        pkt: *mut AVPacket,
+    ) -> Result<()> {
+        let s = stats.entry((*pkt).stream_index).or_default();
+        s.bytes += (*pkt).size as u64;
+        s.packets += 1;
+        muxer.write_packet(pkt)
+    }
+
+    /// Encode a frame (or flush the encoder with null) and write the packets to the muxer,
+    /// the frame is not consumed
+    unsafe fn encode_frame(
+        enc: &mut Encoder,
+        muxer: &mut Muxer,
+        stats: &mut HashMap<i32, StreamStats>,
+        frame: *mut AVFrame,
+    ) -> Result<()> {
+        let mut pkts = enc.encode_frame(frame)?.into_iter();
+        while let Some(mut pkt) = pkts.next() {
+            let ret = Self::write_packet(muxer, stats, pkt);
+            av_packet_free(&mut pkt);
+            if let Err(e) = ret {
+                for mut pkt in pkts {
+                    av_packet_free(&mut pkt);
                 }
+                return Err(e);
             }
-            Ok(true)
+        }
+        Ok(())
+    }
+
+    /// Scale/resample/convert a decoded frame and encode it, the frame is consumed
+    unsafe fn process_frame(
+        &mut self,
+        stream: *mut AVStream,
+        mut frame: *mut AVFrame,
+    ) -> Result<()> {
+        let src_index = (*stream).index;
+        let enc = if let Some(enc) = self.encoders.get_mut(&src_index) {
+            enc
         } else {
-            let src_index = (*stream).index;
-            // check if encoded stream
-            if let Some(enc) = self.encoders.get_mut(&src_index) {
-                for mut frame in self.decoder.decode_pkt(pkt)? {
-                    // scale video frame before sending to encoder
-                    let frame = if let Some(sws) = self.scalers.get_mut(&src_index) {
-                        let enc_ctx = enc.codec_context();
-                        let new_frame = sws.process_frame(
-                            frame,
-                            (*enc_ctx).width as u16,
-                            (*enc_ctx).height as u16,
-                            (*enc_ctx).pix_fmt,
-                        )?;
-                        av_frame_free(&mut frame);
-                        new_frame
-                    } else {
-                        frame
-                    };
+            av_frame_free(&mut frame);
+            bail!("Stream {} is not transcoded", src_index);
+        };
+
+        if let Some(limit) = self.limit.as_mut() {
+            let ts = if (*frame).pts != AV_NOPTS_VALUE {
+                (*frame).pts
+            } else {
+                (*frame).best_effort_timestamp
+            };
+            if limit.check(stream, ts) {
+                av_frame_free(&mut frame);
+                return Ok(());
+            }
+        }
+
+        // scale video frame before sending to encoder
+        if let Some(sws) = self.scalers.get_mut(&src_index) {
+            let enc_ctx = enc.codec_context();
+            let new_frame = sws.process_frame(
+                frame,
+                (*enc_ctx).width as u16,
+                (*enc_ctx).height as u16,
+                (*enc_ctx).pix_fmt,
+            );
+            av_frame_free(&mut frame);
+            frame = new_frame?;
+        }
 
-                    // resample audio frame before encoding
-                    let mut frame = if let Some(swr) = self.resampler.get_mut(&src_index) {
-                        swr.process_frame(frame)?
+        // resample audio frame before encoding
+        if let Some(swr) = self.resampler.get_mut(&src_index) {
+            let new_frame = swr.process_frame(frame);
+            av_frame_free(&mut frame);
+            frame = new_frame?;
+        }
+
+        // convert video frame rate
+        let frames = if let Some(fps) = self.fps.get_mut(&src_index) {
+            let new_frames = fps.process_frame(frame);
+            av_frame_free(&mut frame);
+            new_frames?
+        } else {
+            // rescale from the input stream time base to the encoder time base
+            let enc_ctx = enc.codec_context();
+            let in_tb = (*stream).time_base;
+            let enc_tb = (*enc_ctx).time_base;
+            let ts = if (*frame).pts != AV_NOPTS_VALUE {
+                (*frame).pts
+            } else {
+                (*frame).best_effort_timestamp
+            };
+            if (*enc_ctx).codec_type == AVMediaType::AVMEDIA_TYPE_AUDIO {
+                // count samples so that resampling does not cause timestamp drift
+                let (start, samples) = self.audio_samples.entry(src_index).or_insert((
+                    if ts != AV_NOPTS_VALUE {
+                        av_rescale_q(ts, in_tb, enc_tb)
                     } else {
-                        frame
-                    };
+                        0
+                    },
+                    0,
+                ));
+                let sample_tb = AVRational {
+                    num: 1,
+                    den: (*frame).sample_rate,
+                };
+                (*frame).pts = *start + av_rescale_q(*samples, sample_tb, enc_tb);
+                *samples += (*frame).nb_samples as i64;
+            } else if ts != AV_NOPTS_VALUE {
+                (*frame).pts = av_rescale_q(ts, in_tb, enc_tb);
+            }
+            (*frame).time_base = enc_tb;
+            vec![frame]
+        };
 
-                    // encode frame and send packets to muxer
-                    for mut new_pkt in enc.encode_frame(frame)? {
-                        self.muxer.write_packet(new_pkt)?;
-                        av_packet_free(&mut new_pkt);
-                    }
+        // encode frame and send packets to muxer
+        let mut frames = frames.into_iter();
+        while let Some(mut frame) = frames.next() {
+            let ret = Self::encode_frame(enc, &mut self.muxer, &mut self.stats, frame);
+            av_frame_free(&mut frame);
+            if let Err(e) = ret {
+                for mut frame in frames {
                     av_frame_free(&mut frame);
                 }
-            } else if let Some(dst_stream) = self.copy_stream.get(&src_index) {
-                // write pkt directly to muxer (re-mux)
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode/copy a packet from the input, the packet is not consumed
+    unsafe fn process_packet(&mut self, pkt: *mut AVPacket, stream: *mut AVStream) -> Result<()> {
+        let src_index = (*stream).index;
+        // check if encoded stream
+        if self.encoders.contains_key(&src_index) {
+            let mut frames = self.decoder.decode_pkt(pkt)?.into_iter();
+            while let Some(frame) = frames.next() {
+                if let Err(e) = self.process_frame(stream, frame) {
+                    for mut frame in frames {
+                        av_frame_free(&mut frame);
+                    }
+                    return Err(e);
+                }
+            }
+        } else if let Some(dst_stream) = self.copy_stream.get(&src_index) {
+            let skip = if let Some(limit) = self.limit.as_mut() {
+                limit.check(stream, (*pkt).pts)
+            } else {
+                false
+            };
+            // write pkt directly to muxer (re-mux)
+            if !skip {
                 (*pkt).stream_index = *dst_stream;
-                self.muxer.write_packet(pkt)?;
+                Self::write_packet(&mut self.muxer, &mut self.stats, pkt)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the decoders, then the frame rate converters and finally the encoders
+    /// so that no buffered frames are lost
+    unsafe fn flush(&mut self) -> Result<()> {
+        // frames are collected first as processing them borrows the whole transcoder
+        let mut drained = Vec::new();
+        let ret = self.decoder.drain(|stream, frame| {
+            drained.push((stream, frame));
+            Ok(())
+        });
+        let mut drained = drained.into_iter();
+        if let Err(e) = ret {
+            for (_, mut frame) in drained {
+                av_frame_free(&mut frame);
+            }
+            return Err(e);
+        }
+        while let Some((stream, frame)) = drained.next() {
+            if let Err(e) = self.process_frame(stream, frame) {
+                for (_, mut frame) in drained {
+                    av_frame_free(&mut frame);
+                }
+                return Err(e);
+            }
+        }
+
+        for (src_index, enc) in self.encoders.iter_mut() {
+            if let Some(fps) = self.fps.get_mut(src_index) {
+                let mut frames = fps.flush()?.into_iter();
+                while let Some(mut frame) = frames.next() {
+                    let ret = Self::encode_frame(enc, &mut self.muxer, &mut self.stats, frame);
+                    av_frame_free(&mut frame);
+                    if let Err(e) = ret {
+                        for mut frame in frames {
+                            av_frame_free(&mut frame);
+                        }
+                        return Err(e);
+                    }
+                }
             }
+            Self::encode_frame(enc, &mut self.muxer, &mut self.stats, ptr::null_mut())?;
+        }
+        Ok(())
+    }
+
+    /// Process the next packet, called by [run]
+    unsafe fn next(&mut self) -> Result<bool> {
+        let (mut pkt, stream) = self.demuxer.get_packet()?;
+
+        // stop reading once all streams have reached the limit
+        if let Some(limit) = &self.limit {
+            if !pkt.is_null() && limit.is_done(self.encoders.keys().chain(self.copy_stream.keys()))
+            {
+                av_packet_free(&mut pkt);
+            }
+        }
 
+        // flush
+        if pkt.is_null() {
+            self.flush()?;
+            Ok(true)
+        } else {
+            let ret = self.process_packet(pkt, stream);
             av_packet_free(&mut pkt);
+            ret?;
             Ok(false)
         }
     }
 
     /// Run the transcoder
-    pub unsafe fn run(mut self, mux_options: Option<HashMap<String, String>>) -> Result<()> {
+    pub unsafe fn run(self, mux_options: Option<HashMap<String, String>>) -> Result<()> {
+        self.run_with_stats(mux_options)?;
+        Ok(())
+    }
+
+    /// Run the transcoder, returning the output statistics by output stream index
+    pub unsafe fn run_with_stats(
+        mut self,
+        mux_options: Option<HashMap<String, String>>,
+    ) -> Result<HashMap<i32, StreamStats>> {
         self.muxer.open(mux_options)?;
         while !self.next()? {
             // nothing here
         }
         self.muxer.close()?;
-        Ok(())
+        Ok(std::mem::take(&mut self.stats))
+    }
+}
+
+/// Output settings of a transcoded video stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoOptions {
+    pub codec: AVCodecID,
+    pub pix_fmt: AVPixelFormat,
+    /// Output width, 0 keeps the input aspect ratio for the given height
+    pub width: u16,
+    /// Output height, 0 keeps the input aspect ratio for the given width
+    pub height: u16,
+    /// Bitrate in bits/s, 0 uses the codec default
+    pub bitrate: i64,
+    /// Output framerate, frames are dropped/duplicated if it differs from the input
+    ///
+    /// Required when the input framerate is unknown
+    pub framerate: Option<f32>,
+}
+
+impl Default for VideoOptions {
+    fn default() -> Self {
+        Self {
+            codec: AVCodecID::AV_CODEC_ID_H264,
+            pix_fmt: AVPixelFormat::AV_PIX_FMT_YUV420P,
+            width: 0,
+            height: 0,
+            bitrate: 0,
+            framerate: None,
+        }
+    }
+}
+
+impl VideoOptions {
+    /// Output size for an input stream, derived sizes are rounded to an even number
+    fn output_size(&self, in_stream: &StreamInfo) -> (i32, i32) {
+        let (w, h) = (in_stream.width as i32, in_stream.height as i32);
+        match (self.width as i32, self.height as i32) {
+            (0, 0) => (w, h),
+            (0, oh) if h > 0 => ((w * oh / h + 1) & !1, oh),
+            (ow, 0) if w > 0 => (ow, (h * ow / w + 1) & !1),
+            (ow, oh) => (ow, oh),
+        }
+    }
+}
+
+/// Framerate of an input stream, the average framerate is unknown for some inputs
+/// (eg. raw streams) so fall back to the real base framerate, 0 if neither is known
+unsafe fn input_framerate(in_stream: &StreamInfo) -> f32 {
+    if in_stream.fps > 0.0 || in_stream.stream.is_null() {
+        return in_stream.fps;
+    }
+    let r_fps = av_q2d((*in_stream.stream).r_frame_rate) as f32;
+    if r_fps > 0.0 {
+        r_fps
+    } else {
+        0.0
+    }
+}
+
+/// Output settings of a transcoded audio stream
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioOptions {
+    pub codec: AVCodecID,
+    pub sample_fmt: AVSampleFormat,
+    /// Output sample rate, 0 uses the input sample rate
+    pub sample_rate: u32,
+    /// Output channel count, 0 uses the input channel count
+    pub channels: u16,
+    /// Bitrate in bits/s, 0 uses the codec default
+    pub bitrate: i64,
+}
+
+impl Default for AudioOptions {
+    fn default() -> Self {
+        Self {
+            codec: AVCodecID::AV_CODEC_ID_AAC,
+            sample_fmt: AVSampleFormat::AV_SAMPLE_FMT_FLTP,
+            sample_rate: 0,
+            channels: 0,
+            bitrate: 0,
+        }
+    }
+}
+
+/// How an input stream is written to the output
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamMapping {
+    /// Copy the packets without transcoding
+    Copy,
+    Video(VideoOptions),
+    Audio(AudioOptions),
+}
+
+/// Input stream selected by [TranscodeBuilder]
+#[derive(Clone, Debug, PartialEq)]
+enum StreamSelector {
+    Index(usize),
+    Best(StreamType),
+}
+
+/// Declarative setup of a [Transcoder], input streams which are not mapped are dropped
+pub struct TranscodeBuilder {
+    input: String,
+    output: String,
+    format: Option<String>,
+    streams: Vec<(StreamSelector, StreamMapping)>,
+    limit: Option<LimitKind>,
+}
+
+impl TranscodeBuilder {
+    pub fn new(input: &str, output: &str) -> Self {
+        Self {
+            input: input.to_string(),
+            output: output.to_string(),
+            format: None,
+            streams: Vec::new(),
+            limit: None,
+        }
+    }
+
+    /// Set the output format (eg. "matroska") instead of guessing it from the output extension
+    pub fn with_format(mut self, format: &str) -> Self {
+        self.format = Some(format.to_string());
+        self
+    }
+
+    /// Map the input stream with this index to the output
+    pub fn with_stream(mut self, index: usize, mapping: StreamMapping) -> Self {
+        self.streams.push((StreamSelector::Index(index), mapping));
+        self
+    }
+
+    /// Map the best input stream of this type to the output
+    pub fn with_best_stream(mut self, stream_type: StreamType, mapping: StreamMapping) -> Self {
+        self.streams
+            .push((StreamSelector::Best(stream_type), mapping));
+        self
+    }
+
+    /// See [Transcoder::with_limit]
+    pub fn with_limit(mut self, limit: LimitKind) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Probe the input and setup the output streams, ready to [Transcoder::run]
+    pub unsafe fn build(self) -> Result<Transcoder> {
+        if self.streams.is_empty() {
+            bail!("No streams mapped to the output");
+        }
+        let mut transcoder =
+            Transcoder::new_with_muxer_format(&self.input, &self.output, self.format.as_deref())?;
+        if let Some(limit) = self.limit {
+            transcoder = transcoder.with_limit(limit);
+        }
+        let info = transcoder.prepare()?;
+        for (selector, mapping) in self.streams {
+            let in_stream = match &selector {
+                StreamSelector::Index(i) => info.streams.iter().find(|s| s.index == *i),
+                StreamSelector::Best(t) => info.best_stream(t.clone()),
+            };
+            let in_stream = if let Some(s) = in_stream {
+                s.clone()
+            } else {
+                bail!("Input stream {:?} not found", selector);
+            };
+            match mapping {
+                StreamMapping::Copy => transcoder.copy_stream(in_stream)?,
+                StreamMapping::Video(opt) => {
+                    if in_stream.stream_type != StreamType::Video {
+                        bail!("Input stream {} is not a video stream", in_stream.index);
+                    }
+                    let (width, height) = opt.output_size(&in_stream);
+                    let in_fps = input_framerate(&in_stream);
+                    if in_fps <= 0.0 && opt.framerate.is_none() {
+                        bail!(
+                            "Input stream {} has an unknown framerate, set VideoOptions::framerate",
+                            in_stream.index
+                        );
+                    }
+                    let fps = opt.framerate.unwrap_or(in_fps);
+                    let mut encoder = Encoder::new(opt.codec)?
+                        .with_width(width)
+                        .with_height(height)
+                        .with_pix_fmt(opt.pix_fmt)
+                        .with_framerate(fps)?;
+                    if opt.bitrate > 0 {
+                        encoder = encoder.with_bitrate(opt.bitrate);
+                    }
+                    transcoder.transcode_stream(&in_stream, encoder.open(None)?)?;
+                    if opt.framerate.is_some_and(|f| f != in_fps) {
+                        transcoder.convert_fps(&in_stream)?;
+                    }
+                }
+                StreamMapping::Audio(opt) => {
+                    if in_stream.stream_type != StreamType::Audio {
+                        bail!("Input stream {} is not an audio stream", in_stream.index);
+                    }
+                    let sample_rate = if opt.sample_rate > 0 {
+                        opt.sample_rate as i32
+                    } else {
+                        in_stream.sample_rate as i32
+                    };
+                    let channels = if opt.channels > 0 {
+                        opt.channels as i32
+                    } else {
+                        (*(*in_stream.stream).codecpar).ch_layout.nb_channels
+                    };
+                    let mut encoder = Encoder::new(opt.codec)?
+                        .with_sample_rate(sample_rate)?
+                        .with_sample_format(opt.sample_fmt)
+                        .with_default_channel_layout(channels);
+                    if opt.bitrate > 0 {
+                        encoder = encoder.with_bitrate(opt.bitrate);
+                    }
+                    transcoder.transcode_stream(&in_stream, encoder.open(None)?)?;
+                }
+            }
+        }
+        Ok(transcoder)
     }
 }
 
@@ -176,9 +727,298 @@ mod tests {
             for c in info.streams {
                 transcoder.copy_stream(c)?;
             }
+            let stats = transcoder.run_with_stats(None)?;
+            let total: u64 = stats.values().map(|s| s.bytes).sum();
+            let video = stats.get(&0).expect("no video stats");
+            assert!(video.packets > 0);
+            assert!(video.bytes * 2 > total);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transcode_limit() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_transcode_limit_input.mp4";
+            let output = "test_output/test_transcode_limit.mp4";
+            crate::generate_test_video(input, 90, 30)?;
+            let mut transcoder =
+                Transcoder::new(input, output)?.with_limit(LimitKind::Duration(1.0));
+            let info = transcoder.prepare()?;
+            let video = info.best_video().expect("no video stream").clone();
+            let encoder = crate::test_encoder(256, 256)?.open(None)?;
+            transcoder.transcode_stream(&video, encoder)?;
+            transcoder.convert_fps(&video)?;
+            transcoder.run(None)?;
+
+            let mut demux = Demuxer::new(output)?;
+            let info = demux.probe_input()?;
+            assert!(
+                (info.duration - 1.0).abs() < 0.1,
+                "duration was {}",
+                info.duration
+            );
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transcode_flush() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_transcode_flush_input.mp4";
+            let output = "test_output/test_transcode_flush.mp4";
+            crate::generate_test_video(input, 60, 30)?;
+            let mut transcoder = Transcoder::new(input, output)?;
+            let info = transcoder.prepare()?;
+            let video = info.best_video().expect("no video stream").clone();
+            let encoder = crate::test_encoder(256, 256)?.open(None)?;
+            transcoder.transcode_stream(&video, encoder)?;
+            let stats = transcoder.run_with_stats(None)?;
+            let video_out = stats.get(&0).expect("no video stats");
+            assert_eq!(video_out.packets, 60);
+
+            let mut demux = Demuxer::new(output)?;
+            demux.probe_input()?;
+            let mut count = 0;
+            loop {
+                let (mut pkt, _) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                count += 1;
+                av_packet_free(&mut pkt);
+            }
+            assert_eq!(count, 60);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transcode_multiple_audio() -> Result<()> {
+        use crate::audio_silence;
+        use ffmpeg_sys_the_third::AVSampleFormat::AV_SAMPLE_FMT_FLTP;
+        use ffmpeg_sys_the_third::{av_channel_layout_default, AVChannelLayout, AVCodecID};
+
+        unsafe fn aac_encoder() -> Result<Encoder> {
+            Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                .with_sample_rate(44_100)?
+                .with_sample_format(AV_SAMPLE_FMT_FLTP)
+                .with_default_channel_layout(2)
+                .with_bitrate(128_000)
+                .open(None)
+        }
+
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let input = "test_output/test_transcode_multi_audio_input.mkv";
+            let output = "test_output/test_transcode_multi_audio.mkv";
+
+            let mut enc_a = aac_encoder()?;
+            let mut enc_b = aac_encoder()?;
+            let mut muxer = Muxer::builder().with_output_path(input, None)?.build()?;
+            muxer.add_stream_encoder(&enc_a)?;
+            muxer.add_stream_encoder(&enc_b)?;
+            muxer.open(None)?;
+            let mut layout = AVChannelLayout::empty();
+            av_channel_layout_default(&mut layout, 2);
+            let mut frame = audio_silence(1024, AV_SAMPLE_FMT_FLTP, &layout, 44_100)?;
+            for n in 0..20 {
+                (*frame).pts = n * 1024;
+                muxer.write_encoded(&mut enc_a, frame)?;
+                muxer.write_encoded(&mut enc_b, frame)?;
+            }
+            muxer.write_encoded(&mut enc_a, ptr::null_mut())?;
+            muxer.write_encoded(&mut enc_b, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut frame);
+
+            let mut transcoder = Transcoder::new(input, output)?;
+            let info = transcoder.prepare()?;
+            let audio: Vec<StreamInfo> = info
+                .streams
+                .iter()
+                .filter(|s| s.stream_type == StreamType::Audio)
+                .cloned()
+                .collect();
+            assert_eq!(audio.len(), 2);
+            for stream in &audio {
+                transcoder.transcode_stream(stream, aac_encoder()?)?;
+            }
+            assert!(transcoder.copy_stream(audio[0].clone()).is_err());
+            let stats = transcoder.run_with_stats(None)?;
+            assert_eq!(stats.len(), 2);
+            assert!(stats.values().all(|s| s.packets > 0));
+
+            let mut demux = Demuxer::new(output)?;
+            let info = demux.probe_input()?;
+            let out_audio = info
+                .streams
+                .iter()
+                .filter(|s| s.stream_type == StreamType::Audio)
+                .count();
+            assert_eq!(out_audio, 2);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transcode_time_base() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_transcode_tb_input.mp4";
+            let output = "test_output/test_transcode_tb.mkv";
+            crate::generate_test_video(input, 90, 30)?;
+            let mut transcoder = Transcoder::new(input, output)?;
+            let info = transcoder.prepare()?;
+            let video = info.best_video().expect("no video stream").clone();
+            let in_tb = (*video.stream).time_base;
+            let encoder = crate::test_encoder(256, 256)?.open(None)?;
+            let enc_tb = (*encoder.codec_context()).time_base;
+            assert_ne!(in_tb.den, enc_tb.den);
+            transcoder.transcode_stream(&video, encoder)?;
+            transcoder.run(None)?;
+
+            let mut demux = Demuxer::new(output)?;
+            let out_info = demux.probe_input()?;
+            assert!(
+                (out_info.duration - info.duration).abs() < 0.1,
+                "duration was {}, expected {}",
+                out_info.duration,
+                info.duration
+            );
+            let mut pts = Vec::new();
+            loop {
+                let (mut pkt, stream) = demux.get_packet()?;
+                if pkt.is_null() {
+                    break;
+                }
+                pts.push(av_rescale_q(
+                    (*pkt).pts,
+                    (*stream).time_base,
+                    AVRational { num: 1, den: 30 },
+                ));
+                av_packet_free(&mut pkt);
+            }
+            pts.sort();
+            assert_eq!(pts.len(), 90);
+            assert!(pts.windows(2).all(|w| w[1] - w[0] == 1), "{:?}", pts);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_remux_with_format() -> Result<()> {
+        unsafe {
+            let input = "test_output/test_remux_with_format.mp4";
+            let output = "test_output/test_remux_with_format.dat";
+            crate::generate_test_video(input, 30, 10)?;
+            let mut transcoder = Transcoder::new_with_format(input, output, "matroska")?;
+            let info = transcoder.prepare()?;
+            for c in info.streams {
+                transcoder.copy_stream(c)?;
+            }
             transcoder.run(None)?;
 
+            let mut demux = Demuxer::new(output)?;
+            let info = demux.probe_input()?;
+            assert!(info.format.contains("matroska"));
+            assert!(info.best_video().is_some());
             Ok(())
         }
     }
+
+    #[test]
+    fn test_transcode_builder() -> Result<()> {
+        use crate::{audio_silence, setup_test_encoder};
+        use ffmpeg_sys_the_third::{av_channel_layout_default, AVChannelLayout};
+
+        unsafe {
+            std::fs::create_dir_all("test_output")?;
+            let input = "test_output/test_transcode_builder_input.mkv";
+            let output = "test_output/test_transcode_builder.mp4";
+
+            let (mut frame, video_enc) = setup_test_encoder(1280, 720)?;
+            let mut video_enc = video_enc.open(None)?;
+            let mut audio_enc = Encoder::new(AVCodecID::AV_CODEC_ID_AAC)?
+                .with_sample_rate(48_000)?
+                .with_sample_format(AVSampleFormat::AV_SAMPLE_FMT_FLTP)
+                .with_default_channel_layout(2)
+                .open(None)?;
+            let mut muxer = Muxer::builder().with_output_path(input, None)?.build()?;
+            muxer.add_stream_encoder(&video_enc)?;
+            muxer.add_stream_encoder(&audio_enc)?;
+            muxer.open(None)?;
+
+            let mut layout = AVChannelLayout::empty();
+            av_channel_layout_default(&mut layout, 2);
+            let mut samples =
+                audio_silence(1600, AVSampleFormat::AV_SAMPLE_FMT_FLTP, &layout, 48_000)?;
+            for n in 0..30 {
+                (*frame).pts = n;
+                muxer.write_encoded(&mut video_enc, frame)?;
+                (*samples).pts = n * 1600;
+                muxer.write_encoded(&mut audio_enc, samples)?;
+            }
+            muxer.write_encoded(&mut video_enc, ptr::null_mut())?;
+            muxer.write_encoded(&mut audio_enc, ptr::null_mut())?;
+            muxer.close()?;
+            av_frame_free(&mut frame);
+            av_frame_free(&mut samples);
+
+            let mut transcoder = TranscodeBuilder::new(input, output)
+                .with_best_stream(
+                    StreamType::Video,
+                    StreamMapping::Video(VideoOptions {
+                        height: 480,
+                        bitrate: 1_000_000,
+                        ..Default::default()
+                    }),
+                )
+                .with_best_stream(StreamType::Audio, StreamMapping::Copy)
+                .build()?;
+            transcoder.run(None)?;
+
+            let mut demux = Demuxer::new(output)?;
+            let info = demux.probe_input()?;
+            let video = info.best_video().expect("no video stream");
+            assert_eq!(video.codec, AVCodecID::AV_CODEC_ID_H264 as isize);
+            assert_eq!(video.width, 854);
+            assert_eq!(video.height, 480);
+            let audio = info.best_audio().expect("no audio stream");
+            assert_eq!(audio.codec, AVCodecID::AV_CODEC_ID_AAC as isize);
+            assert_eq!(audio.sample_rate, 48_000);
+
+            // mapping a stream to the wrong media type fails
+            assert!(TranscodeBuilder::new(input, output)
+                .with_best_stream(
+                    StreamType::Audio,
+                    StreamMapping::Video(VideoOptions::default())
+                )
+                .build()
+                .is_err());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_input_framerate() -> Result<()> {
+        unsafe {
+            let path = "test_output/test_input_framerate.mp4";
+            crate::generate_test_video(path, 30, 30)?;
+            let mut demux = Demuxer::new(path)?;
+            let info = demux.probe_input()?;
+            let mut video = info.best_video().expect("no video stream").clone();
+            assert_eq!(input_framerate(&video), video.fps);
+
+            // unknown average framerate
+            video.fps = 0.0;
+            let r_fps = av_q2d((*video.stream).r_frame_rate) as f32;
+            assert!(r_fps > 0.0);
+            assert_eq!(input_framerate(&video), r_fps);
+
+            // no framerate at all
+            (*video.stream).r_frame_rate = AVRational { num: 0, den: 1 };
+            assert_eq!(input_framerate(&video), 0.0);
+        }
+        Ok(())
+    }
 }